@@ -1,21 +1,53 @@
-STACK_SIZE = 0x2000;
+STACK_SIZE = 0x10000;
+/* 8 harts with 8 KiB each, see firmware.rs */
+SBI_STACK_SIZE = 0x10000;
 
 SECTIONS
 {
   /* Start address */
   . = 0x80000000;
 
-  /* Output a text section, starting with the entry point */
+  /* The SBI firmware, starting with the entry point */
   .entry_point : ALIGN(0x1000) {
      _start
     *(.entry_point)
   }
+
+  /* Firmware state, out of the kernel .bss which the kernel clears */
+  . = ALIGN(0x8);
+  .sbi : {
+    *(.sbi.data)
+  }
+
+  /* Machine mode stacks of the harts */
+  . = ALIGN(0x1000);
+  _sbi_stack_start = .;
+  .sbi_stack (NOLOAD) : {
+    . = . + SBI_STACK_SIZE;
+  }
+  _sbi_stack_end = .;
+
+  /* The kernel starts where SBI firmwares such as OpenSBI jump to */
+  . = 0x80200000;
+  .kernel_entry : {
+    *(.kernel_entry)
+  }
   . = ALIGN(0x4);
   _text_start = .;
   .text : {
     *(.text)
     *(.text.*)
   }
+
+  /* Code of the user programs, mapped into every user address space */
+  . = ALIGN(0x1000);
+  _user_text_start = .;
+  .user_text : {
+    *(.user_text)
+    *(.user_text.*)
+  }
+  . = ALIGN(0x1000);
+  _user_text_end = .;
   _text_end = .;
 
   /* Output the rodata */
@@ -50,9 +82,10 @@ SECTIONS
   /* Then we allocate some stack space */
   . = ALIGN(0x1000);
   _stack_start = .;
-  .stack :  {
-   }
-   _stack_end = .;
-   _heap_start = .;
+  .stack (NOLOAD) : {
+    . = . + STACK_SIZE;
+  }
+  _stack_end = .;
+  _heap_start = .;
 }
 
@@ -0,0 +1,334 @@
+// LRU cache of block device sectors, shared by every file system.
+// Buffers are keyed by the registry id of the whole disk and the sector number
+// on it, so that a partition shares the buffers of its disk.
+// Modified buffers are only written to the device when they get evicted or
+// when sync is called.
+
+use crate::block::{self, BlockError};
+use crate::ramdisk;
+use core::ops::Range;
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const CACHE_SIZE: usize = 64;
+
+struct Buffer {
+    device: usize,
+    sector: u64,
+    valid: bool,
+    dirty: bool,
+    // Number of callers currently using the data, pinned buffers are never evicted
+    pins: usize,
+    // Tick of the last access, the smallest one is the least recently used
+    last_used: u64,
+    data: Vec<u8>,
+}
+
+impl Buffer {
+    const fn empty() -> Self {
+        Buffer {
+            device: 0,
+            sector: 0,
+            valid: false,
+            dirty: false,
+            pins: 0,
+            last_used: 0,
+            data: Vec::new(),
+        }
+    }
+}
+
+const EMPTY_BUFFER: Buffer = Buffer::empty();
+
+static mut BUFFERS: [Buffer; CACHE_SIZE] = [EMPTY_BUFFER; CACHE_SIZE];
+static mut TICK: u64 = 0;
+
+static mut HITS: u64 = 0;
+static mut MISSES: u64 = 0;
+
+#[derive(Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub dirty: usize,
+}
+
+fn buffers() -> &'static mut [Buffer; CACHE_SIZE] {
+    unsafe { &mut *(&raw mut BUFFERS) }
+}
+
+fn next_tick() -> u64 {
+    unsafe {
+        TICK += 1;
+        TICK
+    }
+}
+
+fn write_back(buffer: &mut Buffer) -> Result<(), BlockError> {
+    if buffer.valid && buffer.dirty {
+        let device = block::get(buffer.device).ok_or(BlockError::NotInitialized)?;
+        device.write(buffer.sector, &buffer.data)?;
+        buffer.dirty = false;
+    }
+    Ok(())
+}
+
+// Disk holding the device and the sectors of the disk the device covers
+fn extent(device: usize) -> Result<(usize, Range<u64>), BlockError> {
+    let disk = block::get(device).ok_or(BlockError::NotInitialized)?;
+    let length = disk.num_sectors();
+    Ok(match disk.window() {
+        Some((parent, start)) => (parent, start..start + length),
+        None => (device, 0..length),
+    })
+}
+
+// Index of the buffer holding the sector, loading it from the device when
+// load is set. The returned buffer is pinned.
+fn get_buffer(device: usize, sector: u64, load: bool) -> Result<usize, BlockError> {
+    let (device, range) = extent(device)?;
+    let sector = range
+        .start
+        .checked_add(sector)
+        .ok_or(BlockError::OutOfRange)?;
+    if !range.contains(&sector) {
+        return Err(BlockError::OutOfRange);
+    }
+    let buffers = buffers();
+
+    if let Some(index) = buffers
+        .iter()
+        .position(|b| b.valid && b.device == device && b.sector == sector)
+    {
+        unsafe { HITS += 1 };
+        buffers[index].pins += 1;
+        buffers[index].last_used = next_tick();
+        return Ok(index);
+    }
+    unsafe { MISSES += 1 };
+
+    let disk = block::get(device).ok_or(BlockError::NotInitialized)?;
+
+    // Prefer a buffer that was never used, then the least recently used one
+    let index = buffers
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.pins == 0)
+        .min_by_key(|(_, b)| if b.valid { b.last_used } else { 0 })
+        .map(|(index, _)| index)
+        .expect("Every buffer of the cache is in use");
+
+    let buffer = &mut buffers[index];
+    write_back(buffer)?;
+
+    buffer.valid = false;
+    if buffer.data.len() != disk.sector_size() {
+        buffer.data = vec![0; disk.sector_size()];
+    }
+
+    if load {
+        disk.read(sector, &mut buffer.data)?;
+    }
+
+    buffer.device = device;
+    buffer.sector = sector;
+    buffer.valid = true;
+    buffer.dirty = false;
+    buffer.pins = 1;
+    buffer.last_used = next_tick();
+
+    Ok(index)
+}
+
+// Call f with the content of the sector
+pub fn with_sector<R, F: FnOnce(&[u8]) -> R>(
+    device: usize,
+    sector: u64,
+    f: F,
+) -> Result<R, BlockError> {
+    let index = get_buffer(device, sector, true)?;
+    let result = f(&buffers()[index].data);
+    buffers()[index].pins -= 1;
+    Ok(result)
+}
+
+// Call f with the content of the sector and mark it dirty
+pub fn with_sector_mut<R, F: FnOnce(&mut [u8]) -> R>(
+    device: usize,
+    sector: u64,
+    f: F,
+) -> Result<R, BlockError> {
+    let index = get_buffer(device, sector, true)?;
+    let result = f(&mut buffers()[index].data);
+    buffers()[index].dirty = true;
+    buffers()[index].pins -= 1;
+    Ok(result)
+}
+
+// Copy bytes of the sector starting at offset into buf
+pub fn read(device: usize, sector: u64, offset: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+    let sector_size = block::get(device)
+        .ok_or(BlockError::NotInitialized)?
+        .sector_size();
+    if offset + buf.len() > sector_size {
+        return Err(BlockError::InvalidBuffer);
+    }
+
+    with_sector(device, sector, |data| {
+        buf.copy_from_slice(&data[offset..offset + buf.len()])
+    })
+}
+
+// Copy buf into the sector starting at offset. Overwriting a whole sector
+// does not read it from the device first.
+pub fn write(device: usize, sector: u64, offset: usize, buf: &[u8]) -> Result<(), BlockError> {
+    let sector_size = block::get(device)
+        .ok_or(BlockError::NotInitialized)?
+        .sector_size();
+    if offset + buf.len() > sector_size {
+        return Err(BlockError::InvalidBuffer);
+    }
+
+    let index = get_buffer(device, sector, offset != 0 || buf.len() != sector_size)?;
+    let buffer = &mut buffers()[index];
+    buffer.data[offset..offset + buf.len()].copy_from_slice(buf);
+    buffer.dirty = true;
+    buffer.pins -= 1;
+    Ok(())
+}
+
+// Write every dirty buffer of the device back and flush it
+pub fn sync_device(device: usize) -> Result<(), BlockError> {
+    let (disk, range) = extent(device)?;
+    for buffer in buffers()
+        .iter_mut()
+        .filter(|b| b.device == disk && range.contains(&b.sector))
+    {
+        write_back(buffer)?;
+    }
+
+    let disk = block::get(device).ok_or(BlockError::NotInitialized)?;
+    if disk.is_read_only() {
+        return Ok(());
+    }
+    disk.flush()
+}
+
+pub fn sync() -> Result<(), BlockError> {
+    for device in 0..block::count() {
+        sync_device(device)?;
+    }
+    Ok(())
+}
+
+// Drop the cached sectors of a device, dirty buffers are written back first
+pub fn invalidate_device(device: usize) -> Result<(), BlockError> {
+    let (disk, range) = extent(device)?;
+    for buffer in buffers()
+        .iter_mut()
+        .filter(|b| b.device == disk && range.contains(&b.sector))
+    {
+        assert_eq!(buffer.pins, 0, "Invalidating a buffer in use");
+        write_back(buffer)?;
+        buffer.valid = false;
+    }
+    Ok(())
+}
+
+pub fn stats() -> CacheStats {
+    unsafe {
+        CacheStats {
+            hits: HITS,
+            misses: MISSES,
+            dirty: buffers().iter().filter(|b| b.valid && b.dirty).count(),
+        }
+    }
+}
+
+pub fn init() {}
+
+pub fn init_sanity_check() {
+    if block::count() == 0 {
+        return;
+    }
+
+    let device = 0;
+    let disk = block::get(device).unwrap();
+    let mut first = vec![0u8; disk.sector_size()];
+    disk.read(0, &mut first)
+        .expect("Cannot read the first sector");
+
+    // The second read of a sector is served from the cache
+    let before = stats();
+    let mut cached = vec![0u8; disk.sector_size()];
+    read(device, 0, 0, &mut cached).expect("Cannot read the first sector");
+    read(device, 0, 0, &mut cached).expect("Cannot read the first sector");
+    assert!(cached == first, "Cache returned different data");
+    assert_eq!(
+        stats().hits,
+        before.hits + 1,
+        "Second read missed the cache"
+    );
+
+    // Touch more sectors than the cache holds, sector 0 must get evicted
+    let sectors = (CACHE_SIZE as u64 + 1).min(disk.num_sectors());
+    for sector in 1..sectors {
+        with_sector(device, sector, |_| ()).expect("Cannot read a sector");
+    }
+    if sectors > CACHE_SIZE as u64 {
+        let misses = stats().misses;
+        read(device, 0, 0, &mut cached).expect("Cannot read the first sector");
+        assert_eq!(
+            stats().misses,
+            misses + 1,
+            "Least recently used sector was kept"
+        );
+    }
+
+    // Dropped sectors are read from the device again
+    invalidate_device(device).expect("Cannot invalidate the cache");
+    let misses = stats().misses;
+    read(device, 0, 0, &mut cached).expect("Cannot read the first sector");
+    assert_eq!(stats().misses, misses + 1, "Invalidated sector was kept");
+    assert!(cached == first, "Sector changed on the device");
+
+    // A partition goes through the buffers of its disk
+    for id in 0..block::count() {
+        let partition = block::get(id).unwrap();
+        if let Some((disk, start)) = partition.window() {
+            read(id, 0, 0, &mut cached).expect("Cannot read a partition");
+            let hits = stats().hits;
+            let mut on_disk = vec![0u8; partition.sector_size()];
+            read(disk, start, 0, &mut on_disk).expect("Cannot read a disk");
+            assert_eq!(stats().hits, hits + 1, "Partition has its own buffers");
+            assert!(cached == on_disk);
+        }
+    }
+    assert_eq!(
+        read(device, 0, 1, &mut cached),
+        Err(BlockError::InvalidBuffer)
+    );
+
+    // Writes stay in the cache until sync. The disks belong to the user,
+    // this is checked on the scratch disk.
+    let scratch = match ramdisk::scratch() {
+        Some(scratch) => scratch,
+        None => return,
+    };
+    let disk = block::get(scratch).unwrap();
+    let data = vec![0xa5u8; disk.sector_size()];
+    let mut on_disk = vec![0u8; disk.sector_size()];
+    write(scratch, 0, 0, &data).expect("Cannot write a sector");
+    with_sector_mut(scratch, 1, |sector| sector[0] = 1).expect("Cannot write a sector");
+    assert_eq!(stats().dirty, 2, "Written buffers are not dirty");
+    disk.read(0, &mut on_disk).expect("Cannot read a sector");
+    assert!(on_disk != data, "Write went past the cache");
+
+    sync_device(scratch).expect("Cannot sync the buffer cache");
+    assert_eq!(stats().dirty, 0, "Sync left dirty buffers");
+    disk.read(0, &mut on_disk).expect("Cannot read a sector");
+    assert!(on_disk == data, "Sync did not write the sector");
+    let _ = ramdisk::scratch();
+}
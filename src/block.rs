@@ -1,31 +1,306 @@
-use crate::page_allocator::{alloc, dealloc, PAGE_SIZE};
-use crate::virtio;
+use crate::slab::SlabCache;
+use crate::{devfs, lock, plic, reg, virtio};
 
-use crate::virtio::{Descriptor, MmioOffset, Queue, VIRTIO_DESC_F_NEXT, VIRTIO_RING_SIZE};
+use crate::virtio::{Buffer, MmioOffset, Virtqueue, VIRTIO_RING_SIZE};
 use core::arch::asm;
 use core::ptr;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
 
 pub fn init() {}
 
-pub fn init_sanity_check() {}
+static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+fn count_completion(result: Result<(), BlockError>, _context: usize) {
+    assert!(result.is_ok(), "Queued read failed");
+    COMPLETED.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn init_sanity_check() {
+    for id in 0..count() {
+        let device = get(id).unwrap();
+        let sector_size = device.sector_size();
+
+        let mut original = vec![0u8; sector_size];
+        device
+            .read(0, &mut original)
+            .expect("Cannot read the first sector");
+
+        // The check stays read-only, the disk belongs to the user
+        let mut again = vec![0u8; sector_size];
+        device
+            .read(0, &mut again)
+            .expect("Cannot read the first sector");
+        assert!(original == again, "Sector changed between two reads");
+
+        let mut odd = vec![0u8; sector_size + 1];
+        assert_eq!(device.read(0, &mut odd), Err(BlockError::InvalidBuffer));
+        assert_eq!(
+            device.read(device.num_sectors(), &mut original),
+            Err(BlockError::OutOfRange)
+        );
+    }
+
+    for virtio_block in virtio_devices() {
+        let mut original = [0u8; SECTOR_SIZE];
+        virtio_block
+            .read(0, &mut original)
+            .expect("Cannot read the first sector");
+
+        // Queue more requests than the virtqueue can hold at once
+        let requests = 2 * MAX_IN_FLIGHT + 1;
+        let mut buffers: Vec<u8> = vec![0; requests * SECTOR_SIZE];
+        COMPLETED.store(0, Ordering::SeqCst);
+        for i in 0..requests {
+            unsafe {
+                virtio_block
+                    .submit_with_callback(
+                        Operation::Read,
+                        0,
+                        buffers.as_mut_ptr().add(i * SECTOR_SIZE),
+                        SECTOR_SIZE,
+                        count_completion,
+                        i,
+                    )
+                    .expect("Cannot queue a read");
+            }
+        }
+        virtio_block.wait_idle();
+        assert_eq!(COMPLETED.load(Ordering::SeqCst), requests);
+        assert!(buffers.chunks(SECTOR_SIZE).all(|chunk| chunk == original));
+
+        let mut buffer = [0u8; SECTOR_SIZE];
+        let ticket =
+            unsafe { virtio_block.submit(Operation::Read, 0, buffer.as_mut_ptr(), SECTOR_SIZE) }
+                .expect("Cannot queue a read");
+        virtio_block.wait_idle();
+        assert!(ticket.is_done());
+        ticket.wait().expect("Cannot read the first sector");
+        assert!(buffer == original);
+
+        // A ticket dropped without waiting still waits and gives the request back
+        let requests_before = unsafe { (*(&raw const REQUEST_CACHE)).stats().objects_in_use };
+        buffer.fill(0);
+        drop(unsafe { virtio_block.submit(Operation::Read, 0, buffer.as_mut_ptr(), SECTOR_SIZE) });
+        assert!(buffer == original);
+        assert_eq!(
+            unsafe { (*(&raw const REQUEST_CACHE)).stats().objects_in_use },
+            requests_before,
+            "Dropped ticket leaked its request"
+        );
+
+        // Every descriptor must be back on the free list
+        assert_eq!(
+            virtio_block.state().queue.num_free(),
+            VIRTIO_RING_SIZE,
+            "Descriptors are leaking"
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    NotInitialized,
+    // The buffer is not a multiple of the sector size
+    InvalidBuffer,
+    // The request goes past the end of the device
+    OutOfRange,
+    ReadOnly,
+    IoError,
+    Unsupported,
+    // The device did not write the status byte
+    NoResponse,
+    // No memory left to track the request
+    OutOfMemory,
+}
+
+//----------- Block device registry ---------------//
+
+pub trait BlockDevice {
+    fn name(&self) -> &str;
+
+    // Number of bytes addressed by one sector
+    fn sector_size(&self) -> usize;
+
+    fn num_sectors(&self) -> u64;
+
+    fn is_read_only(&self) -> bool;
+
+    // Read buf.len() / sector_size() sectors starting at sector
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    // Write buf.len() / sector_size() sectors starting at sector
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
 
-pub struct BlockDevice {
-    queue: *mut Queue,
+    fn flush(&self) -> Result<(), BlockError>;
+
+    // Registry id of the disk and first sector on it when the device is a
+    // window of another device
+    fn window(&self) -> Option<(usize, u64)> {
+        None
+    }
+}
+
+pub const MAX_BLOCK_DEVICES: usize = 16;
+
+static mut REGISTRY: [Option<&'static dyn BlockDevice>; MAX_BLOCK_DEVICES] =
+    [None; MAX_BLOCK_DEVICES];
+static mut REGISTERED: usize = 0;
+
+// Make a device available to the rest of the kernel and in /dev, returns its id
+pub fn register(device: &'static dyn BlockDevice) -> Option<usize> {
+    let id = lock::without_interrupts(|| unsafe {
+        if REGISTERED == MAX_BLOCK_DEVICES {
+            return None;
+        }
+
+        REGISTRY[REGISTERED] = Some(device);
+        REGISTERED += 1;
+        Some(REGISTERED - 1)
+    })?;
+
+    let _ = devfs::register_block(id);
+    Some(id)
+}
+
+pub fn count() -> usize {
+    unsafe { REGISTERED }
+}
+
+pub fn get(id: usize) -> Option<&'static dyn BlockDevice> {
+    unsafe {
+        if id < REGISTERED {
+            REGISTRY[id]
+        } else {
+            None
+        }
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static dyn BlockDevice> {
+    (0..count())
+        .filter_map(get)
+        .find(|device| device.name() == name)
+}
+
+// Number of sectors covered by buf, after checking it fits on the device
+pub fn check_request(device: &dyn BlockDevice, sector: u64, len: usize) -> Result<u64, BlockError> {
+    if len % device.sector_size() != 0 {
+        return Err(BlockError::InvalidBuffer);
+    }
+
+    let count = (len / device.sector_size()) as u64;
+    if sector
+        .checked_add(count)
+        .map_or(true, |end| end > device.num_sectors())
+    {
+        return Err(BlockError::OutOfRange);
+    }
+
+    Ok(count)
+}
+
+//----------- Virtio block driver ---------------//
+
+pub const MAX_VIRTIO_BLOCK_DEVICES: usize = 8;
+
+const DEVICE_NAMES: [&str; MAX_VIRTIO_BLOCK_DEVICES] =
+    ["vda", "vdb", "vdc", "vdd", "vde", "vdf", "vdg", "vdh"];
+
+// Feature bits of the block device we know how to use
+const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
+const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
+const SUPPORTED_FEATURES: u32 =
+    VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+
+// Layout of the device configuration, relative to MmioOffset::Config
+const CONFIG_CAPACITY: usize = 0x0;
+const CONFIG_SIZE_MAX: usize = 0x8;
+const CONFIG_BLK_SIZE: usize = 0x14;
+
+pub struct VirtioBlockDevice {
+    queue: Virtqueue,
     dev: *mut u32,
-    idx: u16,
+    read_only: bool,
+    irq: u32,
+    // Size of the disk in 512 bytes sectors
+    capacity: u64,
+    // Logical block size advertised by the device
+    block_size: usize,
+    // Largest data buffer the device accepts in a single request
+    max_transfer: usize,
+    // Requests submitted to the device, indexed by their head descriptor
+    in_flight: [*mut Request; VIRTIO_RING_SIZE],
+    in_flight_count: usize,
+    // Requests submitted while MAX_IN_FLIGHT requests are already in the virtqueue
+    pending_head: *mut Request,
+    pending_tail: *mut Request,
 }
 
-pub static mut VIRTIO_BLOCK_DEVICE: BlockDevice = BlockDevice {
-    queue: null_mut(),
-    dev: null_mut(),
-    idx: 0,
+impl VirtioBlockDevice {
+    const fn empty() -> Self {
+        VirtioBlockDevice {
+            queue: Virtqueue::empty(),
+            dev: null_mut(),
+            read_only: false,
+            irq: 0,
+            capacity: 0,
+            block_size: SECTOR_SIZE,
+            max_transfer: 0,
+            in_flight: [null_mut(); VIRTIO_RING_SIZE],
+            in_flight_count: 0,
+            pending_head: null_mut(),
+            pending_tail: null_mut(),
+        }
+    }
+}
+
+const EMPTY_DEVICE: VirtioBlockDevice = VirtioBlockDevice::empty();
+
+static mut VIRTIO_BLOCK_DEVICES: [VirtioBlockDevice; MAX_VIRTIO_BLOCK_DEVICES] =
+    [EMPTY_DEVICE; MAX_VIRTIO_BLOCK_DEVICES];
+static mut VIRTIO_BLOCK_COUNT: usize = 0;
+
+// Handle to one of the virtio disks, this is what goes in the registry
+pub struct VirtioBlock {
+    index: usize,
+}
+
+static VIRTIO_BLOCKS: [VirtioBlock; MAX_VIRTIO_BLOCK_DEVICES] = {
+    let mut handles = [const { VirtioBlock { index: 0 } }; MAX_VIRTIO_BLOCK_DEVICES];
+    let mut i = 0;
+    while i < MAX_VIRTIO_BLOCK_DEVICES {
+        handles[i] = VirtioBlock { index: i };
+        i += 1;
+    }
+    handles
 };
 
-pub fn initialize_block_device(pointer: *mut u32) -> bool {
+pub fn virtio_devices() -> &'static [VirtioBlock] {
+    unsafe { &VIRTIO_BLOCKS[..VIRTIO_BLOCK_COUNT] }
+}
+
+fn read_config_u32(dev: *mut u32, offset: usize) -> u32 {
+    unsafe {
+        dev.add((MmioOffset::Config as usize + offset) / 4)
+            .read_volatile()
+    }
+}
+
+pub fn initialize_block_device(pointer: *mut u32, irq: u32) -> bool {
     let mut current_status_bit: u32 = 0;
 
     unsafe {
+        if VIRTIO_BLOCK_COUNT == MAX_VIRTIO_BLOCK_DEVICES {
+            return false;
+        }
+
         // 1. Reset the device.
         current_status_bit |= virtio::StatusField::Acknowledge as u32;
         pointer
@@ -49,8 +324,9 @@ pub fn initialize_block_device(pointer: *mut u32) -> bool {
             .read_volatile();
 
         pointer
-            .add(virtio::MmioOffset::DeviceFeatures as usize / 4)
-            .write_volatile(features);
+            .add(virtio::MmioOffset::DriverFeatures as usize / 4)
+            .write_volatile(features & SUPPORTED_FEATURES);
+        let features = features & SUPPORTED_FEATURES;
 
         // 5. Set the FEATURES_OK status bit. The driver MUST NOT accept new feature bits after this step.
         current_status_bit |= virtio::StatusField::FeaturesOk as u32;
@@ -69,48 +345,46 @@ pub fn initialize_block_device(pointer: *mut u32) -> bool {
         };
 
         // 7. Perform device-specific setup, including discovery of virtqueues for the device,optional per-bussetup, reading and possibly writing the device’s virtio configuration space, and population of virtqueues.
-        let number_queues = pointer
-            .add(virtio::MmioOffset::QueueNumMax as usize / 4)
-            .read_volatile();
+        assert_ne!(pointer, null_mut(), "dev is null, allocation failed");
+        let queue = match Virtqueue::setup(pointer, 0) {
+            Some(queue) => queue,
+            None => panic!("queue fails..."),
+        };
 
-        // This must be a power of two
-        if VIRTIO_RING_SIZE > number_queues as usize {
-            panic!("queue fails...");
+        let index = VIRTIO_BLOCK_COUNT;
+        let device = &mut (*(&raw mut VIRTIO_BLOCK_DEVICES))[index];
+        device.queue = queue;
+        device.dev = pointer;
+        device.read_only = features & VIRTIO_BLK_F_RO != 0;
+        device.irq = irq;
+
+        // The capacity is a 64-bit value split in two registers
+        device.capacity = read_config_u32(pointer, CONFIG_CAPACITY) as u64
+            | (read_config_u32(pointer, CONFIG_CAPACITY + 4) as u64) << 32;
+
+        device.block_size = SECTOR_SIZE;
+        if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+            let block_size = read_config_u32(pointer, CONFIG_BLK_SIZE) as usize;
+            if block_size >= SECTOR_SIZE && block_size % SECTOR_SIZE == 0 {
+                device.block_size = block_size;
+            }
         }
 
-        // Writes the size of the queue
-        pointer
-            .add(virtio::MmioOffset::QueueNum as usize / 4)
-            .write_volatile(VIRTIO_RING_SIZE as u32);
-
-        // Selects the queue
-        pointer
-            .add(virtio::MmioOffset::QueueSel as usize / 4)
-            .write_volatile(0);
-
-        // Negotiates the page size with the host
-        pointer
-            .add(virtio::MmioOffset::GuestPageSize as usize / 4)
-            .write_volatile(PAGE_SIZE as u32);
-
-        let num_pages = (core::mem::size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-        let queue_ptr = alloc(num_pages) as *mut Queue;
-
-        // Physical memory address shifted by the page size
-        pointer
-            .add(virtio::MmioOffset::QueuePfn as usize / 4)
-            .write_volatile((queue_ptr as u32) / PAGE_SIZE as u32);
-
-        assert_ne!(
-            queue_ptr,
-            null_mut(),
-            "queue_ptr is null, allocation failed"
-        );
-        assert_ne!(pointer, null_mut(), "dev is null, allocation failed");
+        // A request only carries a single data descriptor
+        device.max_transfer = usize::MAX;
+        if features & VIRTIO_BLK_F_SIZE_MAX != 0 {
+            let size_max = read_config_u32(pointer, CONFIG_SIZE_MAX) as usize;
+            device.max_transfer = (size_max / device.block_size) * device.block_size;
+        }
+        // A size_max below the block size would never let a transfer advance
+        device.max_transfer = device
+            .max_transfer
+            .min(u32::MAX as usize & !(PAGE_ALIGN - 1))
+            .max(device.block_size);
 
-        VIRTIO_BLOCK_DEVICE.queue = queue_ptr;
-        VIRTIO_BLOCK_DEVICE.dev = pointer;
-        VIRTIO_BLOCK_DEVICE.idx = 0;
+        // Completions are signaled through the plic
+        plic::enable_device(irq);
+        plic::set_priority(irq, 1);
 
         // 8. Set the DRIVER_OK status bit. At this point the device is “live”.
         current_status_bit |= virtio::StatusField::DriverOk as u32;
@@ -118,6 +392,9 @@ pub fn initialize_block_device(pointer: *mut u32) -> bool {
             .add(virtio::MmioOffset::Status as usize / 4)
             .write_volatile(current_status_bit);
 
+        VIRTIO_BLOCK_COUNT += 1;
+        register(&VIRTIO_BLOCKS[index]);
+
         // Now we can use the virtio block driver
         true
     }
@@ -148,125 +425,394 @@ pub struct Status {
     status: u8,
 }
 
+// Called once the device is done with a request submitted with submit_with_callback.
+// Runs with interrupts disabled, usually from the trap handler.
+pub type Callback = fn(result: Result<(), BlockError>, context: usize);
+
 #[repr(C)]
 pub struct Request {
     header: Header,
     data: Data,
     status: Status,
     head: u16,
+    // Set once the device gave the descriptor chain back
+    done: bool,
+    // Index of the virtio device the request belongs to
+    device: usize,
+    len: usize,
+    callback: Option<Callback>,
+    context: usize,
+    // Requests waiting for a free slot are linked together
+    next: *mut Request,
 }
 
-fn is_next_flag_set(flag: u16) -> bool {
-    flag & VIRTIO_DESC_F_NEXT != 0
+static mut REQUEST_CACHE: SlabCache = SlabCache::for_type::<Request>("block_request");
+
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+// Written by us before submitting, the device overwrites it when done
+const VIRTIO_BLK_S_PENDING: u8 = 0xff;
+
+// Virtio always addresses the disk in 512 bytes sectors
+pub const SECTOR_SIZE: usize = 512;
+
+// Chunks of big transfers stay page aligned
+const PAGE_ALIGN: usize = 4096;
+
+// Each request uses a header, a data and a status descriptor
+pub const MAX_IN_FLIGHT: usize = VIRTIO_RING_SIZE / 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Flush,
 }
 
-pub fn fill_next_descriptor(desc: Descriptor) -> u16 {
-    unsafe {
-        let current_idx: u16 = VIRTIO_BLOCK_DEVICE.idx;
-        let next_idx = (current_idx + 1) % VIRTIO_RING_SIZE as u16;
+// A submitted request the caller has to wait on. Dropping it waits as well,
+// the device may still be using the buffer and the request.
+#[must_use = "the buffer must stay valid until the request is waited on"]
+pub struct Ticket {
+    request: *mut Request,
+}
+
+impl Ticket {
+    pub fn is_done(&self) -> bool {
+        unsafe { ptr::read_volatile(&(*self.request).done) }
+    }
+
+    // Block until the device completed the request, the request is freed
+    // when the ticket goes away
+    pub fn wait(self) -> Result<(), BlockError> {
+        self.wait_done();
+        unsafe { request_result(self.request) }
+    }
+
+    fn wait_done(&self) {
+        let request = self.request;
+        unsafe { wait_until((*request).device, || ptr::read_volatile(&(*request).done)) };
+    }
+}
 
-        (*VIRTIO_BLOCK_DEVICE.queue).desc[current_idx as usize] = desc;
-        if is_next_flag_set((*VIRTIO_BLOCK_DEVICE.queue).desc[current_idx as usize].flags) {
-            // If the next flag is set, we need another descriptor.
-            (*VIRTIO_BLOCK_DEVICE.queue).desc[current_idx as usize].next = next_idx;
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.wait_done();
+        unsafe { (*(&raw mut REQUEST_CACHE)).free(self.request as *mut u8) };
+    }
+}
+
+impl VirtioBlock {
+    fn state(&self) -> &'static mut VirtioBlockDevice {
+        unsafe { &mut (*(&raw mut VIRTIO_BLOCK_DEVICES))[self.index] }
+    }
+
+    // Queue a request without waiting for it. The sector is in 512 bytes units and
+    // the buffer must stay valid until the ticket reports the request as done.
+    pub unsafe fn submit(
+        &self,
+        operation: Operation,
+        sector: u64,
+        data: *mut u8,
+        len: usize,
+    ) -> Result<Ticket, BlockError> {
+        let request = self.new_request(operation, sector, data, len, None, 0)?;
+        enqueue(request);
+        Ok(Ticket { request })
+    }
+
+    // Queue a request and call back once it completed. The buffer must stay
+    // valid until the callback ran.
+    pub unsafe fn submit_with_callback(
+        &self,
+        operation: Operation,
+        sector: u64,
+        data: *mut u8,
+        len: usize,
+        callback: Callback,
+        context: usize,
+    ) -> Result<(), BlockError> {
+        let request = self.new_request(operation, sector, data, len, Some(callback), context)?;
+        enqueue(request);
+        Ok(())
+    }
+
+    // Block until every submitted request completed
+    pub fn wait_idle(&self) {
+        let state = self.state();
+        unsafe {
+            wait_until(self.index, || {
+                state.in_flight_count == 0 && state.pending_head.is_null()
+            })
         }
+    }
 
-        VIRTIO_BLOCK_DEVICE.idx = next_idx as u16;
+    unsafe fn new_request(
+        &self,
+        operation: Operation,
+        sector: u64,
+        data: *mut u8,
+        len: usize,
+        callback: Option<Callback>,
+        context: usize,
+    ) -> Result<*mut Request, BlockError> {
+        let state = self.state();
+        if !state.queue.is_ready() {
+            return Err(BlockError::NotInitialized);
+        }
+        if operation == Operation::Write && state.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+
+        let block_request = (*(&raw mut REQUEST_CACHE)).alloc() as *mut Request;
+        if block_request.is_null() {
+            return Err(BlockError::OutOfMemory);
+        }
+
+        (*block_request).header.sector = sector;
+        (*block_request).header.blktype = match operation {
+            Operation::Read => VIRTIO_BLK_T_IN,
+            Operation::Write => VIRTIO_BLK_T_OUT,
+            Operation::Flush => VIRTIO_BLK_T_FLUSH,
+        };
+        (*block_request).header.reserved = 0;
+        (*block_request).data.data = data;
+        (*block_request).status.status = VIRTIO_BLK_S_PENDING;
+        (*block_request).done = false;
+        (*block_request).device = self.index;
+        (*block_request).len = len;
+        (*block_request).callback = callback;
+        (*block_request).context = context;
+        (*block_request).next = null_mut();
+
+        Ok(block_request)
+    }
+
+    // Split a transfer in requests the device accepts and wait for all of them
+    fn transfer(
+        &self,
+        operation: Operation,
+        sector: u64,
+        data: *mut u8,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let state = self.state();
+        let sectors_per_block = (state.block_size / SECTOR_SIZE) as u64;
+        let mut virtio_sector = sector * sectors_per_block;
+        let mut offset = 0;
+
+        while offset < len {
+            let chunk = (len - offset).min(state.max_transfer);
+            unsafe {
+                self.submit(operation, virtio_sector, data.add(offset), chunk)?
+                    .wait()?
+            };
+
+            offset += chunk;
+            virtio_sector += (chunk / SECTOR_SIZE) as u64;
+        }
 
-        current_idx
+        Ok(())
     }
 }
 
-pub const VIRTIO_BLK_T_IN: u32 = 0;
-pub const BUFFER_LEN: usize = 512;
-
-pub unsafe fn read_block_device(_sector_idx: usize) -> [u8; BUFFER_LEN] {
-    // Safety assertions
-    assert_ne!(
-        VIRTIO_BLOCK_DEVICE.queue,
-        null_mut(),
-        "It seems the block driver is not initalized"
-    );
-
-    let block_request_size = size_of::<Request>();
-    assert!(
-        block_request_size < 4096,
-        "Block request size needs more space"
-    );
-
-    //----------- The block request  ---------------//
-
-    // TODO: Implement better allocation here
-    let block_request = alloc(1) as *mut Request;
-
-    let buffer = alloc(1) as *mut u8;
-    (*block_request).header.sector = 0 as u64;
-    // VIRTIO_BLK_T_IN -> block read
-    (*block_request).header.blktype = VIRTIO_BLK_T_IN;
-    (*block_request).data.data = buffer;
-    (*block_request).header.reserved = 0;
-    (*block_request).status.status = 0;
-
-    //----------- Descriptor ring 1  ---------------//
-
-    let descriptor = Descriptor {
-        addr: &(*block_request).header as *const Header as u64,
-        len: size_of::<Header>() as u32,
-        flags: virtio::VIRTIO_DESC_F_NEXT,
-        next: 1,
-    };
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        DEVICE_NAMES[self.index]
+    }
 
-    let head_idx: usize = fill_next_descriptor(descriptor) as usize;
+    fn sector_size(&self) -> usize {
+        self.state().block_size
+    }
 
-    //----------- Descriptor ring 2  ---------------//
+    fn num_sectors(&self) -> u64 {
+        let state = self.state();
+        state.capacity / (state.block_size / SECTOR_SIZE) as u64
+    }
 
-    let desc = Descriptor {
-        addr: buffer as u64,
-        len: BUFFER_LEN as u32,
-        flags: virtio::VIRTIO_DESC_F_NEXT | virtio::VIRTIO_DESC_F_WRITE,
-        next: 2,
-    };
+    fn is_read_only(&self) -> bool {
+        self.state().read_only
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+        self.transfer(Operation::Read, sector, buf.as_mut_ptr(), buf.len())
+    }
 
-    fill_next_descriptor(desc);
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+
+        // The device only reads from the buffer for a write request
+        self.transfer(Operation::Write, sector, buf.as_ptr() as *mut u8, buf.len())
+    }
 
-    //----------- Descriptor ring 3  ---------------//
+    fn flush(&self) -> Result<(), BlockError> {
+        unsafe { self.submit(Operation::Flush, 0, null_mut(), 0)?.wait() }
+    }
+}
+
+// Hand the request to the device, or park it until a slot frees up
+unsafe fn enqueue(request: *mut Request) {
+    let state = VIRTIO_BLOCKS[(*request).device].state();
+
+    lock::without_interrupts(|| {
+        if state.in_flight_count < MAX_IN_FLIGHT {
+            start(state, request);
+        } else if state.pending_tail.is_null() {
+            state.pending_head = request;
+            state.pending_tail = request;
+        } else {
+            (*state.pending_tail).next = request;
+            state.pending_tail = request;
+        }
+    });
+}
+
+// Build the header/data/status descriptor chain and notify the device.
+// Must be called with interrupts disabled.
+unsafe fn start(state: &mut VirtioBlockDevice, block_request: *mut Request) {
+    //----------- Descriptor chain  ---------------//
+
+    let mut buffers = [Buffer {
+        addr: &(*block_request).header as *const Header as u64,
+        len: size_of::<Header>() as u32,
+        device_writable: false,
+    }; 3];
+    let mut count = 1;
+
+    // Flush requests do not carry any data
+    if (*block_request).len > 0 {
+        // The device writes into the buffer when we read from the disk
+        buffers[count] = Buffer {
+            addr: (*block_request).data.data as u64,
+            len: (*block_request).len as u32,
+            device_writable: (*block_request).header.blktype == VIRTIO_BLK_T_IN,
+        };
+        count += 1;
+    }
 
-    let desc = Descriptor {
+    buffers[count] = Buffer {
         addr: &(*block_request).status as *const Status as u64,
         len: size_of::<Status>() as u32,
-        flags: virtio::VIRTIO_DESC_F_WRITE,
-        next: 0,
+        device_writable: true,
     };
-
-    fill_next_descriptor(desc);
+    count += 1;
 
     //----------- Submit to the queue ---------------//
 
-    asm!("sfence.vma");
-    (*(VIRTIO_BLOCK_DEVICE.queue)).avail.ring[(*VIRTIO_BLOCK_DEVICE.queue).avail.idx as usize] =
-        head_idx as u16;
-    (*VIRTIO_BLOCK_DEVICE.queue).avail.idx += 1;
-    (*VIRTIO_BLOCK_DEVICE.queue).avail.idx %= VIRTIO_RING_SIZE as u16;
-    asm!("sfence.vma");
+    // MAX_IN_FLIGHT guarantees there are enough free descriptors
+    let head = state
+        .queue
+        .add_chain(&buffers[..count])
+        .expect("Virtqueue is full");
+    (*block_request).head = head;
+    state.in_flight[head as usize] = block_request;
+    state.in_flight_count += 1;
 
-    VIRTIO_BLOCK_DEVICE
+    state
         .dev
         .add(MmioOffset::QueueNotify as usize / 4)
         .write_volatile(0);
+}
 
-    //----------- Notify the device ---------------//
+unsafe fn request_result(request: *mut Request) -> Result<(), BlockError> {
+    match ptr::read_volatile(&(*request).status.status) {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_IOERR => Err(BlockError::IoError),
+        VIRTIO_BLK_S_UNSUPP => Err(BlockError::Unsupported),
+        _ => Err(BlockError::NoResponse),
+    }
+}
 
-    // TODO: Implement blocking properly here
-    for _ in 0..100000 {}
+// Owner of the given plic interrupt line
+pub fn is_block_irq(irq: u32) -> bool {
+    virtio_devices()
+        .iter()
+        .any(|device| device.state().irq == irq)
+}
 
-    let mut output: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+// Called from the trap handler when a disk signals completions
+pub fn handle_interrupt(irq: u32) {
+    for device in virtio_devices() {
+        let state = device.state();
+        if state.irq != irq {
+            continue;
+        }
 
-    unsafe {
-        ptr::copy_nonoverlapping(buffer, output.as_mut_ptr(), BUFFER_LEN);
+        unsafe {
+            let status = state
+                .dev
+                .add(MmioOffset::InterruptStatus as usize / 4)
+                .read_volatile();
+            state
+                .dev
+                .add(MmioOffset::InterruptAck as usize / 4)
+                .write_volatile(status);
+
+            process_used_ring(state);
+        }
     }
+}
 
-    // Now we can free the buffer (after copying the data)
-    dealloc(buffer);
+// Walk the used ring, complete the requests the device is done with and
+// start the pending ones in the freed slots.
+unsafe fn process_used_ring(state: &mut VirtioBlockDevice) {
+    while let Some((head, _)) = state.queue.pop_used() {
+        let request = state.in_flight[head as usize];
+        if request.is_null() {
+            continue;
+        }
 
-    output
+        state.in_flight[head as usize] = null_mut();
+        state.in_flight_count -= 1;
+
+        match (*request).callback {
+            Some(callback) => {
+                callback(request_result(request), (*request).context);
+                (*(&raw mut REQUEST_CACHE)).free(request as *mut u8);
+            }
+            None => ptr::write_volatile(&mut (*request).done, true),
+        }
+    }
+
+    while state.in_flight_count < MAX_IN_FLIGHT && !state.pending_head.is_null() {
+        let request = state.pending_head;
+        state.pending_head = (*request).next;
+        if state.pending_head.is_null() {
+            state.pending_tail = null_mut();
+        }
+        (*request).next = null_mut();
+        start(state, request);
+    }
+}
+
+// Sleep until the condition holds. Interrupts are not enabled yet while the
+// kernel initializes, in that case we poll the used ring instead.
+unsafe fn wait_until<F: Fn() -> bool>(device: usize, condition: F) {
+    const SIE_SEIE: usize = 1 << 9;
+    let state = VIRTIO_BLOCKS[device].state();
+
+    loop {
+        let done = lock::without_interrupts(|| {
+            process_used_ring(state);
+            if condition() {
+                return true;
+            }
+
+            // wfi wakes up on a pending interrupt even with interrupts masked,
+            // so the completion cannot slip in between the check and the wait.
+            if reg::sie_read() & SIE_SEIE != 0 {
+                asm!("wfi");
+            }
+            false
+        });
+
+        if done {
+            break;
+        }
+    }
 }
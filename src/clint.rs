@@ -0,0 +1,37 @@
+// Core local interruptor, only the SBI firmware running in machine mode uses it
+const MSIP_OFFSET: usize = 0x0;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xbff8;
+
+// With the firmware state, out of the way of the kernel statics
+#[link_section = ".sbi.data"]
+static mut CLINT_BASE: usize = 0x0200_0000;
+
+pub fn init(base_address: usize) {
+    unsafe {
+        CLINT_BASE = base_address;
+    }
+}
+
+pub fn init_sanity_check() {
+    let first = mtime();
+    for _ in 0..1000 {}
+    assert!(mtime() >= first, "mtime is going backwards");
+}
+
+pub fn mtime() -> u64 {
+    unsafe { ((CLINT_BASE + MTIME_OFFSET) as *const u64).read_volatile() }
+}
+
+pub fn mtimecmp(hart: usize) -> u64 {
+    unsafe { ((CLINT_BASE + MTIMECMP_OFFSET + 8 * hart) as *const u64).read_volatile() }
+}
+
+pub fn set_mtimecmp(hart: usize, value: u64) {
+    unsafe { ((CLINT_BASE + MTIMECMP_OFFSET + 8 * hart) as *mut u64).write_volatile(value) }
+}
+
+// Raise or clear the machine software interrupt of a hart
+pub fn set_msip(hart: usize, pending: bool) {
+    unsafe { ((CLINT_BASE + MSIP_OFFSET + 4 * hart) as *mut u32).write_volatile(pending as u32) }
+}
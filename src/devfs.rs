@@ -0,0 +1,357 @@
+// Device file system mounted on /dev. Drivers register their devices while
+// they initialize, before any file system exists, so the registry is a fixed
+// array. Block devices are accessed through the buffer cache to stay coherent
+// with the file systems using them.
+
+use crate::block;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, InodeRef, Stat, VfsError};
+use crate::{bcache, lock, reg};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+pub const MAX_DEVICES: usize = 32;
+
+// A device read and written as a stream of bytes, offsets are ignored
+pub trait CharDevice {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError>;
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError>;
+}
+
+#[derive(Clone, Copy)]
+pub enum Device {
+    Char(&'static dyn CharDevice),
+    // Registry id of the block device
+    Block(usize),
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    name: &'static str,
+    device: Device,
+}
+
+static mut DEVICES: [Option<Node>; MAX_DEVICES] = [None; MAX_DEVICES];
+static mut DEVICE_COUNT: usize = 0;
+
+fn devices() -> &'static [Option<Node>] {
+    unsafe {
+        let devices = &*(&raw const DEVICES);
+        &devices[..DEVICE_COUNT]
+    }
+}
+
+fn register(name: &'static str, device: Device) -> Result<(), VfsError> {
+    lock::without_interrupts(|| unsafe {
+        if find(name).is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        if DEVICE_COUNT == MAX_DEVICES {
+            return Err(VfsError::NoSpace);
+        }
+
+        DEVICES[DEVICE_COUNT] = Some(Node { name, device });
+        DEVICE_COUNT += 1;
+        Ok(())
+    })
+}
+
+// Make a character device appear as /dev/<name>
+pub fn register_char(name: &'static str, device: &'static dyn CharDevice) -> Result<(), VfsError> {
+    register(name, Device::Char(device))
+}
+
+// Make a block device of the registry appear under its own name
+pub fn register_block(id: usize) -> Result<(), VfsError> {
+    let device = block::get(id).ok_or(VfsError::NotFound)?;
+    register(device.name(), Device::Block(id))
+}
+
+fn find(name: &str) -> Option<usize> {
+    devices()
+        .iter()
+        .position(|node| node.is_some_and(|node| node.name == name))
+}
+
+//----------- Built in devices ---------------//
+
+struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(buf.len())
+    }
+}
+
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(buf.len())
+    }
+}
+
+// xorshift64* seeded from the timer, not suitable for cryptography. Writes
+// are mixed into the state.
+struct Random;
+
+static mut RANDOM_STATE: u64 = 0x9e37_79b9_7f4a_7c15;
+
+fn next_random() -> u64 {
+    unsafe {
+        let mut x = RANDOM_STATE;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        RANDOM_STATE = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl CharDevice for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = next_random().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        for byte in buf {
+            unsafe {
+                RANDOM_STATE = (RANDOM_STATE ^ *byte as u64).rotate_left(8);
+                if RANDOM_STATE == 0 {
+                    RANDOM_STATE = 1;
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+static NULL: Null = Null;
+static ZERO: Zero = Zero;
+static RANDOM: Random = Random;
+
+//----------- File system ---------------//
+
+struct DevRoot;
+
+impl Inode for DevRoot {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: 1,
+            file_type: FileType::Directory,
+            size: devices().len() as u64,
+            links: 2,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        let index = find(name).ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(DevInode { index }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Ok(devices()
+            .iter()
+            .flatten()
+            .map(|node| DirEntry {
+                name: String::from(node.name),
+                file_type: match node.device {
+                    Device::Char(_) => FileType::CharDevice,
+                    Device::Block(_) => FileType::BlockDevice,
+                },
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct DevInode {
+    index: usize,
+}
+
+impl DevInode {
+    fn device(&self) -> Device {
+        devices()[self.index].unwrap().device
+    }
+}
+
+// Size and sector size of a block device
+fn block_geometry(id: usize) -> Result<(u64, usize), VfsError> {
+    let device = block::get(id).ok_or(VfsError::IoError)?;
+    let sector_size = device.sector_size();
+    Ok((device.num_sectors() * sector_size as u64, sector_size))
+}
+
+impl Inode for DevInode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let (file_type, size) = match self.device() {
+            Device::Char(_) => (FileType::CharDevice, 0),
+            Device::Block(id) => (FileType::BlockDevice, block_geometry(id)?.0),
+        };
+
+        Ok(Stat {
+            inode: self.index as u64 + 2,
+            file_type,
+            size,
+            links: 1,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let id = match self.device() {
+            Device::Char(device) => return device.read(buf),
+            Device::Block(id) => id,
+        };
+
+        let (size, sector_size) = block_geometry(id)?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let in_sector = (position % sector_size as u64) as usize;
+            let chunk = (sector_size - in_sector).min(len - done);
+            bcache::read(
+                id,
+                position / sector_size as u64,
+                in_sector,
+                &mut buf[done..done + chunk],
+            )
+            .map_err(|_| VfsError::IoError)?;
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        let id = match self.device() {
+            Device::Char(device) => return device.write(buf),
+            Device::Block(id) => id,
+        };
+
+        if block::get(id).ok_or(VfsError::IoError)?.is_read_only() {
+            return Err(VfsError::ReadOnly);
+        }
+        let (size, sector_size) = block_geometry(id)?;
+        if offset >= size && !buf.is_empty() {
+            return Err(VfsError::NoSpace);
+        }
+        let len = buf.len().min(size.saturating_sub(offset) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let in_sector = (position % sector_size as u64) as usize;
+            let chunk = (sector_size - in_sector).min(len - done);
+            bcache::write(
+                id,
+                position / sector_size as u64,
+                in_sector,
+                &buf[done..done + chunk],
+            )
+            .map_err(|_| VfsError::IoError)?;
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    // Opening with O_TRUNC must not fail on devices
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(DevRoot)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        bcache::sync().map_err(|_| VfsError::IoError)
+    }
+}
+
+pub fn init() {
+    unsafe { RANDOM_STATE ^= reg::time_read() };
+    register_char("null", &NULL).expect("Cannot register /dev/null");
+    register_char("zero", &ZERO).expect("Cannot register /dev/zero");
+    register_char("random", &RANDOM).expect("Cannot register /dev/random");
+
+    vfs::mkdir_all("/dev").expect("Cannot create /dev");
+    vfs::mount("/dev", Arc::new(DevFs)).expect("Cannot mount /dev");
+}
+
+pub fn init_sanity_check() {
+    assert_eq!(register_char("null", &NULL), Err(VfsError::AlreadyExists));
+
+    let mut buffer = [0xffu8; 16];
+    let fd = vfs::open("/dev/zero", vfs::O_RDWR).expect("Cannot open /dev/zero");
+    assert_eq!(vfs::read(fd, &mut buffer), Ok(16));
+    assert_eq!(buffer, [0; 16]);
+    vfs::close(fd).expect("Cannot close /dev/zero");
+
+    let fd = vfs::open("/dev/null", vfs::O_RDWR).expect("Cannot open /dev/null");
+    assert_eq!(vfs::write(fd, b"gone"), Ok(4));
+    assert_eq!(vfs::read(fd, &mut buffer), Ok(0));
+    vfs::close(fd).expect("Cannot close /dev/null");
+
+    let fd = vfs::open("/dev/random", vfs::O_RDONLY).expect("Cannot open /dev/random");
+    assert_eq!(vfs::read(fd, &mut buffer), Ok(16));
+    assert!(buffer != [0; 16], "/dev/random returned zeros");
+    vfs::close(fd).expect("Cannot close /dev/random");
+
+    // Block device files hold the same bytes as the device
+    for id in 0..block::count() {
+        let device = block::get(id).unwrap();
+        let mut path = String::from("/dev/");
+        path.push_str(device.name());
+
+        let stat = vfs::stat(&path).expect("Block device is missing from /dev");
+        assert_eq!(stat.file_type, FileType::BlockDevice);
+        assert_eq!(
+            stat.size,
+            device.num_sectors() * device.sector_size() as u64
+        );
+
+        let mut sector = alloc::vec![0u8; device.sector_size()];
+        bcache::read(id, 0, 0, &mut sector).expect("Cannot read a block device");
+        let fd = vfs::open(&path, vfs::O_RDONLY).expect("Cannot open a block device");
+        vfs::lseek(fd, 1, vfs::Whence::Set).expect("Cannot seek a block device");
+        assert_eq!(vfs::read(fd, &mut buffer), Ok(16));
+        assert_eq!(&buffer[..], &sector[1..17]);
+        vfs::close(fd).expect("Cannot close a block device");
+    }
+}
@@ -0,0 +1,1769 @@
+// ext2 file system on top of a block device. Blocks and inodes are taken
+// from the bitmaps of the block groups, the free counts of the group
+// descriptors and of the superblock are kept in step.
+// The first block device holding an ext2 volume is mounted at init.
+
+use crate::bcache;
+use crate::block::{self, BlockError};
+use crate::ramdisk;
+use crate::vfs::{
+    DirEntry as VfsDirEntry, FileSystem, FileType, Inode as VfsInode, InodeRef, Stat, VfsError,
+};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::RefCell;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+const GOOD_OLD_REVISION: u32 = 0;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+// Fields of a group descriptor
+const BG_BLOCK_BITMAP: u64 = 0;
+const BG_INODE_BITMAP: u64 = 4;
+const BG_INODE_TABLE: u64 = 8;
+const BG_FREE_BLOCKS: u64 = 12;
+const BG_FREE_INODES: u64 = 14;
+const BG_USED_DIRS: u64 = 16;
+
+// Free counts of the superblock
+const S_FREE_BLOCKS: u64 = SUPERBLOCK_OFFSET + 12;
+const S_FREE_INODES: u64 = SUPERBLOCK_OFFSET + 16;
+
+// Incompatible features we know how to handle, anything else cannot be mounted
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+// Read only compatible features we keep right when writing, a volume with
+// any other one is mounted read only
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+// Format bits of i_mode
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xa000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFBLK: u16 = 0x6000;
+
+// File types of directory entries
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_SYMLINK: u8 = 7;
+
+// Directory indexed by a hash tree. We only know the linear layout, so the
+// flag goes away when we change the directory and Linux scans it again.
+const INDEX_FL: u32 = 0x1000;
+
+const MAX_NAME_LEN: usize = 255;
+// Files of a volume without the large file feature stay below 2 GiB
+const MAX_SMALL_FILE_SIZE: u64 = i32::MAX as u64;
+
+// Time stamp of the inodes we write, there is no clock to read the date from
+// (1980-01-01, like the date of FAT entries)
+const DEFAULT_TIME: u32 = 315_532_800;
+
+const DIRECT_BLOCKS: usize = 12;
+const SINGLE_INDIRECT: usize = 12;
+const DOUBLE_INDIRECT: usize = 13;
+const TRIPLE_INDIRECT: usize = 14;
+
+// Symlinks shorter than this are stored inside i_block
+const FAST_SYMLINK_MAX: u64 = 60;
+// How many symlinks a single lookup may follow
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+// Volume made on the scratch disk by the sanity check
+const SCRATCH_BLOCK_SIZE: usize = 1024;
+const SCRATCH_INODES: u32 = 64;
+// Entries added to the root directory of the scratch volume so that it grows
+const SCRATCH_FILL_ENTRIES: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    Io(BlockError),
+    // The device does not hold an ext2 volume
+    NotExt2,
+    // The volume uses features we do not implement
+    Unsupported,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    InvalidPath,
+    TooManySymlinks,
+    // A block map or directory entry does not make sense
+    Corrupted,
+    NoSpace,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    ReadOnly,
+}
+
+impl From<BlockError> for Ext2Error {
+    fn from(error: BlockError) -> Self {
+        Ext2Error::Io(error)
+    }
+}
+
+pub struct Ext2 {
+    // Registry id of the block device
+    device: usize,
+    sector_size: usize,
+    block_size: usize,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    // First inode that is not reserved
+    first_inode: u32,
+    // Block holding the first group descriptor
+    descriptor_block: u32,
+    group_count: u32,
+    file_type_in_entries: bool,
+    large_files: bool,
+    read_only: bool,
+    // Inodes held by the VFS, open files included
+    held: RefCell<Vec<u32>>,
+}
+
+#[derive(Clone)]
+pub struct Inode {
+    pub number: u32,
+    pub mode: u16,
+    pub links: u16,
+    pub size: u64,
+    // Sectors of 512 bytes used by the file, including metadata blocks
+    sectors: u32,
+    flags: u32,
+    block: [u32; 15],
+    // Block of extended attributes, 0 when there is none
+    file_acl: u32,
+}
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            _ => FileType::Regular,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+}
+
+fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+impl Ext2 {
+    pub fn mount(device: usize) -> Result<Ext2, Ext2Error> {
+        let disk = block::get(device).ok_or(Ext2Error::Io(BlockError::NotInitialized))?;
+        let sector_size = disk.sector_size();
+        if (disk.num_sectors() * sector_size as u64) < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64 {
+            return Err(Ext2Error::NotExt2);
+        }
+
+        let mut superblock = vec![0u8; SUPERBLOCK_SIZE];
+        read_bytes(device, sector_size, SUPERBLOCK_OFFSET, &mut superblock)?;
+        if le_u16(&superblock, 56) != EXT2_MAGIC {
+            return Err(Ext2Error::NotExt2);
+        }
+
+        let inodes_count = le_u32(&superblock, 0);
+        let blocks_count = le_u32(&superblock, 4);
+        let first_data_block = le_u32(&superblock, 20);
+        let log_block_size = le_u32(&superblock, 24);
+        let blocks_per_group = le_u32(&superblock, 32);
+        let inodes_per_group = le_u32(&superblock, 40);
+        let revision = le_u32(&superblock, 76);
+
+        let (first_inode, inode_size, incompat, ro_compat) = if revision == GOOD_OLD_REVISION {
+            (GOOD_OLD_FIRST_INODE, GOOD_OLD_INODE_SIZE, 0, 0)
+        } else {
+            (
+                le_u32(&superblock, 84),
+                le_u16(&superblock, 88) as usize,
+                le_u32(&superblock, 96),
+                le_u32(&superblock, 100),
+            )
+        };
+
+        if log_block_size > 6
+            || first_data_block >= blocks_count
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+        {
+            return Err(Ext2Error::NotExt2);
+        }
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(Ext2Error::Unsupported);
+        }
+
+        let block_size = 1024 << log_block_size;
+        if block_size % sector_size != 0 {
+            return Err(Ext2Error::Unsupported);
+        }
+
+        Ok(Ext2 {
+            device,
+            sector_size,
+            block_size,
+            blocks_count,
+            inodes_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_inode,
+            descriptor_block: first_data_block + 1,
+            group_count: (blocks_count - first_data_block).div_ceil(blocks_per_group),
+            file_type_in_entries: incompat & INCOMPAT_FILETYPE != 0,
+            large_files: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: disk.is_read_only() || ro_compat & !SUPPORTED_RO_COMPAT != 0,
+            held: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), Ext2Error> {
+        read_bytes(self.device, self.sector_size, offset, buf)
+    }
+
+    fn read_block(&self, block: u32, offset: usize, buf: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks_count {
+            return Err(Ext2Error::Corrupted);
+        }
+        self.read_bytes(block as u64 * self.block_size as u64 + offset as u64, buf)
+    }
+
+    fn read_u16(&self, offset: u64) -> Result<u16, Ext2Error> {
+        let mut value = [0u8; 2];
+        self.read_bytes(offset, &mut value)?;
+        Ok(u16::from_le_bytes(value))
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, Ext2Error> {
+        let mut value = [0u8; 4];
+        self.read_bytes(offset, &mut value)?;
+        Ok(u32::from_le_bytes(value))
+    }
+
+    // Offset on the device of a field of a group descriptor
+    fn descriptor(&self, group: u32, field: u64) -> u64 {
+        self.descriptor_block as u64 * self.block_size as u64
+            + group as u64 * GROUP_DESCRIPTOR_SIZE as u64
+            + field
+    }
+
+    fn group_of(&self, number: u32) -> u32 {
+        (number - 1) / self.inodes_per_group
+    }
+
+    // Offset on the device of an inode in the inode table of its group
+    fn inode_position(&self, number: u32) -> Result<u64, Ext2Error> {
+        if number == 0 || number > self.inodes_count {
+            return Err(Ext2Error::NotFound);
+        }
+
+        let group = self.group_of(number);
+        let index = (number - 1) % self.inodes_per_group;
+        if group >= self.group_count {
+            return Err(Ext2Error::Corrupted);
+        }
+
+        let inode_table = self.read_u32(self.descriptor(group, BG_INODE_TABLE))?;
+        Ok(inode_table as u64 * self.block_size as u64 + index as u64 * self.inode_size as u64)
+    }
+
+    pub fn read_inode(&self, number: u32) -> Result<Inode, Ext2Error> {
+        let mut raw = [0u8; GOOD_OLD_INODE_SIZE];
+        self.read_bytes(self.inode_position(number)?, &mut raw)?;
+
+        let mode = le_u16(&raw, 0);
+        let mut size = le_u32(&raw, 4) as u64;
+        // The high half of the size shares its field with i_dir_acl
+        if self.large_files && mode & S_IFMT == S_IFREG {
+            size |= (le_u32(&raw, 108) as u64) << 32;
+        }
+
+        let mut block = [0u32; 15];
+        for (i, entry) in block.iter_mut().enumerate() {
+            *entry = le_u32(&raw, 40 + i * 4);
+        }
+
+        Ok(Inode {
+            number,
+            mode,
+            links: le_u16(&raw, 26),
+            size,
+            sectors: le_u32(&raw, 28),
+            flags: le_u32(&raw, 32),
+            block,
+            file_acl: le_u32(&raw, 104),
+        })
+    }
+
+    // Entry of an indirect block
+    fn indirect(&self, block: u32, index: usize) -> Result<u32, Ext2Error> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut entry = [0u8; 4];
+        self.read_block(block, index * 4, &mut entry)?;
+        Ok(u32::from_le_bytes(entry))
+    }
+
+    // Disk block holding the index-th block of the inode, 0 for a hole
+    fn block_map(&self, inode: &Inode, index: usize) -> Result<u32, Ext2Error> {
+        let per_block = self.block_size / 4;
+
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.block[index]);
+        }
+
+        let index = index - DIRECT_BLOCKS;
+        if index < per_block {
+            return self.indirect(inode.block[SINGLE_INDIRECT], index);
+        }
+
+        let index = index - per_block;
+        if index < per_block * per_block {
+            let level1 = self.indirect(inode.block[DOUBLE_INDIRECT], index / per_block)?;
+            return self.indirect(level1, index % per_block);
+        }
+
+        let index = index - per_block * per_block;
+        if index < per_block * per_block * per_block {
+            let level1 = self.indirect(
+                inode.block[TRIPLE_INDIRECT],
+                index / (per_block * per_block),
+            )?;
+            let level2 = self.indirect(level1, index / per_block % per_block)?;
+            return self.indirect(level2, index % per_block);
+        }
+
+        Err(Ext2Error::Corrupted)
+    }
+
+    // Read from the inode starting at offset, returns the number of bytes read.
+    // Holes read as zeros.
+    pub fn read(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        if inode.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        self.read_data(inode, offset, buf)
+    }
+
+    fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        if offset >= inode.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(inode.size - offset) as usize;
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let index = (position / self.block_size as u64) as usize;
+            let in_block = (position % self.block_size as u64) as usize;
+            let chunk = (len - done).min(self.block_size - in_block);
+
+            match self.block_map(inode, index)? {
+                0 => buf[done..done + chunk].fill(0),
+                block => self.read_block(block, in_block, &mut buf[done..done + chunk])?,
+            }
+            done += chunk;
+        }
+
+        Ok(len)
+    }
+
+    pub fn root(&self) -> Result<Inode, Ext2Error> {
+        self.read_inode(ROOT_INODE)
+    }
+
+    pub fn read_dir(&self, directory: &Inode) -> Result<Vec<DirEntry>, Ext2Error> {
+        if !directory.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+
+        let mut entries = Vec::new();
+        let mut data = vec![0u8; self.block_size];
+        let mut offset = 0;
+
+        // Entries never cross a block boundary
+        while offset < directory.size {
+            let len = self.read_data(directory, offset, &mut data)?;
+            let mut position = 0;
+
+            while position + 8 <= len {
+                let (inode, record_len, name_len) = self.record(&data[..len], position)?;
+                if inode != 0 {
+                    let name = &data[position + 8..position + 8 + name_len];
+                    entries.push(DirEntry {
+                        name: String::from_utf8_lossy(name).into_owned(),
+                        inode,
+                    });
+                }
+                position += record_len;
+            }
+
+            offset += self.block_size as u64;
+        }
+
+        Ok(entries)
+    }
+
+    // Inode, record length and name length of the directory record at position
+    fn record(&self, data: &[u8], position: usize) -> Result<(u32, usize, usize), Ext2Error> {
+        let inode = le_u32(data, position);
+        let record_len = le_u16(data, position + 4) as usize;
+        let name_len = if self.file_type_in_entries {
+            data[position + 6] as usize
+        } else {
+            le_u16(data, position + 6) as usize
+        };
+
+        if record_len < 8 || position + record_len > data.len() || 8 + name_len > record_len {
+            return Err(Ext2Error::Corrupted);
+        }
+        Ok((inode, record_len, name_len))
+    }
+
+    pub fn find(&self, directory: &Inode, name: &str) -> Result<Inode, Ext2Error> {
+        let entry = self
+            .read_dir(directory)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(Ext2Error::NotFound)?;
+        self.read_inode(entry.inode)
+    }
+
+    // A fast symlink has no data block, the target lives in i_block. The
+    // block of extended attributes is counted in its sectors.
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        let acl_sectors = if inode.file_acl != 0 {
+            (self.block_size / 512) as u32
+        } else {
+            0
+        };
+        inode.is_symlink()
+            && inode.size < FAST_SYMLINK_MAX
+            && inode.sectors.saturating_sub(acl_sectors) == 0
+    }
+
+    // Target of a symbolic link
+    pub fn read_link(&self, inode: &Inode) -> Result<String, Ext2Error> {
+        if !inode.is_symlink() {
+            return Err(Ext2Error::InvalidPath);
+        }
+
+        if self.is_fast_symlink(inode) {
+            let mut target = Vec::with_capacity(inode.size as usize);
+            for entry in inode.block.iter() {
+                target.extend_from_slice(&entry.to_le_bytes());
+            }
+            target.truncate(inode.size as usize);
+            return Ok(String::from_utf8_lossy(&target).into_owned());
+        }
+
+        let mut target = vec![0u8; inode.size as usize];
+        let len = self.read_data(inode, 0, &mut target)?;
+        target.truncate(len);
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    // Resolve an absolute path like "/etc/motd", following symlinks
+    pub fn lookup(&self, path: &str) -> Result<Inode, Ext2Error> {
+        self.resolve(path, true)
+    }
+
+    // Same as lookup, but a symlink at the end of the path is not followed
+    pub fn lookup_link(&self, path: &str) -> Result<Inode, Ext2Error> {
+        self.resolve(path, false)
+    }
+
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<Inode, Ext2Error> {
+        if !path.starts_with('/') {
+            return Err(Ext2Error::InvalidPath);
+        }
+
+        let mut depth = 0;
+        self.walk(self.root()?, path, follow_last, &mut depth)
+    }
+
+    // Walk path starting at directory, "." and ".." are real entries on disk
+    fn walk(
+        &self,
+        directory: Inode,
+        path: &str,
+        follow_last: bool,
+        depth: &mut usize,
+    ) -> Result<Inode, Ext2Error> {
+        let mut current = if path.starts_with('/') {
+            self.root()?
+        } else {
+            directory
+        };
+
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(component) = components.next() {
+            if !current.is_dir() {
+                return Err(Ext2Error::NotADirectory);
+            }
+
+            let parent = current.clone();
+            current = self.find(&current, component)?;
+
+            let is_last = components.peek().is_none();
+            if current.is_symlink() && (!is_last || follow_last) {
+                *depth += 1;
+                if *depth > MAX_SYMLINK_DEPTH {
+                    return Err(Ext2Error::TooManySymlinks);
+                }
+
+                // Relative targets start from the directory holding the link
+                let target = self.read_link(&current)?;
+                current = self.walk(parent, &target, true, depth)?;
+            }
+        }
+
+        Ok(current)
+    }
+
+    pub fn list(&self, path: &str) -> Result<Vec<DirEntry>, Ext2Error> {
+        self.read_dir(&self.lookup(path)?)
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, Ext2Error> {
+        let file = self.lookup(path)?;
+        let mut data = vec![0u8; file.size as usize];
+        let len = self.read(&file, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), Ext2Error> {
+        if self.read_only {
+            Err(Ext2Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_bytes(&self, offset: u64, buf: &[u8]) -> Result<(), Ext2Error> {
+        write_bytes(self.device, self.sector_size, offset, buf)
+    }
+
+    fn write_block(&self, block: u32, offset: usize, buf: &[u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks_count {
+            return Err(Ext2Error::Corrupted);
+        }
+        self.write_bytes(block as u64 * self.block_size as u64 + offset as u64, buf)
+    }
+
+    // Add delta to a free or used count of a group descriptor
+    fn add_u16(&self, offset: u64, delta: i32) -> Result<(), Ext2Error> {
+        let value = self.read_u16(offset)? as i32 + delta;
+        self.write_bytes(offset, &(value as u16).to_le_bytes())
+    }
+
+    // Add delta to a free count of the superblock
+    fn add_u32(&self, offset: u64, delta: i32) -> Result<(), Ext2Error> {
+        let value = self.read_u32(offset)?.wrapping_add_signed(delta);
+        self.write_bytes(offset, &value.to_le_bytes())
+    }
+
+    // Free blocks and free inodes of the volume, as the superblock counts them
+    pub fn free_counts(&self) -> Result<(u32, u32), Ext2Error> {
+        Ok((self.read_u32(S_FREE_BLOCKS)?, self.read_u32(S_FREE_INODES)?))
+    }
+
+    // Store the fields kept in Inode back in the inode table, the others are
+    // left as they are. An inode without links gets its deletion time.
+    fn write_inode(&self, inode: &Inode) -> Result<(), Ext2Error> {
+        let position = self.inode_position(inode.number)?;
+        let mut raw = [0u8; GOOD_OLD_INODE_SIZE];
+        self.read_bytes(position, &mut raw)?;
+
+        raw[0..2].copy_from_slice(&inode.mode.to_le_bytes());
+        raw[4..8].copy_from_slice(&(inode.size as u32).to_le_bytes());
+        for time in [8, 12, 16] {
+            raw[time..time + 4].copy_from_slice(&DEFAULT_TIME.to_le_bytes());
+        }
+        let deleted = if inode.links == 0 { DEFAULT_TIME } else { 0 };
+        raw[20..24].copy_from_slice(&deleted.to_le_bytes());
+        raw[26..28].copy_from_slice(&inode.links.to_le_bytes());
+        raw[28..32].copy_from_slice(&inode.sectors.to_le_bytes());
+        raw[32..36].copy_from_slice(&inode.flags.to_le_bytes());
+        for (i, entry) in inode.block.iter().enumerate() {
+            raw[40 + i * 4..44 + i * 4].copy_from_slice(&entry.to_le_bytes());
+        }
+        if inode.is_file() {
+            raw[108..112].copy_from_slice(&((inode.size >> 32) as u32).to_le_bytes());
+        }
+
+        self.write_bytes(position, &raw)
+    }
+
+    // Set the first clear bit from start on below limit in a bitmap block
+    fn take_bit(&self, bitmap: u32, start: u32, limit: u32) -> Result<Option<u32>, Ext2Error> {
+        let mut data = vec![0u8; self.block_size];
+        self.read_block(bitmap, 0, &mut data)?;
+
+        let limit = limit.min(self.block_size as u32 * 8);
+        for bit in start..limit {
+            let (byte, mask) = ((bit / 8) as usize, 1u8 << (bit % 8));
+            if data[byte] & mask == 0 {
+                self.write_block(bitmap, byte, &[data[byte] | mask])?;
+                return Ok(Some(bit));
+            }
+        }
+        Ok(None)
+    }
+
+    fn clear_bit(&self, bitmap: u32, bit: u32) -> Result<(), Ext2Error> {
+        let (byte, mask) = ((bit / 8) as usize, 1u8 << (bit % 8));
+        let mut value = [0u8];
+        self.read_block(bitmap, byte, &mut value)?;
+
+        // Freeing twice means the bitmap and the inodes disagree
+        if value[0] & mask == 0 {
+            return Err(Ext2Error::Corrupted);
+        }
+        self.write_block(bitmap, byte, &[value[0] & !mask])
+    }
+
+    // Allocate a zeroed block, looking in the goal group first
+    fn alloc_block(&self, goal: u32) -> Result<u32, Ext2Error> {
+        for i in 0..self.group_count {
+            let group = (goal + i) % self.group_count;
+            if self.read_u16(self.descriptor(group, BG_FREE_BLOCKS))? == 0 {
+                continue;
+            }
+
+            // The last group may be shorter than the others
+            let first = self.first_data_block + group * self.blocks_per_group;
+            let limit = self.blocks_per_group.min(self.blocks_count - first);
+            let bitmap = self.read_u32(self.descriptor(group, BG_BLOCK_BITMAP))?;
+            if let Some(bit) = self.take_bit(bitmap, 0, limit)? {
+                self.add_u16(self.descriptor(group, BG_FREE_BLOCKS), -1)?;
+                self.add_u32(S_FREE_BLOCKS, -1)?;
+
+                let block = first + bit;
+                self.write_block(block, 0, &vec![0u8; self.block_size])?;
+                return Ok(block);
+            }
+        }
+        Err(Ext2Error::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), Ext2Error> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(Ext2Error::Corrupted);
+        }
+
+        let group = (block - self.first_data_block) / self.blocks_per_group;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        let bitmap = self.read_u32(self.descriptor(group, BG_BLOCK_BITMAP))?;
+        self.clear_bit(bitmap, bit)?;
+        self.add_u16(self.descriptor(group, BG_FREE_BLOCKS), 1)?;
+        self.add_u32(S_FREE_BLOCKS, 1)
+    }
+
+    // Allocate an inode number, looking in the goal group first. The reserved
+    // inodes at the start of the first group are never handed out.
+    fn alloc_inode(&self, goal: u32, directory: bool) -> Result<u32, Ext2Error> {
+        for i in 0..self.group_count {
+            let group = (goal + i) % self.group_count;
+            if self.read_u16(self.descriptor(group, BG_FREE_INODES))? == 0 {
+                continue;
+            }
+
+            let first = group * self.inodes_per_group + 1;
+            let start = self.first_inode.saturating_sub(first);
+            let bitmap = self.read_u32(self.descriptor(group, BG_INODE_BITMAP))?;
+            if let Some(bit) = self.take_bit(bitmap, start, self.inodes_per_group)? {
+                self.add_u16(self.descriptor(group, BG_FREE_INODES), -1)?;
+                self.add_u32(S_FREE_INODES, -1)?;
+                if directory {
+                    self.add_u16(self.descriptor(group, BG_USED_DIRS), 1)?;
+                }
+                return Ok(first + bit);
+            }
+        }
+        Err(Ext2Error::NoSpace)
+    }
+
+    fn free_inode(&self, number: u32, directory: bool) -> Result<(), Ext2Error> {
+        let group = self.group_of(number);
+        let bitmap = self.read_u32(self.descriptor(group, BG_INODE_BITMAP))?;
+        self.clear_bit(bitmap, (number - 1) % self.inodes_per_group)?;
+        self.add_u16(self.descriptor(group, BG_FREE_INODES), 1)?;
+        self.add_u32(S_FREE_INODES, 1)?;
+        if directory {
+            self.add_u16(self.descriptor(group, BG_USED_DIRS), -1)?;
+        }
+        Ok(())
+    }
+
+    // Allocate a block for the inode, data or indirect, in the group of the inode
+    fn alloc_for(&self, inode: &mut Inode) -> Result<u32, Ext2Error> {
+        let block = self.alloc_block(self.group_of(inode.number))?;
+        inode.sectors += (self.block_size / 512) as u32;
+        Ok(block)
+    }
+
+    fn release(&self, inode: &mut Inode, block: u32) -> Result<(), Ext2Error> {
+        self.free_block(block)?;
+        inode.sectors = inode.sectors.saturating_sub((self.block_size / 512) as u32);
+        Ok(())
+    }
+
+    // Same as block_map, but holes on the way are allocated. The caller
+    // writes the inode back.
+    fn block_map_alloc(&self, inode: &mut Inode, index: usize) -> Result<u32, Ext2Error> {
+        let per_block = self.block_size / 4;
+
+        if index < DIRECT_BLOCKS {
+            if inode.block[index] == 0 {
+                inode.block[index] = self.alloc_for(inode)?;
+            }
+            return Ok(inode.block[index]);
+        }
+
+        // Find the indirect tree holding the block, then walk down its levels
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = per_block;
+        for (level, slot) in (SINGLE_INDIRECT..=TRIPLE_INDIRECT).enumerate() {
+            if index < span {
+                if inode.block[slot] == 0 {
+                    inode.block[slot] = self.alloc_for(inode)?;
+                }
+
+                let mut block = inode.block[slot];
+                let mut divisor = span / per_block;
+                for _ in 0..=level {
+                    let entry = index / divisor % per_block;
+                    let mut next = self.indirect(block, entry)?;
+                    if next == 0 {
+                        next = self.alloc_for(inode)?;
+                        self.write_block(block, entry * 4, &next.to_le_bytes())?;
+                    }
+                    block = next;
+                    divisor /= per_block;
+                }
+                return Ok(block);
+            }
+            index -= span;
+            span *= per_block;
+        }
+
+        Err(Ext2Error::NoSpace)
+    }
+
+    fn max_size(&self, inode: &Inode) -> u64 {
+        match (inode.is_file(), self.large_files) {
+            (true, true) => u64::MAX,
+            (true, false) => MAX_SMALL_FILE_SIZE,
+            _ => u32::MAX as u64,
+        }
+    }
+
+    // Write to the inode starting at offset, returns the number of bytes
+    // written. Writing past the end grows the file, skipped blocks stay holes.
+    pub fn write(&self, inode: &mut Inode, offset: u64, buf: &[u8]) -> Result<usize, Ext2Error> {
+        self.check_writable()?;
+        if inode.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        self.write_data(inode, offset, buf)
+    }
+
+    fn write_data(&self, inode: &mut Inode, offset: u64, buf: &[u8]) -> Result<usize, Ext2Error> {
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.max_size(inode) => {}
+            _ => return Err(Ext2Error::NoSpace),
+        }
+
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = (position / self.block_size as u64) as usize;
+            let in_block = (position % self.block_size as u64) as usize;
+            let chunk = (buf.len() - done).min(self.block_size - in_block);
+
+            result = self
+                .block_map_alloc(inode, index)
+                .and_then(|block| self.write_block(block, in_block, &buf[done..done + chunk]));
+            if result.is_err() {
+                break;
+            }
+            done += chunk;
+        }
+
+        // Blocks taken before a failure belong to the inode all the same
+        inode.size = inode.size.max(offset + done as u64);
+        self.write_inode(inode)?;
+        match result {
+            Err(error) if done == 0 => Err(error),
+            _ => Ok(done),
+        }
+    }
+
+    // Change the size of a file, growing leaves a hole
+    pub fn truncate(&self, inode: &mut Inode, size: u64) -> Result<(), Ext2Error> {
+        self.check_writable()?;
+        if inode.is_dir() {
+            return Err(Ext2Error::IsADirectory);
+        }
+        if size > self.max_size(inode) {
+            return Err(Ext2Error::NoSpace);
+        }
+
+        if size < inode.size {
+            self.free_blocks_from(inode, size)?;
+        }
+        inode.size = size;
+        self.write_inode(inode)
+    }
+
+    // Free every block past size. The end of the last block kept is zeroed,
+    // growing the file again must read zeros there.
+    fn free_blocks_from(&self, inode: &mut Inode, size: u64) -> Result<(), Ext2Error> {
+        let keep = size.div_ceil(self.block_size as u64) as usize;
+        let tail = (size % self.block_size as u64) as usize;
+        if tail != 0 {
+            let block = self.block_map(inode, keep - 1)?;
+            if block != 0 {
+                self.write_block(block, tail, &vec![0u8; self.block_size - tail])?;
+            }
+        }
+
+        for index in keep.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            let block = inode.block[index];
+            if block != 0 {
+                self.release(inode, block)?;
+                inode.block[index] = 0;
+            }
+        }
+
+        let per_block = self.block_size / 4;
+        let mut first = DIRECT_BLOCKS;
+        let mut span = per_block;
+        for (level, slot) in (SINGLE_INDIRECT..=TRIPLE_INDIRECT).enumerate() {
+            let top = inode.block[slot];
+            if top != 0 && self.free_tree(inode, top, level, keep.saturating_sub(first))? {
+                self.release(inode, top)?;
+                inode.block[slot] = 0;
+            }
+            first += span;
+            span *= per_block;
+        }
+        Ok(())
+    }
+
+    // Free the blocks of an indirect tree from its first-th data block on,
+    // the entries of a level 0 block are data blocks. Returns true when
+    // nothing is left below block.
+    fn free_tree(
+        &self,
+        inode: &mut Inode,
+        block: u32,
+        level: usize,
+        first: usize,
+    ) -> Result<bool, Ext2Error> {
+        let per_block = self.block_size / 4;
+        // Data blocks below each entry
+        let span = per_block.pow(level as u32);
+        let mut data = vec![0u8; self.block_size];
+        self.read_block(block, 0, &mut data)?;
+
+        let mut empty = true;
+        for entry in 0..per_block {
+            let child = le_u32(&data, entry * 4);
+            if child == 0 {
+                continue;
+            }
+
+            let start = entry * span;
+            let freed = if start + span <= first {
+                false
+            } else {
+                level == 0
+                    || self.free_tree(inode, child, level - 1, first.saturating_sub(start))?
+            };
+            if freed {
+                self.release(inode, child)?;
+                self.write_block(block, entry * 4, &0u32.to_le_bytes())?;
+            } else {
+                empty = false;
+            }
+        }
+        Ok(empty)
+    }
+
+    fn entry_type(&self, mode: u16) -> u8 {
+        match mode & S_IFMT {
+            S_IFDIR => FT_DIR,
+            S_IFLNK => FT_SYMLINK,
+            S_IFCHR => FT_CHRDEV,
+            S_IFBLK => FT_BLKDEV,
+            _ => FT_REG_FILE,
+        }
+    }
+
+    // Fill a directory record spanning all of record
+    fn put_record(&self, record: &mut [u8], name: &str, inode: u32, file_type: u8) {
+        let record_len = record.len() as u16;
+        record[0..4].copy_from_slice(&inode.to_le_bytes());
+        record[4..6].copy_from_slice(&record_len.to_le_bytes());
+        if self.file_type_in_entries {
+            record[6] = name.len() as u8;
+            record[7] = file_type;
+        } else {
+            record[6..8].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        }
+        record[8..8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    // A directory we changed is written back without its hash index
+    fn touch_dir(&self, directory: &mut Inode) -> Result<(), Ext2Error> {
+        directory.flags &= !INDEX_FL;
+        self.write_inode(directory)
+    }
+
+    // Add an entry to a directory, in the first record with enough room left
+    // or else in a new block at the end
+    fn add_entry(&self, directory: &mut Inode, name: &str, inode: &Inode) -> Result<(), Ext2Error> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') {
+            return Err(Ext2Error::InvalidPath);
+        }
+
+        let needed = entry_len(name.len());
+        let file_type = self.entry_type(inode.mode);
+        let blocks = directory.size.div_ceil(self.block_size as u64) as usize;
+        let mut data = vec![0u8; self.block_size];
+
+        for index in 0..blocks {
+            let block = match self.block_map(directory, index)? {
+                0 => return Err(Ext2Error::Corrupted),
+                block => block,
+            };
+            self.read_block(block, 0, &mut data)?;
+
+            let mut position = 0;
+            while position + 8 <= self.block_size {
+                let (number, record_len, name_len) = self.record(&data, position)?;
+                let used = if number == 0 { 0 } else { entry_len(name_len) };
+                if record_len >= used + needed {
+                    // The record keeps what it uses, the new entry gets the rest
+                    if used != 0 {
+                        data[position + 4..position + 6]
+                            .copy_from_slice(&(used as u16).to_le_bytes());
+                    }
+                    let record = &mut data[position + used..position + record_len];
+                    self.put_record(record, name, inode.number, file_type);
+                    self.write_block(block, 0, &data)?;
+                    return self.touch_dir(directory);
+                }
+                position += record_len;
+            }
+        }
+
+        let block = match self.block_map_alloc(directory, blocks) {
+            Ok(block) => block,
+            Err(error) => {
+                self.write_inode(directory)?;
+                return Err(error);
+            }
+        };
+        data.fill(0);
+        self.put_record(&mut data, name, inode.number, file_type);
+        self.write_block(block, 0, &data)?;
+        directory.size += self.block_size as u64;
+        self.touch_dir(directory)
+    }
+
+    // Point the entry name of a directory to another inode, or remove it
+    // when there is none. Returns the inode the entry pointed to.
+    fn update_entry(
+        &self,
+        directory: &mut Inode,
+        name: &str,
+        inode: Option<u32>,
+    ) -> Result<u32, Ext2Error> {
+        let blocks = directory.size.div_ceil(self.block_size as u64) as usize;
+        let mut data = vec![0u8; self.block_size];
+
+        for index in 0..blocks {
+            let block = match self.block_map(directory, index)? {
+                0 => return Err(Ext2Error::Corrupted),
+                block => block,
+            };
+            self.read_block(block, 0, &mut data)?;
+
+            let mut previous = None;
+            let mut position = 0;
+            while position + 8 <= self.block_size {
+                let (number, record_len, name_len) = self.record(&data, position)?;
+                if number != 0 && &data[position + 8..position + 8 + name_len] == name.as_bytes() {
+                    match (inode, previous) {
+                        (Some(inode), _) => {
+                            data[position..position + 4].copy_from_slice(&inode.to_le_bytes())
+                        }
+                        // The previous record takes the space back
+                        (None, Some(previous)) => {
+                            let merged = (position + record_len - previous) as u16;
+                            data[previous + 4..previous + 6].copy_from_slice(&merged.to_le_bytes());
+                        }
+                        // The first record of a block cannot be merged, it is emptied
+                        (None, None) => data[position..position + 4].fill(0),
+                    }
+                    self.write_block(block, 0, &data)?;
+                    self.touch_dir(directory)?;
+                    return Ok(number);
+                }
+                previous = Some(position);
+                position += record_len;
+            }
+        }
+
+        Err(Ext2Error::NotFound)
+    }
+
+    // Create a regular file or a directory in a directory, mode holds the
+    // format and the permission bits
+    pub fn create(&self, directory: &mut Inode, name: &str, mode: u16) -> Result<Inode, Ext2Error> {
+        match mode & S_IFMT {
+            S_IFREG | S_IFDIR => self.create_inode(directory, name, mode, ""),
+            _ => Err(Ext2Error::Unsupported),
+        }
+    }
+
+    pub fn symlink(
+        &self,
+        directory: &mut Inode,
+        name: &str,
+        target: &str,
+    ) -> Result<Inode, Ext2Error> {
+        if target.is_empty() || target.len() >= self.block_size {
+            return Err(Ext2Error::InvalidPath);
+        }
+        self.create_inode(directory, name, S_IFLNK | 0o777, target)
+    }
+
+    fn create_inode(
+        &self,
+        directory: &mut Inode,
+        name: &str,
+        mode: u16,
+        target: &str,
+    ) -> Result<Inode, Ext2Error> {
+        self.check_writable()?;
+        match self.find(directory, name) {
+            Ok(_) => return Err(Ext2Error::AlreadyExists),
+            Err(Ext2Error::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let number = self.alloc_inode(self.group_of(directory.number), is_dir)?;
+        // What a previous owner left in the inode must not show through
+        self.write_bytes(self.inode_position(number)?, &vec![0u8; self.inode_size])?;
+        let mut inode = Inode {
+            number,
+            mode,
+            links: if is_dir { 2 } else { 1 },
+            size: 0,
+            sectors: 0,
+            flags: 0,
+            block: [0; 15],
+            file_acl: 0,
+        };
+
+        let result = self
+            .fill_inode(directory, &mut inode, target)
+            .and_then(|_| self.add_entry(directory, name, &inode));
+        if let Err(error) = result {
+            self.release_inode(&mut inode)?;
+            return Err(error);
+        }
+
+        // The ".." of the new directory links to its parent
+        if is_dir {
+            directory.links += 1;
+            self.write_inode(directory)?;
+        }
+        Ok(inode)
+    }
+
+    // First content of a new inode: the dot entries of a directory or the
+    // target of a symlink
+    fn fill_inode(
+        &self,
+        directory: &Inode,
+        inode: &mut Inode,
+        target: &str,
+    ) -> Result<(), Ext2Error> {
+        match inode.mode & S_IFMT {
+            S_IFDIR => {
+                let block = self.block_map_alloc(inode, 0)?;
+                let mut data = vec![0u8; self.block_size];
+                let (dot, dot_dot) = data.split_at_mut(entry_len(1));
+                self.put_record(dot, ".", inode.number, FT_DIR);
+                self.put_record(dot_dot, "..", directory.number, FT_DIR);
+                self.write_block(block, 0, &data)?;
+                inode.size = self.block_size as u64;
+            }
+            S_IFLNK if (target.len() as u64) < FAST_SYMLINK_MAX => {
+                let mut bytes = [0u8; 60];
+                bytes[..target.len()].copy_from_slice(target.as_bytes());
+                for (i, entry) in inode.block.iter_mut().enumerate() {
+                    *entry = le_u32(&bytes, i * 4);
+                }
+                inode.size = target.len() as u64;
+            }
+            S_IFLNK => {
+                self.write_data(inode, 0, target.as_bytes())?;
+            }
+            _ => {}
+        }
+        self.write_inode(inode)
+    }
+
+    // Give the blocks and the number of an inode without links back. The
+    // block of extended attributes may be shared, it is left alone.
+    fn release_inode(&self, inode: &mut Inode) -> Result<(), Ext2Error> {
+        if !self.is_fast_symlink(inode) {
+            self.free_blocks_from(inode, 0)?;
+        }
+        inode.size = 0;
+        inode.links = 0;
+        self.write_inode(inode)?;
+        self.free_inode(inode.number, inode.is_dir())
+    }
+
+    // Remove the entry name of a directory, the inode goes with its last link.
+    // A directory must be empty.
+    pub fn unlink(&self, directory: &mut Inode, name: &str) -> Result<(), Ext2Error> {
+        self.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(Ext2Error::InvalidPath);
+        }
+
+        let mut inode = self.find(directory, name)?;
+        if inode.is_dir()
+            && self
+                .read_dir(&inode)?
+                .iter()
+                .any(|entry| entry.name != "." && entry.name != "..")
+        {
+            return Err(Ext2Error::DirectoryNotEmpty);
+        }
+
+        self.update_entry(directory, name, None)?;
+        if inode.is_dir() {
+            // Its ".." was a link to the parent
+            directory.links = directory.links.saturating_sub(1);
+            self.write_inode(directory)?;
+            inode.links = 0;
+        } else {
+            inode.links = inode.links.saturating_sub(1);
+        }
+
+        if inode.links == 0 {
+            self.release_inode(&mut inode)
+        } else {
+            self.write_inode(&inode)
+        }
+    }
+
+    // Move the entry name of a directory to new_name in new_directory. The
+    // directories are given by number, both names may be in the same one.
+    pub fn rename(
+        &self,
+        directory: u32,
+        name: &str,
+        new_directory: u32,
+        new_name: &str,
+    ) -> Result<(), Ext2Error> {
+        self.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(Ext2Error::InvalidPath);
+        }
+
+        let inode = self.find(&self.read_inode(directory)?, name)?;
+        let mut new_parent = self.read_inode(new_directory)?;
+        if !new_parent.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        match self.find(&new_parent, new_name) {
+            Ok(existing) if existing.number == inode.number => return Ok(()),
+            Ok(_) => return Err(Ext2Error::AlreadyExists),
+            Err(Ext2Error::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        self.add_entry(&mut new_parent, new_name, &inode)?;
+        let mut parent = self.read_inode(directory)?;
+        self.update_entry(&mut parent, name, None)?;
+
+        // A directory changes parent: its ".." and the links it gives
+        if inode.is_dir() && directory != new_directory {
+            let mut moved = inode;
+            self.update_entry(&mut moved, "..", Some(new_directory))?;
+            parent.links = parent.links.saturating_sub(1);
+            self.write_inode(&parent)?;
+            let mut new_parent = self.read_inode(new_directory)?;
+            new_parent.links += 1;
+            self.write_inode(&new_parent)?;
+        }
+        Ok(())
+    }
+
+    // Write every change back to the disk
+    pub fn sync(&self) -> Result<(), Ext2Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        bcache::sync_device(self.device)?;
+        Ok(())
+    }
+}
+
+// Bytes a directory record needs for a name, records are 4 bytes aligned
+fn entry_len(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
+
+// Read bytes at any offset of the device through the buffer cache
+fn read_bytes(
+    device: usize,
+    sector_size: usize,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<(), Ext2Error> {
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let sector = position / sector_size as u64;
+        let in_sector = (position % sector_size as u64) as usize;
+        let len = (buf.len() - done).min(sector_size - in_sector);
+
+        bcache::read(device, sector, in_sector, &mut buf[done..done + len])?;
+        done += len;
+    }
+    Ok(())
+}
+
+// Write bytes at any offset of the device through the buffer cache
+fn write_bytes(
+    device: usize,
+    sector_size: usize,
+    offset: u64,
+    buf: &[u8],
+) -> Result<(), Ext2Error> {
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let sector = position / sector_size as u64;
+        let in_sector = (position % sector_size as u64) as usize;
+        let len = (buf.len() - done).min(sector_size - in_sector);
+
+        bcache::write(device, sector, in_sector, &buf[done..done + len])?;
+        done += len;
+    }
+    Ok(())
+}
+
+static mut VOLUME: Option<Ext2> = None;
+
+pub fn volume() -> Option<&'static Ext2> {
+    unsafe { (*(&raw const VOLUME)).as_ref() }
+}
+
+// Mount the first block device holding an ext2 volume
+pub fn init() {
+    for device in 0..block::count() {
+        if let Ok(ext2) = Ext2::mount(device) {
+            unsafe { VOLUME = Some(ext2) };
+            return;
+        }
+    }
+}
+
+pub fn init_sanity_check() {
+    // The volume of the user is only read at boot
+    if let Some(ext2) = volume() {
+        check_root(ext2);
+    }
+
+    // Writes are checked on a volume made on the scratch ram disk
+    let device = match ramdisk::scratch() {
+        Some(device) => device,
+        None => return,
+    };
+    let free = format_scratch(device);
+    let ext2 = Ext2::mount(device).expect("Cannot mount the scratch volume");
+    check_root(&ext2);
+    assert_eq!(ext2.free_counts(), Ok(free));
+
+    let block_size = ext2.block_size();
+    let per_block = block_size / 4;
+    let mut root = ext2.root().expect("Cannot read the root inode");
+
+    // A file long enough to need the single indirect block
+    let data: Vec<u8> = (0..block_size * (DIRECT_BLOCKS + 2) + 100)
+        .map(|i| i as u8)
+        .collect();
+    let mut file = ext2
+        .create(&mut root, "file", S_IFREG | 0o644)
+        .expect("Cannot create a file");
+    assert_eq!(
+        ext2.create(&mut root, "file", S_IFREG | 0o644).err(),
+        Some(Ext2Error::AlreadyExists)
+    );
+    assert_eq!(ext2.write(&mut file, 0, &data), Ok(data.len()));
+    assert!(ext2.read_file("/file").expect("Cannot read a written file") == data);
+    // 15 data blocks and the indirect block
+    assert_eq!(
+        ext2.free_counts().map(|(blocks, _)| blocks),
+        Ok(free.0 - (DIRECT_BLOCKS as u32 + 4))
+    );
+
+    // A byte far away goes through the double indirect block, what is
+    // between stays a hole
+    let far = ((DIRECT_BLOCKS + per_block) * block_size) as u64;
+    assert_eq!(ext2.write(&mut file, far, b"x"), Ok(1));
+    let mut bytes = [0xffu8; 2];
+    assert_eq!(ext2.read(&file, far - 1, &mut bytes), Ok(2));
+    assert_eq!(&bytes, b"\0x");
+
+    // Shrinking frees the blocks, growing again reads zeros
+    ext2.truncate(&mut file, 10)
+        .expect("Cannot truncate a file");
+    ext2.truncate(&mut file, 20).expect("Cannot grow a file");
+    let read = ext2
+        .read_file("/file")
+        .expect("Cannot read a truncated file");
+    assert!(read[..10] == data[..10] && read[10..] == [0; 10]);
+    assert_eq!(
+        ext2.free_counts().map(|(blocks, _)| blocks),
+        Ok(free.0 - 1),
+        "Truncate leaks blocks"
+    );
+
+    let dir = ext2
+        .create(&mut root, "dir", S_IFDIR | 0o755)
+        .expect("Cannot create a directory");
+    assert_eq!(ext2.root().map(|root| root.links), Ok(3));
+    ext2.rename(ROOT_INODE, "file", dir.number, "moved")
+        .expect("Cannot move a file");
+    assert_eq!(ext2.lookup("/file").err(), Some(Ext2Error::NotFound));
+    assert!(ext2.read_file("/dir/moved").unwrap()[..10] == data[..10]);
+
+    // A directory moved to another parent points its ".." there
+    let mut dir = ext2.read_inode(dir.number).unwrap();
+    ext2.create(&mut dir, "sub", S_IFDIR | 0o755)
+        .expect("Cannot create a directory");
+    ext2.rename(dir.number, "sub", ROOT_INODE, "sub")
+        .expect("Cannot move a directory");
+    assert_eq!(
+        ext2.lookup("/sub/..").map(|inode| inode.number),
+        Ok(ROOT_INODE)
+    );
+    assert_eq!(ext2.lookup("/dir").map(|dir| dir.links), Ok(2));
+    assert_eq!(ext2.root().map(|root| root.links), Ok(4));
+
+    // Short targets live in the inode, long ones in a block
+    let mut root = ext2.root().unwrap();
+    let long = alloc::format!("/dir/{}moved", "./".repeat(40));
+    ext2.symlink(&mut root, "link", "dir/moved")
+        .expect("Cannot create a symlink");
+    ext2.symlink(&mut root, "long-link", &long)
+        .expect("Cannot create a symlink");
+    for link in ["/link", "/long-link"] {
+        assert_eq!(ext2.lookup(link).map(|inode| inode.size), Ok(20));
+    }
+    assert_eq!(
+        ext2.read_link(&ext2.lookup_link("/long-link").unwrap()),
+        Ok(long)
+    );
+
+    // The root directory grows by a block once its first one is full
+    let names: Vec<String> = (0..SCRATCH_FILL_ENTRIES)
+        .map(|i| alloc::format!("entry-with-a-rather-long-name-{:02}", i))
+        .collect();
+    for name in names.iter() {
+        ext2.create(&mut root, name, S_IFREG | 0o644)
+            .expect("Cannot fill a directory");
+    }
+    assert_eq!(root.size, 2 * block_size as u64);
+    for name in names.iter() {
+        ext2.unlink(&mut root, name).expect("Cannot remove a file");
+    }
+
+    assert_eq!(
+        ext2.unlink(&mut root, "dir").err(),
+        Some(Ext2Error::DirectoryNotEmpty)
+    );
+    for name in ["link", "long-link", "sub"] {
+        ext2.unlink(&mut root, name)
+            .expect("Cannot remove an entry");
+    }
+    let mut dir = ext2.lookup("/dir").unwrap();
+    ext2.unlink(&mut dir, "moved")
+        .expect("Cannot remove a file");
+    ext2.unlink(&mut root, "dir")
+        .expect("Cannot remove a directory");
+
+    // Everything is back but the block the root directory grew by, the
+    // group descriptor agrees with the superblock
+    assert_eq!(
+        ext2.free_counts(),
+        Ok((free.0 - 1, free.1)),
+        "Blocks or inodes are leaking"
+    );
+    assert_eq!(root.links, 2);
+    assert_eq!(
+        ext2.read_u16(ext2.descriptor(0, BG_FREE_BLOCKS)),
+        Ok(free.0 as u16 - 1)
+    );
+    assert_eq!(ext2.read_u16(ext2.descriptor(0, BG_USED_DIRS)), Ok(1));
+
+    // The free counts reach the disk on sync
+    ext2.sync().expect("Cannot sync the volume");
+    let disk = block::get(device).unwrap();
+    let mut sector = vec![0u8; disk.sector_size()];
+    disk.read(SUPERBLOCK_OFFSET / disk.sector_size() as u64, &mut sector)
+        .expect("Cannot read the superblock");
+    let in_sector = (S_FREE_BLOCKS % disk.sector_size() as u64) as usize;
+    assert_eq!(le_u32(&sector, in_sector), free.0 - 1);
+
+    // Leave the scratch disk clean for the next check
+    let _ = ramdisk::scratch();
+}
+
+fn check_root(ext2: &Ext2) {
+    let root = ext2.root().expect("Cannot read the root inode");
+    assert!(root.is_dir(), "Root inode is not a directory");
+
+    // The parent of the root is the root itself
+    let entries = ext2
+        .read_dir(&root)
+        .expect("Cannot list the root directory");
+    for name in [".", ".."] {
+        let entry = entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .expect("Root directory has no dot entries");
+        assert_eq!(entry.inode, ROOT_INODE);
+    }
+    assert_eq!(
+        ext2.lookup("/../.").map(|inode| inode.number),
+        Ok(ROOT_INODE)
+    );
+}
+
+// Make an ext2 volume on the scratch disk: blocks of 1 KiB in a single
+// group, the bitmaps, the inode table and the root directory right after
+// the group descriptors. Returns the free blocks and inodes.
+fn format_scratch(device: usize) -> (u32, u32) {
+    let disk = block::get(device).expect("Scratch disk is missing");
+    let sectors_per_block = (SCRATCH_BLOCK_SIZE / disk.sector_size()) as u64;
+    let blocks = (disk.num_sectors() / sectors_per_block) as u32;
+    let bits_per_block = SCRATCH_BLOCK_SIZE * 8;
+    let table_blocks = SCRATCH_INODES * GOOD_OLD_INODE_SIZE as u32 / SCRATCH_BLOCK_SIZE as u32;
+    let root_block = 5 + table_blocks;
+    // Block 0 is left for the boot loader, the group starts at block 1
+    let free_blocks = blocks - 1 - root_block;
+    let free_inodes = SCRATCH_INODES - (GOOD_OLD_FIRST_INODE - 1);
+
+    let write = |number: u32, data: &[u8]| {
+        disk.write(number as u64 * sectors_per_block, data)
+            .expect("Cannot write the scratch volume")
+    };
+    let set_bits = |data: &mut [u8], bits: core::ops::Range<usize>| {
+        for bit in bits {
+            data[bit / 8] |= 1 << (bit % 8);
+        }
+    };
+    let mut block = vec![0u8; SCRATCH_BLOCK_SIZE];
+
+    block[0..4].copy_from_slice(&SCRATCH_INODES.to_le_bytes());
+    block[4..8].copy_from_slice(&blocks.to_le_bytes());
+    block[12..16].copy_from_slice(&free_blocks.to_le_bytes());
+    block[16..20].copy_from_slice(&free_inodes.to_le_bytes());
+    block[20..24].copy_from_slice(&1u32.to_le_bytes());
+    block[32..36].copy_from_slice(&(bits_per_block as u32).to_le_bytes());
+    block[36..40].copy_from_slice(&(bits_per_block as u32).to_le_bytes());
+    block[40..44].copy_from_slice(&SCRATCH_INODES.to_le_bytes());
+    block[56..58].copy_from_slice(&EXT2_MAGIC.to_le_bytes());
+    block[58..60].copy_from_slice(&1u16.to_le_bytes());
+    block[60..62].copy_from_slice(&1u16.to_le_bytes());
+    block[76..80].copy_from_slice(&1u32.to_le_bytes());
+    block[84..88].copy_from_slice(&GOOD_OLD_FIRST_INODE.to_le_bytes());
+    block[88..90].copy_from_slice(&(GOOD_OLD_INODE_SIZE as u16).to_le_bytes());
+    block[96..100].copy_from_slice(&INCOMPAT_FILETYPE.to_le_bytes());
+    write(1, &block);
+
+    block.fill(0);
+    block[0..4].copy_from_slice(&3u32.to_le_bytes());
+    block[4..8].copy_from_slice(&4u32.to_le_bytes());
+    block[8..12].copy_from_slice(&5u32.to_le_bytes());
+    block[12..14].copy_from_slice(&(free_blocks as u16).to_le_bytes());
+    block[14..16].copy_from_slice(&(free_inodes as u16).to_le_bytes());
+    block[16..18].copy_from_slice(&1u16.to_le_bytes());
+    write(2, &block);
+
+    // Bits past the end of the group are set, like mke2fs does
+    block.fill(0);
+    set_bits(&mut block, 0..root_block as usize);
+    set_bits(&mut block, blocks as usize - 1..bits_per_block);
+    write(3, &block);
+
+    block.fill(0);
+    set_bits(&mut block, 0..GOOD_OLD_FIRST_INODE as usize - 1);
+    set_bits(&mut block, SCRATCH_INODES as usize..bits_per_block);
+    write(4, &block);
+
+    // The root directory is the second inode of the table
+    block.fill(0);
+    let root = &mut block[GOOD_OLD_INODE_SIZE..2 * GOOD_OLD_INODE_SIZE];
+    root[0..2].copy_from_slice(&(S_IFDIR | 0o755).to_le_bytes());
+    root[4..8].copy_from_slice(&(SCRATCH_BLOCK_SIZE as u32).to_le_bytes());
+    root[26..28].copy_from_slice(&2u16.to_le_bytes());
+    root[28..32].copy_from_slice(&((SCRATCH_BLOCK_SIZE / 512) as u32).to_le_bytes());
+    root[40..44].copy_from_slice(&root_block.to_le_bytes());
+    write(5, &block);
+
+    block.fill(0);
+    for (position, name, record_len) in [(0, ".", 12), (12, "..", SCRATCH_BLOCK_SIZE - 12)] {
+        let record = &mut block[position..position + record_len];
+        record[0..4].copy_from_slice(&ROOT_INODE.to_le_bytes());
+        record[4..6].copy_from_slice(&(record_len as u16).to_le_bytes());
+        record[6] = name.len() as u8;
+        record[7] = FT_DIR;
+        record[8..8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+    write(root_block, &block);
+
+    (free_blocks, free_inodes)
+}
+
+//----------- VFS glue ---------------//
+
+impl From<Ext2Error> for VfsError {
+    fn from(error: Ext2Error) -> Self {
+        match error {
+            Ext2Error::Io(_) | Ext2Error::Corrupted => VfsError::IoError,
+            Ext2Error::NotExt2 => VfsError::InvalidArgument,
+            Ext2Error::Unsupported => VfsError::Unsupported,
+            Ext2Error::NotFound => VfsError::NotFound,
+            Ext2Error::NotADirectory => VfsError::NotADirectory,
+            Ext2Error::IsADirectory => VfsError::IsADirectory,
+            Ext2Error::InvalidPath => VfsError::InvalidPath,
+            Ext2Error::TooManySymlinks => VfsError::TooManySymlinks,
+            Ext2Error::NoSpace => VfsError::NoSpace,
+            Ext2Error::AlreadyExists => VfsError::AlreadyExists,
+            Ext2Error::DirectoryNotEmpty => VfsError::DirectoryNotEmpty,
+            Ext2Error::ReadOnly => VfsError::ReadOnly,
+        }
+    }
+}
+
+pub struct Ext2Fs {
+    ext2: &'static Ext2,
+}
+
+impl Ext2Fs {
+    pub fn new(ext2: &'static Ext2) -> Self {
+        Ext2Fs { ext2 }
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.ext2.root().expect("Cannot read the ext2 root inode");
+        Ext2Inode::new(self.ext2, ROOT_INODE)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(self.ext2.sync()?)
+    }
+}
+
+// Only the number is kept, the inode is read again for every operation since
+// another handle may have changed it
+struct Ext2Inode {
+    ext2: &'static Ext2,
+    number: u32,
+}
+
+impl Ext2Inode {
+    fn new(ext2: &'static Ext2, number: u32) -> Arc<Ext2Inode> {
+        ext2.held.borrow_mut().push(number);
+        Arc::new(Ext2Inode { ext2, number })
+    }
+
+    fn inode(&self) -> Result<Inode, VfsError> {
+        Ok(self.ext2.read_inode(self.number)?)
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        let mut held = self.ext2.held.borrow_mut();
+        if let Some(index) = held.iter().position(|number| *number == self.number) {
+            held.swap_remove(index);
+        }
+    }
+}
+
+impl VfsInode for Ext2Inode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let inode = self.inode()?;
+        Ok(Stat {
+            inode: inode.number as u64,
+            file_type: inode.file_type(),
+            size: inode.size,
+            links: inode.links as u32,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(self.ext2.read(&self.inode()?, offset, buf)?)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(self.ext2.write(&mut self.inode()?, offset, buf)?)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        Ok(self.ext2.truncate(&mut self.inode()?, size)?)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        let inode = self.ext2.find(&self.inode()?, name)?;
+        Ok(Ext2Inode::new(self.ext2, inode.number))
+    }
+
+    fn read_dir(&self) -> Result<Vec<VfsDirEntry>, VfsError> {
+        let mut entries = Vec::new();
+        for entry in self.ext2.read_dir(&self.inode()?)? {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let inode = self.ext2.read_inode(entry.inode)?;
+            entries.push(VfsDirEntry {
+                name: entry.name,
+                file_type: inode.file_type(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<InodeRef, VfsError> {
+        let mode = match file_type {
+            FileType::Regular => S_IFREG | 0o644,
+            FileType::Directory => S_IFDIR | 0o755,
+            _ => return Err(VfsError::Unsupported),
+        };
+        let inode = self.ext2.create(&mut self.inode()?, name, mode)?;
+        Ok(Ext2Inode::new(self.ext2, inode.number))
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, VfsError> {
+        let inode = self.ext2.symlink(&mut self.inode()?, name, target)?;
+        Ok(Ext2Inode::new(self.ext2, inode.number))
+    }
+
+    // An inode that is held, by an open file for one, keeps its blocks
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let mut directory = self.inode()?;
+        let target = self.ext2.find(&directory, name)?;
+        if self.ext2.held.borrow().contains(&target.number) {
+            return Err(VfsError::Busy);
+        }
+        Ok(self.ext2.unlink(&mut directory, name)?)
+    }
+
+    // Inode numbers do not change when an entry moves, held inodes can be renamed
+    fn rename(
+        &self,
+        name: &str,
+        new_parent: &dyn VfsInode,
+        new_name: &str,
+    ) -> Result<(), VfsError> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<Ext2Inode>()
+            .filter(|parent| core::ptr::eq(parent.ext2, self.ext2))
+            .ok_or(VfsError::CrossDevice)?;
+        Ok(self
+            .ext2
+            .rename(self.number, name, new_parent.number, new_name)?)
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        Ok(self.ext2.read_link(&self.inode()?)?)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
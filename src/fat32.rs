@@ -0,0 +1,1664 @@
+// FAT32 file system on top of a block device, all sectors go through the buffer cache.
+// The first block device holding a FAT32 volume is mounted at init. Changes stay
+// in the buffer cache until sync is called.
+
+use crate::bcache;
+use crate::block::{self, BlockError};
+use crate::ramdisk;
+use crate::vfs::{DirEntry as VfsDirEntry, FileSystem, FileType, Inode, InodeRef, Stat, VfsError};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+
+const BOOT_SIGNATURE: u16 = 0xaa55;
+const DIR_ENTRY_SIZE: usize = 32;
+
+// Values of a FAT entry
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FAT_BAD_CLUSTER: u32 = 0x0fff_fff7;
+const FAT_END_OF_CHAIN: u32 = 0x0fff_fff8;
+const FAT_FREE: u32 = 0;
+// Written to mark the end of a chain
+const FAT_EOC: u32 = 0x0fff_ffff;
+
+// Signatures of the FSInfo sector
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+// 1980-01-01, the FAT epoch, we have no real time clock
+const FAT_DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+// First byte of a short entry
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+// A name really starting with 0xe5 is stored as 0x05
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+// Bits of the reserved byte Windows uses for all lowercase 8.3 names
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+const LFN_LAST_ENTRY: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1f;
+const LFN_CHARS_PER_ENTRY: usize = 13;
+// Offsets of the UTF-16 characters inside a long name entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Io(BlockError),
+    // The device does not hold a FAT32 volume
+    NotFat32,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    InvalidPath,
+    // A cluster chain or directory entry does not make sense
+    Corrupted,
+    // No free cluster left on the volume
+    NoSpace,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    ReadOnly,
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> Self {
+        FatError::Io(error)
+    }
+}
+
+pub struct Fat32 {
+    // Registry id of the block device
+    device: usize,
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    reserved_sectors: u64,
+    fat_count: u64,
+    // Sectors used by one copy of the FAT
+    fat_size: u64,
+    root_cluster: u32,
+    first_data_sector: u64,
+    cluster_count: u32,
+    fs_info_sector: Option<u64>,
+    read_only: bool,
+    // Where the search for a free cluster starts
+    next_free: Cell<u32>,
+    // Free cluster count of FSInfo, None when unknown. It is written back on
+    // sync once clusters were taken or freed.
+    free_count: Cell<Option<u32>>,
+    fs_info_dirty: Cell<bool>,
+    // Paths of the inodes handed to the VFS, they keep a copy of their entry
+    // so the files must not move or go away under them
+    inodes: RefCell<Vec<String>>,
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    // First cluster of the directory holding the entry, 0 for the root itself
+    parent: u32,
+    // Index of the first long name entry and of the short entry in the parent
+    first_slot: usize,
+    slot: usize,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+}
+
+fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+// "FOO     TXT" -> "FOO.TXT", honoring the lowercase flags
+fn short_name(entry: &[u8]) -> String {
+    let mut name = String::new();
+    let lower_base = entry[12] & LOWERCASE_BASE != 0;
+    let lower_ext = entry[12] & LOWERCASE_EXT != 0;
+
+    for (i, byte) in entry[..8].iter().enumerate() {
+        let byte = if i == 0 && *byte == ENTRY_KANJI_E5 {
+            ENTRY_DELETED
+        } else {
+            *byte
+        };
+        if byte == b' ' {
+            break;
+        }
+        name.push(if lower_base {
+            byte.to_ascii_lowercase()
+        } else {
+            byte
+        } as char);
+    }
+
+    if entry[8] != b' ' {
+        name.push('.');
+        for byte in entry[8..11].iter().take_while(|b| **b != b' ') {
+            name.push(if lower_ext {
+                byte.to_ascii_lowercase()
+            } else {
+                *byte
+            } as char);
+        }
+    }
+
+    name
+}
+
+// Checksum of the 8.3 name stored in every long name entry
+fn short_name_checksum(entry: &[u8]) -> u8 {
+    entry[..11]
+        .iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+// Long name being assembled from the entries preceding a short entry.
+// Entries are stored last part first.
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    expected: u8,
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName {
+            chars: Vec::new(),
+            checksum: 0,
+            expected: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.chars.clear();
+        self.expected = 0;
+    }
+
+    fn push(&mut self, entry: &[u8]) {
+        let sequence = entry[0] & LFN_SEQUENCE_MASK;
+        if entry[0] & LFN_LAST_ENTRY != 0 {
+            self.chars = vec![0xffff; sequence as usize * LFN_CHARS_PER_ENTRY];
+            self.checksum = entry[13];
+            self.expected = sequence;
+        }
+
+        // Out of order entries or an orphan: forget about the long name
+        if sequence == 0 || sequence != self.expected || entry[13] != self.checksum {
+            self.reset();
+            return;
+        }
+
+        let start = (sequence as usize - 1) * LFN_CHARS_PER_ENTRY;
+        for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = le_u16(entry, *offset);
+        }
+        self.expected -= 1;
+    }
+
+    // The complete name if it belongs to the given short entry
+    fn take(&mut self, short_entry: &[u8]) -> Option<String> {
+        let complete = !self.chars.is_empty()
+            && self.expected == 0
+            && self.checksum == short_name_checksum(short_entry);
+
+        let name = if complete {
+            // The name is terminated by 0x0000 and padded with 0xffff
+            let len = self
+                .chars
+                .iter()
+                .position(|c| *c == 0 || *c == 0xffff)
+                .unwrap_or(self.chars.len());
+            Some(
+                char::decode_utf16(self.chars[..len].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        self.reset();
+        name
+    }
+}
+
+impl Fat32 {
+    pub fn mount(device: usize) -> Result<Fat32, FatError> {
+        let disk = block::get(device).ok_or(FatError::Io(BlockError::NotInitialized))?;
+        if disk.num_sectors() == 0 {
+            return Err(FatError::NotFat32);
+        }
+
+        let mut boot = vec![0u8; disk.sector_size()];
+        bcache::read(device, 0, 0, &mut boot)?;
+        if boot.len() < 512 || le_u16(&boot, 510) != BOOT_SIGNATURE {
+            return Err(FatError::NotFat32);
+        }
+
+        let bytes_per_sector = le_u16(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as usize;
+        let reserved_sectors = le_u16(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entry_count = le_u16(&boot, 17);
+        let total_sectors_16 = le_u16(&boot, 19) as u64;
+        let fat_size_16 = le_u16(&boot, 22);
+        let total_sectors_32 = le_u32(&boot, 32) as u64;
+        let fat_size = le_u32(&boot, 36) as u64;
+        let root_cluster = le_u32(&boot, 44);
+        let fs_info_sector = le_u16(&boot, 48) as u64;
+
+        // FAT12/16 have a fixed root directory and a 16-bit FAT size
+        if bytes_per_sector != disk.sector_size()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || root_entry_count != 0
+            || fat_size_16 != 0
+            || fat_size == 0
+        {
+            return Err(FatError::NotFat32);
+        }
+
+        let total_sectors = if total_sectors_16 != 0 {
+            total_sectors_16
+        } else {
+            total_sectors_32
+        };
+        let first_data_sector = reserved_sectors + fat_count * fat_size;
+        if total_sectors > disk.num_sectors() || first_data_sector >= total_sectors {
+            return Err(FatError::NotFat32);
+        }
+
+        // The FAT may describe fewer clusters than it has room for
+        let cluster_count = ((total_sectors - first_data_sector) / sectors_per_cluster as u64)
+            .min(fat_size * bytes_per_sector as u64 / 4 - 2) as u32;
+        if root_cluster < 2 || root_cluster >= cluster_count + 2 {
+            return Err(FatError::NotFat32);
+        }
+
+        // FSInfo is only a hint, ignore it when it does not look right
+        let fs_info = if fs_info_sector != 0 && fs_info_sector < reserved_sectors {
+            bcache::with_sector(device, fs_info_sector, |data| {
+                (le_u32(data, 0) == FSINFO_LEAD_SIGNATURE
+                    && le_u32(data, 484) == FSINFO_STRUCT_SIGNATURE
+                    && le_u16(data, 510) == BOOT_SIGNATURE)
+                    .then(|| le_u32(data, 488))
+            })?
+        } else {
+            None
+        };
+        let fs_info_sector = fs_info.map(|_| fs_info_sector);
+        let free_count = fs_info.filter(|free| *free <= cluster_count);
+
+        Ok(Fat32 {
+            device,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_size,
+            root_cluster,
+            first_data_sector,
+            cluster_count,
+            fs_info_sector,
+            read_only: disk.is_read_only(),
+            next_free: Cell::new(2),
+            free_count: Cell::new(free_count),
+            fs_info_dirty: Cell::new(false),
+            inodes: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.first_data_sector + (cluster as u64 - 2) * self.sectors_per_cluster as u64
+    }
+
+    fn check_writable(&self) -> Result<(), FatError> {
+        if self.read_only {
+            Err(FatError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Sector and offset of the FAT entry of a cluster in the first FAT
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as u64 * 4;
+        (
+            self.reserved_sectors + offset / self.bytes_per_sector as u64,
+            (offset % self.bytes_per_sector as u64) as usize,
+        )
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FatError> {
+        let (sector, offset) = self.fat_position(cluster);
+        let entry = bcache::with_sector(self.device, sector, |data| le_u32(data, offset))?;
+        Ok(entry & FAT_ENTRY_MASK)
+    }
+
+    // Every copy of the FAT is kept identical, the top 4 bits are reserved
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let (sector, offset) = self.fat_position(cluster);
+        for copy in 0..self.fat_count {
+            bcache::with_sector_mut(self.device, sector + copy * self.fat_size, |data| {
+                let entry = le_u32(data, offset) & !FAT_ENTRY_MASK | value & FAT_ENTRY_MASK;
+                data[offset..offset + 4].copy_from_slice(&entry.to_le_bytes());
+            })?;
+        }
+        Ok(())
+    }
+
+    // Take a free cluster, zero it and link it after previous
+    fn alloc_cluster(&self, previous: Option<u32>) -> Result<u32, FatError> {
+        let start = self.next_free.get();
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (start - 2 + i) % self.cluster_count;
+            if self.fat_entry(cluster)? != FAT_FREE {
+                continue;
+            }
+
+            self.set_fat_entry(cluster, FAT_EOC)?;
+            self.count_cluster(false);
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+
+            // Whole sectors are overwritten without being read first
+            let zero = vec![0u8; self.bytes_per_sector];
+            for sector in 0..self.sectors_per_cluster as u64 {
+                bcache::write(self.device, self.cluster_sector(cluster) + sector, 0, &zero)?;
+            }
+
+            self.next_free.set(if cluster + 1 < self.cluster_count + 2 {
+                cluster + 1
+            } else {
+                2
+            });
+            return Ok(cluster);
+        }
+
+        Err(FatError::NoSpace)
+    }
+
+    // Give every cluster of the chain starting at first back to the free pool
+    fn free_chain(&self, first: u32) -> Result<(), FatError> {
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            if !self.is_valid_cluster(current) {
+                return Err(FatError::Corrupted);
+            }
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, FAT_FREE)?;
+            self.count_cluster(true);
+        }
+        Ok(())
+    }
+
+    // Keep the free count of FSInfo in step with the FAT
+    fn count_cluster(&self, freed: bool) {
+        if let Some(free) = self.free_count.get() {
+            let free = if freed {
+                free + 1
+            } else {
+                free.saturating_sub(1)
+            };
+            self.free_count.set(Some(free));
+        }
+        self.fs_info_dirty.set(true);
+    }
+
+    // Cluster following the given one, None at the end of the chain
+    pub fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let next = self.fat_entry(cluster)?;
+        if next >= FAT_END_OF_CHAIN {
+            Ok(None)
+        } else if next == FAT_BAD_CLUSTER || !self.is_valid_cluster(next) {
+            Err(FatError::Corrupted)
+        } else {
+            Ok(Some(next))
+        }
+    }
+
+    // Copy bytes of a cluster starting at offset into buf
+    fn read_cluster(&self, cluster: u32, offset: usize, buf: &mut [u8]) -> Result<(), FatError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done;
+            let sector = self.cluster_sector(cluster) + (position / self.bytes_per_sector) as u64;
+            let in_sector = position % self.bytes_per_sector;
+            let len = (buf.len() - done).min(self.bytes_per_sector - in_sector);
+
+            bcache::read(self.device, sector, in_sector, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    // Copy buf into a cluster starting at offset
+    fn write_cluster(&self, cluster: u32, offset: usize, buf: &[u8]) -> Result<(), FatError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done;
+            let sector = self.cluster_sector(cluster) + (position / self.bytes_per_sector) as u64;
+            let in_sector = position % self.bytes_per_sector;
+            let len = (buf.len() - done).min(self.bytes_per_sector - in_sector);
+
+            bcache::write(self.device, sector, in_sector, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: String::from("/"),
+            attributes: ATTR_DIRECTORY,
+            first_cluster: self.root_cluster,
+            size: 0,
+            parent: 0,
+            first_slot: 0,
+            slot: 0,
+        }
+    }
+
+    fn is_root(&self, entry: &DirEntry) -> bool {
+        entry.parent == 0
+    }
+
+    // Sector and offset of the slot-th entry of the directory starting at cluster
+    fn slot_position(&self, directory: u32, slot: usize) -> Result<(u64, usize), FatError> {
+        let position = slot * DIR_ENTRY_SIZE;
+        let mut cluster = directory;
+        for _ in 0..position / self.cluster_size() {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupted)?;
+        }
+
+        let in_cluster = position % self.cluster_size();
+        Ok((
+            self.cluster_sector(cluster) + (in_cluster / self.bytes_per_sector) as u64,
+            in_cluster % self.bytes_per_sector,
+        ))
+    }
+
+    fn write_slot(&self, directory: u32, slot: usize, raw: &[u8]) -> Result<(), FatError> {
+        let (sector, offset) = self.slot_position(directory, slot)?;
+        bcache::write(self.device, sector, offset, raw)?;
+        Ok(())
+    }
+
+    // Read the first cluster and size of the entry back from its short entry
+    fn refresh(&self, entry: &mut DirEntry) -> Result<(), FatError> {
+        if self.is_root(entry) {
+            return Ok(());
+        }
+
+        let (sector, offset) = self.slot_position(entry.parent, entry.slot)?;
+        let (first_cluster, size) = bcache::with_sector(self.device, sector, |data| {
+            let raw = &data[offset..offset + DIR_ENTRY_SIZE];
+            (
+                (le_u16(raw, 20) as u32) << 16 | le_u16(raw, 26) as u32,
+                le_u32(raw, 28),
+            )
+        })?;
+
+        entry.first_cluster = first_cluster;
+        entry.size = size;
+        Ok(())
+    }
+
+    // Store the first cluster and size of the entry back in its short entry
+    fn update_entry(&self, entry: &DirEntry) -> Result<(), FatError> {
+        let (sector, offset) = self.slot_position(entry.parent, entry.slot)?;
+        bcache::with_sector_mut(self.device, sector, |data| {
+            let raw = &mut data[offset..offset + DIR_ENTRY_SIZE];
+            raw[20..22].copy_from_slice(&((entry.first_cluster >> 16) as u16).to_le_bytes());
+            raw[26..28].copy_from_slice(&(entry.first_cluster as u16).to_le_bytes());
+            raw[28..32].copy_from_slice(&entry.size.to_le_bytes());
+        })?;
+        Ok(())
+    }
+
+    // Call f with the index and the raw 32 bytes of every entry of a directory
+    // until it returns false
+    fn for_each_raw_entry<F: FnMut(usize, &[u8]) -> bool>(
+        &self,
+        directory: &DirEntry,
+        mut f: F,
+    ) -> Result<(), FatError> {
+        if !directory.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+
+        let mut cluster = Some(directory.first_cluster);
+        let mut visited = 0;
+        let mut index = 0;
+        let mut raw = vec![0u8; self.cluster_size()];
+
+        while let Some(current) = cluster {
+            // A loop in the chain would keep us here forever
+            visited += 1;
+            if !self.is_valid_cluster(current) || visited > self.cluster_count {
+                return Err(FatError::Corrupted);
+            }
+
+            self.read_cluster(current, 0, &mut raw)?;
+            for entry in raw.chunks(DIR_ENTRY_SIZE) {
+                if entry[0] == ENTRY_END || !f(index, entry) {
+                    return Ok(());
+                }
+                index += 1;
+            }
+
+            cluster = self.next_cluster(current)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_dir(&self, directory: &DirEntry) -> Result<Vec<DirEntry>, FatError> {
+        let mut entries = Vec::new();
+        let mut long_name = LongName::new();
+        let mut long_name_start = 0;
+
+        self.for_each_raw_entry(directory, |index, entry| {
+            let attributes = entry[11];
+            if entry[0] == ENTRY_DELETED {
+                long_name.reset();
+            } else if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                if entry[0] & LFN_LAST_ENTRY != 0 {
+                    long_name_start = index;
+                }
+                long_name.push(entry);
+            } else if attributes & ATTR_VOLUME_ID != 0 {
+                long_name.reset();
+            } else {
+                let (name, first_slot) = match long_name.take(entry) {
+                    Some(name) => (name, long_name_start),
+                    None => (short_name(entry), index),
+                };
+                let cluster = (le_u16(entry, 20) as u32) << 16 | le_u16(entry, 26) as u32;
+                entries.push(DirEntry {
+                    name,
+                    attributes,
+                    // ".." of a directory under the root points to cluster 0
+                    first_cluster: if cluster == 0 && attributes & ATTR_DIRECTORY != 0 {
+                        self.root_cluster
+                    } else {
+                        cluster
+                    },
+                    size: le_u32(entry, 28),
+                    parent: directory.first_cluster,
+                    first_slot,
+                    slot: index,
+                });
+            }
+            true
+        })?;
+
+        Ok(entries)
+    }
+
+    // Names are compared without case, like every FAT implementation does
+    pub fn find(&self, directory: &DirEntry, name: &str) -> Result<DirEntry, FatError> {
+        self.read_dir(directory)?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            .ok_or(FatError::NotFound)
+    }
+
+    // Resolve an absolute path like "/etc/motd"
+    pub fn lookup(&self, path: &str) -> Result<DirEntry, FatError> {
+        if !path.starts_with('/') {
+            return Err(FatError::InvalidPath);
+        }
+
+        let mut current = self.root();
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            if !current.is_dir() {
+                return Err(FatError::NotADirectory);
+            }
+            current = self.find(&current, component)?;
+        }
+        Ok(current)
+    }
+
+    pub fn list(&self, path: &str) -> Result<Vec<DirEntry>, FatError> {
+        self.read_dir(&self.lookup(path)?)
+    }
+
+    // Read from a file starting at offset, returns the number of bytes read
+    pub fn read(&self, file: &DirEntry, offset: usize, buf: &mut [u8]) -> Result<usize, FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+
+        let size = file.size as usize;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+
+        // Walk the chain up to the cluster holding offset
+        let cluster_size = self.cluster_size();
+        let mut cluster = file.first_cluster;
+        for _ in 0..offset / cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupted)?;
+        }
+
+        let mut done = 0;
+        let mut in_cluster = offset % cluster_size;
+        loop {
+            if !self.is_valid_cluster(cluster) {
+                return Err(FatError::Corrupted);
+            }
+
+            let chunk = (len - done).min(cluster_size - in_cluster);
+            self.read_cluster(cluster, in_cluster, &mut buf[done..done + chunk])?;
+            done += chunk;
+            in_cluster = 0;
+
+            if done == len {
+                return Ok(len);
+            }
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupted)?;
+        }
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FatError> {
+        let file = self.lookup(path)?;
+        let mut data = vec![0u8; file.size as usize];
+        let len = self.read(&file, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    //----------- Write support ---------------//
+
+    fn directory_at(&self, cluster: u32) -> DirEntry {
+        DirEntry {
+            first_cluster: cluster,
+            ..self.root()
+        }
+    }
+
+    // Split "/a/b/c" into the directory "/a/b" and the name "c"
+    fn split_parent<'a>(&self, path: &'a str) -> Result<(DirEntry, &'a str), FatError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').ok_or(FatError::InvalidPath)?;
+        if !is_valid_name(name) {
+            return Err(FatError::InvalidPath);
+        }
+
+        let parent = self.lookup(if parent.is_empty() { "/" } else { parent })?;
+        if !parent.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+
+    fn check_absent(&self, directory: &DirEntry, name: &str) -> Result<(), FatError> {
+        match self.find(directory, name) {
+            Ok(_) => Err(FatError::AlreadyExists),
+            Err(FatError::NotFound) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    // 8.3 name for a new entry, with a numeric tail when the long name does not fit
+    fn short_name_for(&self, directory: &DirEntry, name: &str) -> Result<ShortName, FatError> {
+        if let Some(short) = fits_short_name(name) {
+            return Ok(short);
+        }
+
+        let mut taken = Vec::new();
+        self.for_each_raw_entry(directory, |_, entry| {
+            if entry[0] != ENTRY_DELETED && entry[11] & ATTR_LONG_NAME != ATTR_LONG_NAME {
+                let mut raw = [0u8; 11];
+                raw.copy_from_slice(&entry[..11]);
+                taken.push(raw);
+            }
+            true
+        })?;
+
+        let basis = basis_name(name);
+        for number in 1..1_000_000u32 {
+            let mut tail = [0u8; 8];
+            let mut len = 0;
+            let mut n = number;
+            while n > 0 {
+                tail[7 - len] = b'0' + (n % 10) as u8;
+                n /= 10;
+                len += 1;
+            }
+            tail[7 - len] = b'~';
+            let tail = &tail[7 - len..];
+
+            let mut raw = basis;
+            let base_len = basis[..8].iter().position(|b| *b == b' ').unwrap_or(8);
+            let start = base_len.min(8 - tail.len());
+            raw[start..start + tail.len()].copy_from_slice(tail);
+            raw[start + tail.len()..8].fill(b' ');
+
+            if !taken.contains(&raw) {
+                return Ok(ShortName {
+                    raw,
+                    case: 0,
+                    needs_long_name: true,
+                });
+            }
+        }
+
+        Err(FatError::AlreadyExists)
+    }
+
+    // Index of the first of count consecutive free slots, the directory is
+    // extended with a new cluster when it is full
+    fn find_free_slots(&self, directory: &DirEntry, count: usize) -> Result<usize, FatError> {
+        let mut cluster = Some(directory.first_cluster);
+        let mut last = directory.first_cluster;
+        let mut raw = vec![0u8; self.cluster_size()];
+        let mut index = 0;
+        let mut run_start = 0;
+        let mut run = 0;
+        let mut visited = 0;
+
+        while let Some(current) = cluster {
+            visited += 1;
+            if !self.is_valid_cluster(current) || visited > self.cluster_count {
+                return Err(FatError::Corrupted);
+            }
+
+            self.read_cluster(current, 0, &mut raw)?;
+            for entry in raw.chunks(DIR_ENTRY_SIZE) {
+                if entry[0] == ENTRY_END || entry[0] == ENTRY_DELETED {
+                    if run == 0 {
+                        run_start = index;
+                    }
+                    run += 1;
+                    if run == count {
+                        return Ok(run_start);
+                    }
+                } else {
+                    run = 0;
+                }
+                index += 1;
+            }
+
+            last = current;
+            cluster = self.next_cluster(current)?;
+        }
+
+        // New clusters are zeroed, all of their slots are free
+        if run == 0 {
+            run_start = index;
+        }
+        while run < count {
+            last = self.alloc_cluster(Some(last))?;
+            run += self.cluster_size() / DIR_ENTRY_SIZE;
+        }
+        Ok(run_start)
+    }
+
+    // Write the long name and short entries of a new file in the directory
+    fn add_entry(
+        &self,
+        directory: &DirEntry,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+        size: u32,
+    ) -> Result<DirEntry, FatError> {
+        let short = self.short_name_for(directory, name)?;
+        let long_entries = if short.needs_long_name {
+            long_name_entries(name, short_name_checksum(&short.raw))
+        } else {
+            Vec::new()
+        };
+
+        let first_slot = self.find_free_slots(directory, long_entries.len() + 1)?;
+        for (i, entry) in long_entries.iter().enumerate() {
+            self.write_slot(directory.first_cluster, first_slot + i, entry)?;
+        }
+
+        let slot = first_slot + long_entries.len();
+        let raw = short_entry(&short, attributes, first_cluster, size);
+        self.write_slot(directory.first_cluster, slot, &raw)?;
+
+        Ok(DirEntry {
+            name: String::from(name),
+            attributes,
+            first_cluster,
+            size,
+            parent: directory.first_cluster,
+            first_slot,
+            slot,
+        })
+    }
+
+    // Mark the long name and short entries as deleted
+    fn remove_entry(&self, entry: &DirEntry) -> Result<(), FatError> {
+        for slot in entry.first_slot..=entry.slot {
+            let (sector, offset) = self.slot_position(entry.parent, slot)?;
+            bcache::with_sector_mut(self.device, sector, |data| data[offset] = ENTRY_DELETED)?;
+        }
+        Ok(())
+    }
+
+    // "." and ".." cannot be renamed or deleted, neither can the root
+    fn check_removable(&self, entry: &DirEntry) -> Result<(), FatError> {
+        if self.is_root(entry) || entry.name == "." || entry.name == ".." {
+            return Err(FatError::InvalidPath);
+        }
+        Ok(())
+    }
+
+    pub fn create(&self, path: &str) -> Result<DirEntry, FatError> {
+        self.check_writable()?;
+        let (parent, name) = self.split_parent(path)?;
+        self.check_absent(&parent, name)?;
+        self.add_entry(&parent, name, ATTR_ARCHIVE, 0, 0)
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<DirEntry, FatError> {
+        self.check_writable()?;
+        let (parent, name) = self.split_parent(path)?;
+        self.check_absent(&parent, name)?;
+
+        // ".." points to cluster 0 when the parent is the root
+        let cluster = self.alloc_cluster(None)?;
+        let parent_cluster = if parent.first_cluster == self.root_cluster {
+            0
+        } else {
+            parent.first_cluster
+        };
+        let dot = ShortName::dot(1);
+        let dot_dot = ShortName::dot(2);
+        self.write_cluster(cluster, 0, &short_entry(&dot, ATTR_DIRECTORY, cluster, 0))?;
+        self.write_cluster(
+            cluster,
+            DIR_ENTRY_SIZE,
+            &short_entry(&dot_dot, ATTR_DIRECTORY, parent_cluster, 0),
+        )?;
+
+        self.add_entry(&parent, name, ATTR_DIRECTORY, cluster, 0)
+            .inspect_err(|_| {
+                let _ = self.free_chain(cluster);
+            })
+    }
+
+    // Write buf at offset, growing the file when needed. A hole between the end
+    // of the file and offset is filled with zeros.
+    pub fn write(&self, file: &mut DirEntry, offset: usize, buf: &[u8]) -> Result<usize, FatError> {
+        self.check_writable()?;
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset
+            .checked_add(buf.len())
+            .filter(|end| *end <= u32::MAX as usize)
+            .ok_or(FatError::NoSpace)?;
+
+        if offset > file.size as usize {
+            let zeros = vec![0u8; self.cluster_size()];
+            while (file.size as usize) < offset {
+                let len = (offset - file.size as usize).min(zeros.len());
+                self.write(file, file.size as usize, &zeros[..len])?;
+            }
+        }
+
+        let result = self.write_clusters(file, offset, buf);
+
+        // Clusters allocated before a failure must stay reachable
+        if result.is_ok() {
+            file.size = file.size.max(end as u32);
+        }
+        self.update_entry(file)?;
+        result.map(|_| buf.len())
+    }
+
+    fn write_clusters(
+        &self,
+        file: &mut DirEntry,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<(), FatError> {
+        let cluster_size = self.cluster_size();
+        if file.first_cluster == 0 {
+            file.first_cluster = self.alloc_cluster(None)?;
+        }
+
+        // Walk the chain up to offset, it ends right after the last byte of the file
+        let next_or_alloc = |cluster| match self.next_cluster(cluster)? {
+            Some(next) => Ok(next),
+            None => self.alloc_cluster(Some(cluster)),
+        };
+        let mut cluster = file.first_cluster;
+        for _ in 0..offset / cluster_size {
+            cluster = next_or_alloc(cluster)?;
+        }
+
+        let mut done = 0;
+        let mut in_cluster = offset % cluster_size;
+        loop {
+            let chunk = (buf.len() - done).min(cluster_size - in_cluster);
+            self.write_cluster(cluster, in_cluster, &buf[done..done + chunk])?;
+            done += chunk;
+            in_cluster = 0;
+
+            if done == buf.len() {
+                return Ok(());
+            }
+            cluster = next_or_alloc(cluster)?;
+        }
+    }
+
+    // Write at the end of the file, creating it when it does not exist
+    pub fn append(&self, path: &str, data: &[u8]) -> Result<DirEntry, FatError> {
+        let mut file = match self.lookup(path) {
+            Ok(file) => file,
+            Err(FatError::NotFound) => self.create(path)?,
+            Err(error) => return Err(error),
+        };
+        let end = file.size as usize;
+        self.write(&mut file, end, data)?;
+        Ok(file)
+    }
+
+    // Shrink the file and free the clusters past the new end, or grow it with zeros
+    pub fn truncate(&self, file: &mut DirEntry, size: u32) -> Result<(), FatError> {
+        self.check_writable()?;
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+
+        // Growing fills with zeros one cluster at a time, like a hole
+        if size > file.size {
+            let zeros = vec![0u8; self.cluster_size()];
+            while file.size < size {
+                let len = ((size - file.size) as usize).min(zeros.len());
+                self.write(file, file.size as usize, &zeros[..len])?;
+            }
+            return Ok(());
+        }
+
+        let keep = (size as usize).div_ceil(self.cluster_size());
+        if keep == 0 {
+            if file.first_cluster != 0 {
+                self.free_chain(file.first_cluster)?;
+                file.first_cluster = 0;
+            }
+        } else {
+            let mut last = file.first_cluster;
+            for _ in 1..keep {
+                last = self.next_cluster(last)?.ok_or(FatError::Corrupted)?;
+            }
+            if let Some(next) = self.next_cluster(last)? {
+                self.set_fat_entry(last, FAT_EOC)?;
+                self.free_chain(next)?;
+            }
+        }
+
+        file.size = size;
+        self.update_entry(file)
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<DirEntry, FatError> {
+        self.check_writable()?;
+        let entry = self.lookup(from)?;
+        self.check_removable(&entry)?;
+
+        let (parent, name) = self.split_parent(to)?;
+        match self.find(&parent, name) {
+            // Changing the case of a name is fine
+            Ok(existing) if existing.parent == entry.parent && existing.slot == entry.slot => {}
+            Ok(_) => return Err(FatError::AlreadyExists),
+            Err(FatError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        // A directory cannot move below itself
+        if entry.is_dir() {
+            let mut cluster = parent.first_cluster;
+            for _ in 0..self.cluster_count {
+                if cluster == entry.first_cluster {
+                    return Err(FatError::InvalidPath);
+                }
+                if cluster == self.root_cluster {
+                    break;
+                }
+                cluster = self.find(&self.directory_at(cluster), "..")?.first_cluster;
+            }
+        }
+
+        // The new entry is written first so that a failure does not lose the file
+        let moved = self.add_entry(
+            &parent,
+            name,
+            entry.attributes,
+            entry.first_cluster,
+            entry.size,
+        )?;
+        self.remove_entry(&entry)?;
+
+        if entry.is_dir() && moved.parent != entry.parent {
+            let mut dot_dot = self.find(&moved, "..")?;
+            dot_dot.first_cluster = if parent.first_cluster == self.root_cluster {
+                0
+            } else {
+                parent.first_cluster
+            };
+            self.update_entry(&dot_dot)?;
+        }
+
+        Ok(moved)
+    }
+
+    // Remove a file or an empty directory
+    pub fn delete(&self, path: &str) -> Result<(), FatError> {
+        self.check_writable()?;
+        let entry = self.lookup(path)?;
+        self.check_removable(&entry)?;
+
+        if entry.is_dir()
+            && self
+                .read_dir(&entry)?
+                .iter()
+                .any(|child| child.name != "." && child.name != "..")
+        {
+            return Err(FatError::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(&entry)?;
+        if entry.first_cluster != 0 {
+            self.free_chain(entry.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    pub fn free_clusters(&self) -> Result<u32, FatError> {
+        let mut free = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? == FAT_FREE {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    // Write every change back to the disk
+    pub fn sync(&self) -> Result<(), FatError> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        // FSInfo is left alone until the FAT changes, an unknown free count
+        // is left for the next reader to compute
+        if let Some(sector) = self.fs_info_sector.filter(|_| self.fs_info_dirty.get()) {
+            let free = self.free_count.get().unwrap_or(FSINFO_UNKNOWN);
+            bcache::with_sector_mut(self.device, sector, |data| {
+                data[488..492].copy_from_slice(&free.to_le_bytes());
+                data[492..496].copy_from_slice(&self.next_free.get().to_le_bytes());
+            })?;
+            self.fs_info_dirty.set(false);
+        }
+
+        bcache::sync_device(self.device)?;
+        Ok(())
+    }
+}
+
+// Raw 8.3 name of an entry and the lowercase flags of the reserved byte
+struct ShortName {
+    raw: [u8; 11],
+    case: u8,
+    needs_long_name: bool,
+}
+
+impl ShortName {
+    // "." or ".."
+    fn dot(count: usize) -> ShortName {
+        let mut raw = [b' '; 11];
+        raw[..count].fill(b'.');
+        ShortName {
+            raw,
+            case: 0,
+            needs_long_name: false,
+        }
+    }
+}
+
+const INVALID_NAME_CHARS: &[u8] = b"\"*/:<>?\\|";
+// Characters allowed in long names but not in 8.3 names
+const INVALID_SHORT_CHARS: &[u8] = b"+,.;=[] ";
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= 255
+        && !name
+            .bytes()
+            .any(|b| b < 0x20 || INVALID_NAME_CHARS.contains(&b))
+}
+
+fn is_valid_short_char(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !INVALID_SHORT_CHARS.contains(&byte)
+}
+
+// The 8.3 form of the name when it is one, each part must have a single case
+fn fits_short_name(name: &str) -> Option<ShortName> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.bytes().chain(ext.bytes()).all(is_valid_short_char)
+    {
+        return None;
+    }
+
+    let case_flag = |part: &str, flag: u8| {
+        let lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => None,
+            (true, false) => Some(flag),
+            _ => Some(0),
+        }
+    };
+    let case = case_flag(base, LOWERCASE_BASE)? | case_flag(ext, LOWERCASE_EXT)?;
+
+    let mut raw = [b' '; 11];
+    for (i, byte) in base.bytes().enumerate() {
+        raw[i] = byte.to_ascii_uppercase();
+    }
+    for (i, byte) in ext.bytes().enumerate() {
+        raw[8 + i] = byte.to_ascii_uppercase();
+    }
+    if raw[0] == ENTRY_DELETED {
+        raw[0] = ENTRY_KANJI_E5;
+    }
+
+    Some(ShortName {
+        raw,
+        case,
+        needs_long_name: false,
+    })
+}
+
+// Uppercase 8.3 approximation of a long name, before the numeric tail is added
+fn basis_name(name: &str) -> [u8; 11] {
+    let name = name.trim_start_matches(['.', ' ']);
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+
+    let convert = |byte: u8| {
+        if is_valid_short_char(byte) {
+            byte.to_ascii_uppercase()
+        } else {
+            b'_'
+        }
+    };
+
+    let mut raw = [b' '; 11];
+    let base = base
+        .bytes()
+        .filter(|b| *b != b' ' && *b != b'.')
+        .map(convert);
+    for (i, byte) in base.take(8).enumerate() {
+        raw[i] = byte;
+    }
+    if raw[0] == b' ' {
+        raw[0] = b'_';
+    }
+    for (i, byte) in ext
+        .bytes()
+        .filter(|b| *b != b' ')
+        .map(convert)
+        .take(3)
+        .enumerate()
+    {
+        raw[8 + i] = byte;
+    }
+    raw
+}
+
+// Long name entries of a name, in the order they are stored on disk
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    let count = chars.len().div_ceil(LFN_CHARS_PER_ENTRY);
+    if chars.len() % LFN_CHARS_PER_ENTRY != 0 {
+        chars.push(0);
+    }
+    chars.resize(count * LFN_CHARS_PER_ENTRY, 0xffff);
+
+    (1..=count)
+        .rev()
+        .map(|sequence| {
+            let mut entry = [0u8; DIR_ENTRY_SIZE];
+            entry[0] = sequence as u8;
+            if sequence == count {
+                entry[0] |= LFN_LAST_ENTRY;
+            }
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+
+            let start = (sequence - 1) * LFN_CHARS_PER_ENTRY;
+            for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                entry[*offset..*offset + 2].copy_from_slice(&chars[start + i].to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+fn short_entry(
+    name: &ShortName,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(&name.raw);
+    entry[11] = attributes;
+    entry[12] = name.case;
+    // Creation, access and modification dates
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&FAT_DEFAULT_DATE.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+static mut VOLUME: Option<Fat32> = None;
+
+pub fn volume() -> Option<&'static Fat32> {
+    unsafe { (*(&raw const VOLUME)).as_ref() }
+}
+
+// Mount the first block device holding a FAT32 volume
+pub fn init() {
+    for device in 0..block::count() {
+        if let Ok(fat) = Fat32::mount(device) {
+            unsafe { VOLUME = Some(fat) };
+            return;
+        }
+    }
+}
+
+pub fn init_sanity_check() {
+    // "A" as long name of "A~1", built by hand
+    let mut short = [b' '; 32];
+    short[..11].copy_from_slice(b"A~1        ");
+    short[12] = 0;
+    let mut long = [0u8; 32];
+    long[0] = LFN_LAST_ENTRY | 1;
+    long[1] = b'a';
+    long[11] = ATTR_LONG_NAME;
+    long[13] = short_name_checksum(&short);
+    long[5..11].fill(0xff);
+    long[14..26].fill(0xff);
+    long[28..32].fill(0xff);
+
+    let mut name = LongName::new();
+    name.push(&long);
+    assert_eq!(
+        name.take(&short).as_deref(),
+        Some("a"),
+        "Long name is wrong"
+    );
+    assert_eq!(short_name(&short), "A~1");
+
+    // Names that fit in 8.3 do not get a long name
+    let fitting = fits_short_name("readme.txt").expect("readme.txt fits in 8.3");
+    assert_eq!(&fitting.raw, b"README  TXT");
+    assert_eq!(short_name(&short_entry(&fitting, 0, 0, 0)), "readme.txt");
+    assert!(fits_short_name("ReadMe.txt").is_none());
+    assert!(fits_short_name("long file name.txt").is_none());
+    assert_eq!(&basis_name("long file name.txt"), b"LONGFILETXT");
+
+    // The volume of the user is only read at boot
+    if let Some(fat) = volume() {
+        fat.read_dir(&fat.root())
+            .expect("Cannot read the root directory");
+    }
+
+    // Writes are checked on a volume made on the scratch ram disk
+    let device = match ramdisk::scratch() {
+        Some(device) => device,
+        None => return,
+    };
+    let clusters = format_scratch(device);
+    let fat = Fat32::mount(device).expect("Cannot mount the scratch volume");
+    assert_eq!(fat.cluster_count, clusters);
+    // The root directory takes the first cluster
+    assert_eq!(fat.free_count.get(), Some(clusters - 1));
+
+    // Create, grow, shrink, move and remove a file, the volume must end up
+    // with the same number of free clusters
+    let free = fat.free_clusters().expect("Cannot count free clusters");
+    assert_eq!(free, clusters - 1);
+    let data: Vec<u8> = (0..fat.cluster_size() * 2 + 100).map(|i| i as u8).collect();
+
+    let mut file = fat
+        .create("/Sanity Check File.txt")
+        .expect("Cannot create a file");
+    assert_eq!(
+        fat.create("/sanity check file.txt").err(),
+        Some(FatError::AlreadyExists)
+    );
+    fat.write(&mut file, 0, &data).expect("Cannot write a file");
+    fat.append("/Sanity Check File.txt", &data)
+        .expect("Cannot append to a file");
+    let read = fat
+        .read_file("/Sanity Check File.txt")
+        .expect("Cannot read a written file");
+    assert_eq!(read.len(), data.len() * 2);
+    assert!(read[..data.len()] == data[..] && read[data.len()..] == data[..]);
+
+    let mut file = fat
+        .lookup("/Sanity Check File.txt")
+        .expect("Cannot find a written file");
+    fat.truncate(&mut file, 10).expect("Cannot truncate a file");
+    assert!(fat.read_file("/Sanity Check File.txt").unwrap() == data[..10]);
+
+    fat.create_dir("/sanity.dir")
+        .expect("Cannot create a directory");
+    fat.rename("/Sanity Check File.txt", "/sanity.dir/moved.txt")
+        .expect("Cannot move a file");
+    assert_eq!(
+        fat.lookup("/Sanity Check File.txt").err(),
+        Some(FatError::NotFound)
+    );
+    assert!(fat.read_file("/sanity.dir/moved.txt").unwrap() == data[..10]);
+    assert_eq!(
+        fat.delete("/sanity.dir").err(),
+        Some(FatError::DirectoryNotEmpty)
+    );
+
+    fat.delete("/sanity.dir/moved.txt")
+        .expect("Cannot delete a file");
+    fat.delete("/sanity.dir")
+        .expect("Cannot delete a directory");
+    assert_eq!(
+        fat.free_clusters().expect("Cannot count free clusters"),
+        free,
+        "Clusters are leaking"
+    );
+
+    // The free count of FSInfo follows the FAT
+    fat.sync().expect("Cannot sync the volume");
+    let on_disk =
+        bcache::with_sector(device, 1, |data| le_u32(data, 488)).expect("Cannot read FSInfo");
+    assert_eq!(on_disk, free, "FSInfo free count is wrong");
+
+    // Leave the scratch disk clean for the next check
+    let _ = ramdisk::scratch();
+}
+
+// Make a FAT32 volume on the scratch disk: boot sector, FSInfo and a single
+// FAT of one sector, clusters of one sector and the root directory in the
+// first one. Returns the number of clusters.
+fn format_scratch(device: usize) -> u32 {
+    let disk = block::get(device).expect("Scratch disk is missing");
+    let sector_size = disk.sector_size();
+    let clusters = (sector_size / 4 - 2) as u32;
+    let mut sector = vec![0u8; sector_size];
+
+    sector[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(b"MSWIN4.1");
+    sector[11..13].copy_from_slice(&(sector_size as u16).to_le_bytes());
+    sector[13] = 1;
+    sector[14..16].copy_from_slice(&2u16.to_le_bytes());
+    sector[16] = 1;
+    sector[21] = 0xf8;
+    sector[32..36].copy_from_slice(&(disk.num_sectors() as u32).to_le_bytes());
+    sector[36..40].copy_from_slice(&1u32.to_le_bytes());
+    sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+    sector[48..50].copy_from_slice(&1u16.to_le_bytes());
+    sector[66] = 0x29;
+    sector[82..90].copy_from_slice(b"FAT32   ");
+    sector[510..512].copy_from_slice(&BOOT_SIGNATURE.to_le_bytes());
+    disk.write(0, &sector)
+        .expect("Cannot write the boot sector");
+
+    sector.fill(0);
+    sector[0..4].copy_from_slice(&FSINFO_LEAD_SIGNATURE.to_le_bytes());
+    sector[484..488].copy_from_slice(&FSINFO_STRUCT_SIGNATURE.to_le_bytes());
+    sector[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+    sector[492..496].copy_from_slice(&3u32.to_le_bytes());
+    sector[510..512].copy_from_slice(&BOOT_SIGNATURE.to_le_bytes());
+    disk.write(1, &sector).expect("Cannot write FSInfo");
+
+    // Media descriptor, reserved entry and the root directory
+    sector.fill(0);
+    sector[0..4].copy_from_slice(&0x0fff_fff8u32.to_le_bytes());
+    sector[4..8].copy_from_slice(&FAT_EOC.to_le_bytes());
+    sector[8..12].copy_from_slice(&FAT_EOC.to_le_bytes());
+    disk.write(2, &sector).expect("Cannot write the FAT");
+
+    clusters
+}
+
+//----------- VFS glue ---------------//
+
+impl From<FatError> for VfsError {
+    fn from(error: FatError) -> Self {
+        match error {
+            FatError::Io(_) | FatError::Corrupted => VfsError::IoError,
+            FatError::NotFat32 => VfsError::InvalidArgument,
+            FatError::NotFound => VfsError::NotFound,
+            FatError::NotADirectory => VfsError::NotADirectory,
+            FatError::IsADirectory => VfsError::IsADirectory,
+            FatError::InvalidPath => VfsError::InvalidPath,
+            FatError::NoSpace => VfsError::NoSpace,
+            FatError::AlreadyExists => VfsError::AlreadyExists,
+            FatError::DirectoryNotEmpty => VfsError::DirectoryNotEmpty,
+            FatError::ReadOnly => VfsError::ReadOnly,
+        }
+    }
+}
+
+pub struct FatFs {
+    fat: &'static Fat32,
+}
+
+impl FatFs {
+    pub fn new(fat: &'static Fat32) -> Self {
+        FatFs { fat }
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        "fat32"
+    }
+
+    fn root(&self) -> InodeRef {
+        FatInode::new(self.fat, String::from("/"), self.fat.root())
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(self.fat.sync()?)
+    }
+}
+
+// FAT has no inodes, files are found back through their path on the volume
+struct FatInode {
+    fat: &'static Fat32,
+    path: String,
+    entry: DirEntry,
+}
+
+impl FatInode {
+    fn new(fat: &'static Fat32, path: String, entry: DirEntry) -> Arc<FatInode> {
+        fat.inodes.borrow_mut().push(path.clone());
+        Arc::new(FatInode { fat, path, entry })
+    }
+
+    // True while an inode is held for the path or something below it, open
+    // files included
+    fn is_busy(&self, path: &str) -> bool {
+        self.fat.inodes.borrow().iter().any(|held| {
+            held.len() >= path.len()
+                && held[..path.len()].eq_ignore_ascii_case(path)
+                && (held.len() == path.len() || held.as_bytes()[path.len()] == b'/')
+        })
+    }
+
+    // Path of an entry of this directory, spelled like lookup does
+    fn existing_child_path(&self, name: &str) -> Result<String, VfsError> {
+        let entry = self.fat.find(&self.entry()?, name)?;
+        Ok(self.child_path(&entry.name))
+    }
+
+    // Another handle on the same file may have changed its size since we looked it up
+    fn entry(&self) -> Result<DirEntry, VfsError> {
+        let mut entry = self.entry.clone();
+        self.fat.refresh(&mut entry)?;
+        Ok(entry)
+    }
+
+    fn child_path(&self, name: &str) -> String {
+        let mut path = self.path.clone();
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        path.push_str(name);
+        path
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let mut inodes = self.fat.inodes.borrow_mut();
+        if let Some(index) = inodes.iter().position(|held| *held == self.path) {
+            inodes.swap_remove(index);
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let entry = self.entry()?;
+        Ok(Stat {
+            inode: (entry.parent as u64) << 32 | entry.slot as u64,
+            file_type: if entry.is_dir() {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            size: entry.size as u64,
+            links: 1,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(self.fat.read(&self.entry()?, offset as usize, buf)?)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        Ok(self.fat.write(&mut self.entry()?, offset as usize, buf)?)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        let size = u32::try_from(size).map_err(|_| VfsError::NoSpace)?;
+        Ok(self.fat.truncate(&mut self.entry()?, size)?)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        let entry = self.fat.find(&self.entry()?, name)?;
+        Ok(FatInode::new(self.fat, self.child_path(&entry.name), entry))
+    }
+
+    fn read_dir(&self) -> Result<Vec<VfsDirEntry>, VfsError> {
+        Ok(self
+            .fat
+            .read_dir(&self.entry()?)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| VfsDirEntry {
+                file_type: if entry.is_dir() {
+                    FileType::Directory
+                } else {
+                    FileType::Regular
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<InodeRef, VfsError> {
+        let path = self.child_path(name);
+        let entry = match file_type {
+            FileType::Regular => self.fat.create(&path)?,
+            FileType::Directory => self.fat.create_dir(&path)?,
+            _ => return Err(VfsError::Unsupported),
+        };
+        Ok(FatInode::new(self.fat, self.child_path(&entry.name), entry))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let path = self.existing_child_path(name)?;
+        if self.is_busy(&path) {
+            return Err(VfsError::Busy);
+        }
+        Ok(self.fat.delete(&path)?)
+    }
+
+    fn rename(&self, name: &str, new_parent: &dyn Inode, new_name: &str) -> Result<(), VfsError> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<FatInode>()
+            .ok_or(VfsError::CrossDevice)?;
+        let path = self.existing_child_path(name)?;
+        if self.is_busy(&path) {
+            return Err(VfsError::Busy);
+        }
+        self.fat.rename(&path, &new_parent.child_path(new_name))?;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,409 @@
+// Reader for the flattened device tree QEMU hands us in a1.
+// Everything in the blob is stored big-endian.
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+// Values the specification uses when a parent does not say anything
+const DEFAULT_ADDRESS_CELLS: usize = 2;
+const DEFAULT_SIZE_CELLS: usize = 1;
+
+#[repr(C)]
+pub struct Header {
+    magic: u32,
+    total_size: u32,
+    off_dt_struct: u32,
+    off_dt_strings: u32,
+    off_mem_rsvmap: u32,
+    version: u32,
+    last_comp_version: u32,
+    boot_cpuid_phys: u32,
+    size_dt_strings: u32,
+    size_dt_struct: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt {
+    base: usize,
+    total_size: usize,
+    struct_offset: usize,
+    strings_offset: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct Node {
+    fdt: Fdt,
+    name: &'static str,
+    // First token after the node name
+    body: usize,
+    // Cells used by the parent to encode our reg property
+    address_cells: usize,
+    size_cells: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct Property {
+    pub name: &'static str,
+    pub value: &'static [u8],
+}
+
+fn read_be_u32(address: usize) -> u32 {
+    unsafe { u32::from_be((address as *const u32).read_unaligned()) }
+}
+
+fn align4(val: usize) -> usize {
+    (val + 3) & !3
+}
+
+// The null terminated string at the given address
+fn c_str(address: usize) -> &'static str {
+    let mut len = 0;
+    unsafe {
+        while *((address + len) as *const u8) != 0 {
+            len += 1;
+        }
+        let bytes = core::slice::from_raw_parts(address as *const u8, len);
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+}
+
+// Read a number spanning one or two 32-bit cells
+fn read_cells(value: &[u8], cells: usize) -> usize {
+    let mut result = 0;
+    for i in 0..cells {
+        result = (result << 32) | read_be_u32(value.as_ptr() as usize + 4 * i) as usize;
+    }
+    result
+}
+
+impl Fdt {
+    pub fn from_address(address: usize) -> Option<Fdt> {
+        if address == 0 || address % 4 != 0 {
+            return None;
+        }
+
+        let header = address as *const Header;
+        unsafe {
+            if u32::from_be((*header).magic) != FDT_MAGIC {
+                return None;
+            }
+
+            Some(Fdt {
+                base: address,
+                total_size: u32::from_be((*header).total_size) as usize,
+                struct_offset: u32::from_be((*header).off_dt_struct) as usize,
+                strings_offset: u32::from_be((*header).off_dt_strings) as usize,
+            })
+        }
+    }
+
+    pub fn address(&self) -> usize {
+        self.base
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn root(&self) -> Node {
+        let cursor = self.base + self.struct_offset;
+        assert_eq!(
+            read_be_u32(cursor),
+            FDT_BEGIN_NODE,
+            "Device tree has no root"
+        );
+
+        let name = c_str(cursor + 4);
+        Node {
+            fdt: *self,
+            name,
+            body: align4(cursor + 4 + name.len() + 1),
+            address_cells: DEFAULT_ADDRESS_CELLS,
+            size_cells: DEFAULT_SIZE_CELLS,
+        }
+    }
+
+    // Look a node up by its absolute path, e.g. "/soc/plic@c000000". A component
+    // without unit address matches any unit address ("/soc/plic").
+    pub fn find_node(&self, path: &str) -> Option<Node> {
+        let mut current = self.root();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current = current.children().find(|child| child.matches(component))?;
+        }
+        Some(current)
+    }
+
+    // Depth first search for the first node compatible with the given string
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node> {
+        let mut found = None;
+        self.for_each_compatible(compatible, |node| {
+            if found.is_none() {
+                found = Some(node);
+            }
+        });
+        found
+    }
+
+    pub fn for_each_compatible<F: FnMut(Node)>(&self, compatible: &str, mut f: F) {
+        fn walk<F: FnMut(Node)>(node: Node, compatible: &str, f: &mut F) {
+            if node.is_compatible(compatible) {
+                f(node);
+            }
+            for child in node.children() {
+                walk(child, compatible, f);
+            }
+        }
+
+        walk(self.root(), compatible, &mut f);
+    }
+
+    // Returns the (start, size) of the first bank described by the /memory node
+    pub fn memory(&self) -> Option<(usize, usize)> {
+        let node = self.root().children().find(|node| {
+            node.name_without_address() == "memory"
+                || node
+                    .property("device_type")
+                    .map_or(false, |prop| prop.as_str() == Some("memory"))
+        })?;
+        node.reg().next()
+    }
+}
+
+impl Node {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn name_without_address(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or("")
+    }
+
+    fn matches(&self, component: &str) -> bool {
+        self.name == component
+            || (!component.contains('@') && self.name_without_address() == component)
+    }
+
+    pub fn properties(&self) -> PropertyIter {
+        PropertyIter {
+            fdt: self.fdt,
+            cursor: self.body,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    pub fn children(&self) -> NodeIter {
+        let cells = |name, default| {
+            self.property(name)
+                .and_then(|prop| prop.as_u32())
+                .map_or(default, |cells| cells as usize)
+        };
+
+        NodeIter {
+            fdt: self.fdt,
+            cursor: self.body,
+            address_cells: cells("#address-cells", DEFAULT_ADDRESS_CELLS),
+            size_cells: cells("#size-cells", DEFAULT_SIZE_CELLS),
+        }
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .map_or(false, |prop| prop.str_list().any(|s| s == compatible))
+    }
+
+    // (address, size) pairs of the reg property
+    pub fn reg(&self) -> RegIter {
+        RegIter {
+            value: self.property("reg").map_or(&[], |prop| prop.value),
+            address_cells: self.address_cells,
+            size_cells: self.size_cells,
+        }
+    }
+
+    // Interrupt specifiers, we only support the single cell form used by the PLIC
+    pub fn interrupts(&self) -> CellIter {
+        CellIter {
+            value: self.property("interrupts").map_or(&[], |prop| prop.value),
+        }
+    }
+}
+
+impl Property {
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.value.len() < 4 {
+            return None;
+        }
+        Some(read_be_u32(self.value.as_ptr() as usize))
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => self.as_u32().map(|v| v as u64),
+            8 => Some(read_cells(self.value, 2) as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'static str> {
+        self.str_list().next()
+    }
+
+    pub fn str_list(&self) -> impl Iterator<Item = &'static str> {
+        let value: &'static [u8] = self.value;
+        value
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+}
+
+pub struct PropertyIter {
+    fdt: Fdt,
+    cursor: usize,
+}
+
+impl Iterator for PropertyIter {
+    type Item = Property;
+
+    fn next(&mut self) -> Option<Property> {
+        loop {
+            match read_be_u32(self.cursor) {
+                FDT_PROP => {
+                    let len = read_be_u32(self.cursor + 4) as usize;
+                    let name_offset = read_be_u32(self.cursor + 8) as usize;
+                    let value = self.cursor + 12;
+                    self.cursor = align4(value + len);
+
+                    return Some(Property {
+                        name: c_str(self.fdt.base + self.fdt.strings_offset + name_offset),
+                        value: unsafe { core::slice::from_raw_parts(value as *const u8, len) },
+                    });
+                }
+                FDT_NOP => self.cursor += 4,
+                // Properties always come before the sub nodes
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct NodeIter {
+    fdt: Fdt,
+    cursor: usize,
+    address_cells: usize,
+    size_cells: usize,
+}
+
+// Move the cursor past the node starting at the given FDT_BEGIN_NODE token
+fn skip_node(mut cursor: usize) -> usize {
+    let mut depth = 0;
+    loop {
+        match read_be_u32(cursor) {
+            FDT_BEGIN_NODE => {
+                depth += 1;
+                cursor = align4(cursor + 4 + c_str(cursor + 4).len() + 1);
+            }
+            FDT_END_NODE => {
+                depth -= 1;
+                cursor += 4;
+                if depth == 0 {
+                    return cursor;
+                }
+            }
+            FDT_PROP => cursor = align4(cursor + 12 + read_be_u32(cursor + 4) as usize),
+            FDT_NOP => cursor += 4,
+            // FDT_END or a corrupted blob
+            _ => return cursor,
+        }
+    }
+}
+
+impl Iterator for NodeIter {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        loop {
+            match read_be_u32(self.cursor) {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(self.cursor + 4);
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        body: align4(self.cursor + 4 + name.len() + 1),
+                        address_cells: self.address_cells,
+                        size_cells: self.size_cells,
+                    };
+                    self.cursor = skip_node(self.cursor);
+                    return Some(node);
+                }
+                FDT_PROP => {
+                    self.cursor = align4(self.cursor + 12 + read_be_u32(self.cursor + 4) as usize)
+                }
+                FDT_NOP => self.cursor += 4,
+                // FDT_END_NODE of the parent
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct RegIter {
+    value: &'static [u8],
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl Iterator for RegIter {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let entry_len = 4 * (self.address_cells + self.size_cells);
+        if entry_len == 0 || self.value.len() < entry_len {
+            return None;
+        }
+
+        let address = read_cells(self.value, self.address_cells);
+        let size = read_cells(&self.value[4 * self.address_cells..], self.size_cells);
+        self.value = &self.value[entry_len..];
+        Some((address, size))
+    }
+}
+
+pub struct CellIter {
+    value: &'static [u8],
+}
+
+impl Iterator for CellIter {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.value.len() < 4 {
+            return None;
+        }
+
+        let cell = read_be_u32(self.value.as_ptr() as usize);
+        self.value = &self.value[4..];
+        Some(cell)
+    }
+}
+
+pub fn init_sanity_check(fdt: &Fdt) {
+    assert!(fdt.total_size() > 0, "Device tree is empty");
+    assert_eq!(fdt.root().name(), "", "Root node must not have a name");
+    assert!(fdt.memory().is_some(), "Device tree has no memory node");
+    assert!(
+        fdt.find_node("/cpus").is_some(),
+        "Device tree has no cpus node"
+    );
+    assert!(
+        fdt.find_node("/does-not-exist").is_none(),
+        "Path lookup matched a missing node"
+    );
+}
@@ -0,0 +1,603 @@
+// Machine mode firmware implementing the supervisor binary interface for the
+// kernel, like OpenSBI would. It starts at _start, sets the harts up and
+// drops the boot hart into the kernel in supervisor mode. Afterwards it only
+// runs on traps the kernel cannot handle itself: its ecalls and the machine
+// timer and software interrupts.
+//
+// Only QEMU's virt machine is supported: the CLINT provides the timer and the
+// IPIs and the sifive test device resets the machine.
+//
+// The firmware is not isolated from the kernel. PMP hides its entry point,
+// its state and its stacks, so a stray kernel write cannot clobber them, but
+// its trap handler and the drivers it calls (clint, uart, platform) are built
+// into the kernel .text, which supervisor mode can write, and a firmware panic
+// prints through the kernel console. A kernel that wants to can still change
+// what the firmware does.
+
+use crate::sbi::*;
+use crate::{clint, platform, uart};
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const MAX_HARTS: usize = 8;
+pub const STACK_SIZE: usize = 0x2000;
+
+// Not a registered implementation id
+const IMPL_ID: usize = 0x7fff;
+const IMPL_VERSION: usize = 1;
+const SPEC_VERSION: usize = 1 << 24;
+
+// Memory below the kernel hidden from supervisor mode: the entry point, the
+// firmware state and its stacks, not the firmware code (see above)
+const FIRMWARE_SIZE: usize = 0x20_0000;
+
+const MSTATUS_MPP: usize = 0b11 << 11;
+const MSTATUS_MPP_SUPERVISOR: usize = 0b01 << 11;
+const MIP_SSIP: usize = 1 << 1;
+const MIP_MSIP: usize = 1 << 3;
+const MIP_STIP: usize = 1 << 5;
+const MIP_MTIP: usize = 1 << 7;
+const MCAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+const MCAUSE_ECALL_FROM_S: usize = 9;
+
+// Exceptions the kernel handles: misaligned and faulting accesses, illegal
+// instructions, breakpoints, user ecalls and page faults
+const DELEGATED_EXCEPTIONS: usize = (1 << 0)
+    | (1 << 1)
+    | (1 << 2)
+    | (1 << 3)
+    | (1 << 4)
+    | (1 << 5)
+    | (1 << 6)
+    | (1 << 7)
+    | (1 << 8)
+    | (1 << 12)
+    | (1 << 13)
+    | (1 << 15);
+const DELEGATED_INTERRUPTS: usize = (1 << 1) | (1 << 5) | (1 << 9);
+
+// QEMU test device commands
+const TEST_PASS: u32 = 0x5555;
+const TEST_FAIL: u32 = 0x3333;
+const TEST_RESET: u32 = 0x7777;
+
+// Registers of the interrupted hart, indexed by register number
+#[repr(C)]
+pub struct TrapFrame {
+    pub registers: [usize; 32],
+}
+
+const A0: usize = 10;
+const A1: usize = 11;
+const A2: usize = 12;
+const A6: usize = 16;
+const A7: usize = 17;
+
+struct Hart {
+    status: AtomicUsize,
+    start_address: AtomicUsize,
+    opaque: AtomicUsize,
+    // Requests sent with the machine software interrupt
+    ipi_pending: AtomicBool,
+    fence_pending: AtomicBool,
+}
+
+impl Hart {
+    const fn new() -> Self {
+        Hart {
+            status: AtomicUsize::new(HartStatus::Stopped as usize),
+            start_address: AtomicUsize::new(0),
+            opaque: AtomicUsize::new(0),
+            ipi_pending: AtomicBool::new(false),
+            fence_pending: AtomicBool::new(false),
+        }
+    }
+}
+
+// Kept out of the kernel .bss, the kernel clears it when it starts
+#[link_section = ".sbi.data"]
+static HARTS: [Hart; MAX_HARTS] = [const { Hart::new() }; MAX_HARTS];
+
+// Devices the firmware drives, found in the device tree at boot. They are kept
+// with the rest of the firmware state, apart from the driver statics the
+// kernel sets up for itself.
+struct Devices {
+    uart: usize,
+    test: usize,
+    harts: usize,
+}
+
+#[link_section = ".sbi.data"]
+static mut DEVICES: Devices = Devices {
+    uart: 0x1000_0000,
+    test: 0x0010_0000,
+    harts: 1,
+};
+
+fn devices() -> &'static Devices {
+    unsafe { &*(&raw const DEVICES) }
+}
+
+extern "C" {
+    static _start: u8;
+    static _sbi_stack_end: u8;
+    static _kernel_entry: u8;
+}
+
+global_asm!(
+    r#"
+.section .entry_point, "ax"
+.global _start
+_start:
+	# Machine stack of the hart, they are below each other
+	csrr	t0, mhartid
+	li		t1, {max_harts}
+	bgeu	t0, t1, 3f
+	la		sp, _sbi_stack_end
+	li		t1, {stack_size}
+	mul		t1, t1, t0
+	sub		sp, sp, t1
+
+	# The boot hart goes on, the others wait for hart_start
+	bnez	t0, 2f
+	mv		a0, t0
+	call	firmware_main
+
+2:
+	# Only the machine software interrupt can wake us up
+	li		t1, {mip_msip}
+	csrw	mie, t1
+1:
+	wfi
+	csrr	t1, mip
+	andi	t1, t1, {mip_msip}
+	beqz	t1, 1b
+	mv		a0, t0
+	call	firmware_secondary
+
+3:
+	# More harts than we have stacks for
+	wfi
+	j		3b
+
+.align 4
+.global sbi_trap_vector
+sbi_trap_vector:
+	# mscratch holds the top of the machine stack of the hart
+	csrrw	sp, mscratch, sp
+	addi	sp, sp, -256
+	sd		x1, 8(sp)
+	sd		x3, 24(sp)
+	sd		x4, 32(sp)
+	sd		x5, 40(sp)
+	sd		x6, 48(sp)
+	sd		x7, 56(sp)
+	sd		x8, 64(sp)
+	sd		x9, 72(sp)
+	sd		x10, 80(sp)
+	sd		x11, 88(sp)
+	sd		x12, 96(sp)
+	sd		x13, 104(sp)
+	sd		x14, 112(sp)
+	sd		x15, 120(sp)
+	sd		x16, 128(sp)
+	sd		x17, 136(sp)
+	sd		x18, 144(sp)
+	sd		x19, 152(sp)
+	sd		x20, 160(sp)
+	sd		x21, 168(sp)
+	sd		x22, 176(sp)
+	sd		x23, 184(sp)
+	sd		x24, 192(sp)
+	sd		x25, 200(sp)
+	sd		x26, 208(sp)
+	sd		x27, 216(sp)
+	sd		x28, 224(sp)
+	sd		x29, 232(sp)
+	sd		x30, 240(sp)
+	sd		x31, 248(sp)
+	csrr	t0, mscratch
+	sd		t0, 16(sp)
+
+	mv		a0, sp
+	call	sbi_trap
+
+	ld		x1, 8(sp)
+	ld		x3, 24(sp)
+	ld		x4, 32(sp)
+	ld		x5, 40(sp)
+	ld		x6, 48(sp)
+	ld		x7, 56(sp)
+	ld		x8, 64(sp)
+	ld		x9, 72(sp)
+	ld		x10, 80(sp)
+	ld		x11, 88(sp)
+	ld		x12, 96(sp)
+	ld		x13, 104(sp)
+	ld		x14, 112(sp)
+	ld		x15, 120(sp)
+	ld		x16, 128(sp)
+	ld		x17, 136(sp)
+	ld		x18, 144(sp)
+	ld		x19, 152(sp)
+	ld		x20, 160(sp)
+	ld		x21, 168(sp)
+	ld		x22, 176(sp)
+	ld		x23, 184(sp)
+	ld		x24, 192(sp)
+	ld		x25, 200(sp)
+	ld		x26, 208(sp)
+	ld		x27, 216(sp)
+	ld		x28, 224(sp)
+	ld		x29, 232(sp)
+	ld		x30, 240(sp)
+	ld		x31, 248(sp)
+	addi	sp, sp, 256
+	csrrw	sp, mscratch, sp
+	mret
+"#,
+    max_harts = const MAX_HARTS,
+    stack_size = const STACK_SIZE,
+    mip_msip = const MIP_MSIP,
+);
+
+macro_rules! csr_read {
+    ($csr:literal) => {{
+        let value: usize;
+        unsafe { asm!(concat!("csrr {}, ", $csr), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! csr_write {
+    ($csr:literal, $value:expr) => {{
+        let value: usize = $value;
+        unsafe { asm!(concat!("csrw ", $csr, ", {}"), in(reg) value) };
+    }};
+}
+
+macro_rules! csr_set {
+    ($csr:literal, $bits:expr) => {{
+        let bits: usize = $bits;
+        unsafe { asm!(concat!("csrs ", $csr, ", {}"), in(reg) bits) };
+    }};
+}
+
+macro_rules! csr_clear {
+    ($csr:literal, $bits:expr) => {{
+        let bits: usize = $bits;
+        unsafe { asm!(concat!("csrc ", $csr, ", {}"), in(reg) bits) };
+    }};
+}
+
+fn stack_top(hartid: usize) -> usize {
+    &raw const _sbi_stack_end as usize - hartid * STACK_SIZE
+}
+
+fn hart(hartid: usize) -> Option<&'static Hart> {
+    if hartid < MAX_HARTS.min(devices().harts) {
+        Some(&HARTS[hartid])
+    } else {
+        None
+    }
+}
+
+// Trap handling, delegation and memory protection of the running hart
+fn setup_hart(hartid: usize) {
+    csr_write!("mtvec", sbi_trap_vector as *const () as usize);
+    csr_write!("mscratch", stack_top(hartid));
+    csr_write!("medeleg", DELEGATED_EXCEPTIONS);
+    csr_write!("mideleg", DELEGATED_INTERRUPTS);
+    // Let supervisor mode read time, cycle and instret
+    csr_write!("mcounteren", 0b111);
+    csr_write!("mie", MIP_MSIP);
+
+    // Entry 0 hides the firmware state and stacks from supervisor and user
+    // mode, entry 1 gives them everything else
+    let base = &raw const _start as usize;
+    csr_write!("pmpaddr0", (base | (FIRMWARE_SIZE / 2 - 1)) >> 2);
+    csr_write!("pmpaddr1", usize::MAX);
+    // NAPOT without permissions, then NAPOT with read, write and execute
+    csr_write!("pmpcfg0", 0x1f18);
+    unsafe { asm!("sfence.vma") };
+}
+
+// mret into supervisor mode at address with a0 and a1, translation off
+fn enter_supervisor(hartid: usize, address: usize, a0: usize, a1: usize) -> ! {
+    csr_write!("mscratch", stack_top(hartid));
+    csr_write!("satp", 0);
+    csr_clear!("mstatus", MSTATUS_MPP);
+    csr_set!("mstatus", MSTATUS_MPP_SUPERVISOR);
+    csr_write!("mepc", address);
+    unsafe {
+        asm!(
+            "mv sp, {stack}",
+            "mret",
+            stack = in(reg) stack_top(hartid),
+            in("a0") a0,
+            in("a1") a1,
+            options(noreturn)
+        );
+    }
+}
+
+#[no_mangle]
+extern "C" fn firmware_main(hartid: usize, dtb: usize) -> ! {
+    // The firmware only needs the addresses, parsing does not allocate
+    let platform = platform::probe(dtb);
+    unsafe {
+        *(&raw mut DEVICES) = Devices {
+            uart: platform.uart,
+            test: platform.test,
+            harts: platform.harts,
+        };
+    }
+    clint::init(platform.clint);
+    clint::init_sanity_check();
+
+    // The other harts stay stopped until the kernel starts them
+    HARTS[hartid]
+        .status
+        .store(HartStatus::Started as usize, Ordering::Release);
+
+    setup_hart(hartid);
+    enter_supervisor(hartid, &raw const _kernel_entry as usize, hartid, dtb)
+}
+
+#[no_mangle]
+extern "C" fn firmware_secondary(hartid: usize) -> ! {
+    setup_hart(hartid);
+    park(hartid)
+}
+
+// Wait in machine mode until hart_start asks for the hart
+fn park(hartid: usize) -> ! {
+    let hart = &HARTS[hartid];
+    csr_write!("mie", MIP_MSIP);
+    loop {
+        if hart.status.load(Ordering::Acquire) == HartStatus::StartPending as usize {
+            clint::set_msip(hartid, false);
+            hart.status
+                .store(HartStatus::Started as usize, Ordering::Release);
+            enter_supervisor(
+                hartid,
+                hart.start_address.load(Ordering::Acquire),
+                hartid,
+                hart.opaque.load(Ordering::Acquire),
+            );
+        }
+        unsafe { asm!("wfi") };
+        clint::set_msip(hartid, false);
+    }
+}
+
+// Harts selected by a mask, a base of ALL_HARTS selects all of them
+fn for_each_hart<F: FnMut(usize)>(mask: usize, base: usize, mut f: F) -> isize {
+    let harts = MAX_HARTS.min(devices().harts);
+    if base == ALL_HARTS {
+        (0..harts).for_each(f);
+        return SUCCESS;
+    }
+
+    for bit in 0..usize::BITS as usize {
+        if mask & (1 << bit) == 0 {
+            continue;
+        }
+        match base.checked_add(bit) {
+            Some(hartid) if hartid < harts => f(hartid),
+            _ => return ERR_INVALID_PARAM,
+        }
+    }
+    SUCCESS
+}
+
+fn local_fence() {
+    unsafe {
+        asm!("fence.i");
+        asm!("sfence.vma");
+    }
+}
+
+fn base(function: usize, extension: usize) -> (isize, usize) {
+    match function {
+        BASE_GET_SPEC_VERSION => (SUCCESS, SPEC_VERSION),
+        BASE_GET_IMPL_ID => (SUCCESS, IMPL_ID),
+        BASE_GET_IMPL_VERSION => (SUCCESS, IMPL_VERSION),
+        BASE_PROBE_EXTENSION => {
+            let supported = matches!(
+                extension,
+                EXT_BASE
+                    | EXT_TIME
+                    | EXT_IPI
+                    | EXT_RFENCE
+                    | EXT_HSM
+                    | EXT_SRST
+                    | EXT_LEGACY_CONSOLE_PUTCHAR
+                    | EXT_LEGACY_CONSOLE_GETCHAR
+            );
+            (SUCCESS, supported as usize)
+        }
+        BASE_GET_MVENDORID => (SUCCESS, csr_read!("mvendorid")),
+        BASE_GET_MARCHID => (SUCCESS, csr_read!("marchid")),
+        BASE_GET_MIMPID => (SUCCESS, csr_read!("mimpid")),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn set_timer(hartid: usize, deadline: u64) -> (isize, usize) {
+    clint::set_mtimecmp(hartid, deadline);
+    csr_clear!("mip", MIP_STIP);
+    csr_set!("mie", MIP_MTIP);
+    (SUCCESS, 0)
+}
+
+fn send_ipi(mask: usize, base: usize) -> (isize, usize) {
+    let error = for_each_hart(mask, base, |hartid| {
+        HARTS[hartid].ipi_pending.store(true, Ordering::Release);
+        clint::set_msip(hartid, true);
+    });
+    (error, 0)
+}
+
+// The fences are done on the calling hart right away and on the others when
+// they take the software interrupt. Flushing everything is always correct.
+fn rfence(hartid: usize, function: usize, mask: usize, base: usize) -> (isize, usize) {
+    if !matches!(
+        function,
+        RFENCE_FENCE_I | RFENCE_SFENCE_VMA | RFENCE_SFENCE_VMA_ASID
+    ) {
+        return (ERR_NOT_SUPPORTED, 0);
+    }
+
+    let error = for_each_hart(mask, base, |target| {
+        if target == hartid {
+            local_fence();
+        } else if HARTS[target].status.load(Ordering::Acquire) == HartStatus::Started as usize {
+            HARTS[target].fence_pending.store(true, Ordering::Release);
+            clint::set_msip(target, true);
+        }
+    });
+    (error, 0)
+}
+
+fn hsm(hartid: usize, frame: &TrapFrame, function: usize) -> (isize, usize) {
+    let argument = frame.registers[A0];
+    match function {
+        HSM_HART_START => {
+            let Some(target) = hart(argument) else {
+                return (ERR_INVALID_PARAM, 0);
+            };
+            let stopped = target.status.compare_exchange(
+                HartStatus::Stopped as usize,
+                HartStatus::StartPending as usize,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+            if stopped.is_err() {
+                return (ERR_ALREADY_AVAILABLE, 0);
+            }
+
+            target
+                .start_address
+                .store(frame.registers[A1], Ordering::Release);
+            target.opaque.store(frame.registers[A2], Ordering::Release);
+            clint::set_msip(argument, true);
+            (SUCCESS, 0)
+        }
+        HSM_HART_STOP => {
+            HARTS[hartid]
+                .status
+                .store(HartStatus::Stopped as usize, Ordering::Release);
+            csr_clear!("mip", MIP_STIP | MIP_SSIP);
+            park(hartid)
+        }
+        HSM_HART_GET_STATUS => match hart(argument) {
+            Some(target) => (SUCCESS, target.status.load(Ordering::Acquire)),
+            None => (ERR_INVALID_PARAM, 0),
+        },
+        // Only the default retentive suspend, which behaves like wfi
+        HSM_HART_SUSPEND if argument == 0 => {
+            unsafe { asm!("wfi") };
+            (SUCCESS, 0)
+        }
+        _ => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn system_reset(kind: usize, reason: usize) -> (isize, usize) {
+    let command = match (kind, reason) {
+        (0, 0) => TEST_PASS,
+        (0, reason) => TEST_FAIL | ((reason as u32 & 0xffff) << 16),
+        (1 | 2, _) => TEST_RESET,
+        _ => return (ERR_INVALID_PARAM, 0),
+    };
+
+    unsafe { (devices().test as *mut u32).write_volatile(command) };
+    // The device did not react
+    (ERR_FAILED, 0)
+}
+
+fn console_getchar() -> isize {
+    match uart::Uart::at(devices().uart).read() {
+        Some(byte) => byte as isize,
+        None => -1,
+    }
+}
+
+fn handle_ecall(hartid: usize, frame: &mut TrapFrame) {
+    let extension = frame.registers[A7];
+    let function = frame.registers[A6];
+    let (a0, a1) = (frame.registers[A0], frame.registers[A1]);
+
+    // Legacy extensions only return a value in a0
+    match extension {
+        EXT_LEGACY_CONSOLE_PUTCHAR => {
+            uart::Uart::at(devices().uart).write(a0 as u8);
+            frame.registers[A0] = 0;
+            return;
+        }
+        EXT_LEGACY_CONSOLE_GETCHAR => {
+            frame.registers[A0] = console_getchar() as usize;
+            return;
+        }
+        _ => {}
+    }
+
+    let (error, value) = match extension {
+        EXT_BASE => base(function, a0),
+        EXT_TIME if function == TIME_SET_TIMER => set_timer(hartid, a0 as u64),
+        EXT_IPI if function == IPI_SEND_IPI => send_ipi(a0, a1),
+        EXT_RFENCE => rfence(hartid, function, a0, a1),
+        EXT_HSM => hsm(hartid, frame, function),
+        EXT_SRST if function == SRST_SYSTEM_RESET => system_reset(a0, a1),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    };
+    frame.registers[A0] = error as usize;
+    frame.registers[A1] = value;
+}
+
+#[no_mangle]
+extern "C" fn sbi_trap(frame: &mut TrapFrame) {
+    let cause = csr_read!("mcause");
+    let hartid = csr_read!("mhartid");
+
+    if cause & MCAUSE_INTERRUPT != 0 {
+        match cause & !MCAUSE_INTERRUPT {
+            // Timer: hand it to the kernel until it sets the next deadline
+            7 => {
+                csr_clear!("mie", MIP_MTIP);
+                csr_set!("mip", MIP_STIP);
+            }
+            // Software: an IPI for the kernel or a fence request
+            3 => {
+                clint::set_msip(hartid, false);
+                let hart = &HARTS[hartid];
+                if hart.fence_pending.swap(false, Ordering::AcqRel) {
+                    local_fence();
+                }
+                if hart.ipi_pending.swap(false, Ordering::AcqRel) {
+                    csr_set!("mip", MIP_SSIP);
+                }
+            }
+            code => panic!("Unexpected interrupt {} in the SBI firmware", code),
+        }
+        return;
+    }
+
+    match cause {
+        MCAUSE_ECALL_FROM_S => {
+            handle_ecall(hartid, frame);
+            csr_write!("mepc", csr_read!("mepc") + 4);
+        }
+        _ => panic!(
+            "Unhandled exception {} in the SBI firmware at 0x{:x}, value 0x{:x}",
+            cause,
+            csr_read!("mepc"),
+            csr_read!("mtval")
+        ),
+    }
+}
+
+extern "C" {
+    fn sbi_trap_vector();
+}
@@ -0,0 +1,210 @@
+// Initramfs. A cpio archive in the "newc" format is unpacked into the root
+// file system at boot. The archive comes from QEMU's -initrd when the device
+// tree has one, otherwise the one embedded in the kernel image is used.
+
+use crate::platform;
+use crate::vfs::{self, FileType, VfsError};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// Built from config/initramfs with `just initramfs`
+static EMBEDDED: &[u8] = include_bytes!("../config/initramfs.cpio");
+
+const MAGIC: &[u8; 6] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+// File type bits of the mode field
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    BadMagic,
+    // A header field is not hexadecimal or a name is not valid UTF-8
+    BadHeader,
+    // The archive ends in the middle of an entry or has no trailer
+    Truncated,
+}
+
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnpackStats {
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    // Device nodes, fifos and sockets have no equivalent here
+    pub skipped: usize,
+}
+
+fn hex(field: &[u8]) -> Result<u32, CpioError> {
+    let text = core::str::from_utf8(field).map_err(|_| CpioError::BadHeader)?;
+    u32::from_str_radix(text, 16).map_err(|_| CpioError::BadHeader)
+}
+
+// Names and data are padded so that the next item starts on 4 bytes
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+// Every entry of the archive up to the trailer
+pub fn parse(archive: &[u8]) -> Result<Vec<Entry<'_>>, CpioError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = archive
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(CpioError::Truncated)?;
+        if &header[..6] != MAGIC {
+            return Err(CpioError::BadMagic);
+        }
+
+        // Fields are 8 hexadecimal digits following the magic
+        let field = |index: usize| hex(&header[6 + index * 8..6 + (index + 1) * 8]);
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // The name size counts the terminating NUL
+        let name_start = offset + HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated)?;
+        let name = core::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name))
+            .map_err(|_| CpioError::BadHeader)?;
+
+        let data_start = align4(name_start + name_size);
+        let data = archive
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+
+        if name == TRAILER {
+            return Ok(entries);
+        }
+
+        entries.push(Entry { name, mode, data });
+        offset = align4(data_start + file_size);
+    }
+}
+
+// Create every entry of the archive below root. Hard links are not supported.
+pub fn unpack(archive: &[u8], root: &str) -> Result<UnpackStats, VfsError> {
+    let entries = parse(archive).map_err(|_| VfsError::InvalidArgument)?;
+    let mut stats = UnpackStats::default();
+
+    for entry in entries {
+        let name = entry.name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name == "." {
+            continue;
+        }
+
+        let mut path = String::from(root.trim_end_matches('/'));
+        path.push('/');
+        path.push_str(name);
+
+        // Archives do not always list the parents before their content
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            if !parent.is_empty() {
+                vfs::mkdir_all(parent)?;
+            }
+        }
+
+        match entry.mode & S_IFMT {
+            S_IFDIR => {
+                vfs::mkdir_all(&path)?;
+                stats.directories += 1;
+            }
+            S_IFREG => {
+                let inode = match vfs::create(&path, FileType::Regular) {
+                    Ok(dentry) => dentry.inode,
+                    Err(VfsError::AlreadyExists) => {
+                        let inode = vfs::resolve(&path, true)?.inode;
+                        inode.truncate(0)?;
+                        inode
+                    }
+                    Err(error) => return Err(error),
+                };
+                if inode.write_at(0, entry.data)? != entry.data.len() {
+                    return Err(VfsError::NoSpace);
+                }
+                stats.files += 1;
+            }
+            S_IFLNK => {
+                let target =
+                    core::str::from_utf8(entry.data).map_err(|_| VfsError::InvalidArgument)?;
+                vfs::symlink(target, &path)?;
+                stats.symlinks += 1;
+            }
+            _ => stats.skipped += 1,
+        }
+    }
+
+    Ok(stats)
+}
+
+// The archive handed over by QEMU, or the embedded one
+pub fn archive() -> &'static [u8] {
+    match platform::get().initrd {
+        Some((start, end)) => unsafe {
+            core::slice::from_raw_parts(start as *const u8, end - start)
+        },
+        None => EMBEDDED,
+    }
+}
+
+static mut STATS: UnpackStats = UnpackStats {
+    files: 0,
+    directories: 0,
+    symlinks: 0,
+    skipped: 0,
+};
+
+pub fn stats() -> UnpackStats {
+    unsafe { STATS }
+}
+
+pub fn init() {
+    let stats = unpack(archive(), "/").expect("Cannot unpack the initramfs");
+    unsafe { STATS = stats };
+}
+
+pub fn init_sanity_check() {
+    // Every entry of the archive is now in the root file system
+    for entry in parse(archive()).expect("Invalid initramfs") {
+        let name = entry.name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name == "." {
+            continue;
+        }
+
+        let kind = entry.mode & S_IFMT;
+        if kind != S_IFREG && kind != S_IFDIR && kind != S_IFLNK {
+            continue;
+        }
+
+        let mut path = String::from("/");
+        path.push_str(name);
+        let stat = vfs::resolve(&path, false)
+            .and_then(|dentry| dentry.inode.stat())
+            .unwrap_or_else(|_| panic!("Initramfs entry {} is missing", path));
+        if kind == S_IFREG {
+            assert_eq!(
+                stat.size,
+                entry.data.len() as u64,
+                "Unpacked file has the wrong size"
+            );
+        }
+    }
+
+    assert_eq!(parse(b"070701").err(), Some(CpioError::Truncated));
+    assert_eq!(parse(&[b'0'; HEADER_SIZE]).err(), Some(CpioError::BadMagic));
+}
@@ -1,32 +1,187 @@
-use crate::{page_allocator, println};
+use crate::slab::SlabCache;
+use crate::{lock, page_allocator};
 use core::alloc::{GlobalAlloc, Layout};
 use core::fmt::Write;
+use core::mem::size_of;
 use core::ptr::null_mut;
 extern crate alloc;
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 struct MyAllocator;
 
 static ALLOC_SPACE: usize = 256;
 
-static mut KMALLOC_HEAD: *mut u8 = core::ptr::null_mut();
-static mut KMALLOC_END: *mut u8 = core::ptr::null_mut();
+// Every block is a multiple of this size and at least this aligned, so a free
+// block header always fits in whatever is left after a split.
+const BLOCK_ALIGN: usize = 16;
+
+// Small allocations are served by slabs, one cache per power of two
+const SIZE_CLASSES: usize = 7;
+
+static mut SIZE_CLASS_CACHES: [SlabCache; SIZE_CLASSES] = [
+    SlabCache::new("kmalloc-16", 16, 16),
+    SlabCache::new("kmalloc-32", 32, 32),
+    SlabCache::new("kmalloc-64", 64, 64),
+    SlabCache::new("kmalloc-128", 128, 128),
+    SlabCache::new("kmalloc-256", 256, 256),
+    SlabCache::new("kmalloc-512", 512, 512),
+    SlabCache::new("kmalloc-1024", 1024, 1024),
+];
+
+fn size_class(layout: &Layout) -> Option<&'static mut SlabCache> {
+    let size = layout.size().max(layout.align());
+    let class = (0..SIZE_CLASSES).find(|class| size <= 16 << class)?;
+    unsafe { Some(&mut (*(&raw mut SIZE_CLASS_CACHES))[class]) }
+}
+
+// Free blocks are kept in a list sorted by address so that neighbours can be merged
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+static mut FREE_LIST: *mut FreeBlock = null_mut();
+// Bytes taken from the page allocator for the free list, slabs not included
+static mut HEAP_SIZE: usize = 0;
+
+#[derive(Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub free: usize,
+}
+
+const fn align_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
+
+fn block_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(size_of::<FreeBlock>()), BLOCK_ALIGN)
+}
+
+fn block_align(layout: &Layout) -> usize {
+    layout.align().max(BLOCK_ALIGN)
+}
+
+// Give a region back to the free list, merging it with its neighbours
+unsafe fn insert_free(address: usize, size: usize) {
+    let mut previous: *mut FreeBlock = null_mut();
+    let mut current = FREE_LIST;
+
+    while !current.is_null() && (current as usize) < address {
+        previous = current;
+        current = (*current).next;
+    }
+
+    let block = address as *mut FreeBlock;
+    (*block).size = size;
+    (*block).next = current;
+
+    // Merge with the following block
+    if !current.is_null() && address + size == current as usize {
+        (*block).size += (*current).size;
+        (*block).next = (*current).next;
+    }
+
+    if previous.is_null() {
+        FREE_LIST = block;
+    } else if previous as usize + (*previous).size == address {
+        // Merge with the preceding block
+        (*previous).size += (*block).size;
+        (*previous).next = (*block).next;
+    } else {
+        (*previous).next = block;
+    }
+}
+
+// First fit search, returns null when no free block is large enough
+unsafe fn take_free(size: usize, align: usize) -> *mut u8 {
+    let mut previous: *mut FreeBlock = null_mut();
+    let mut current = FREE_LIST;
+
+    while !current.is_null() {
+        let start = current as usize;
+        let end = start + (*current).size;
+        let aligned = align_up(start, align);
+
+        if aligned + size <= end {
+            // Unlink the block and give back what we do not use
+            let next = (*current).next;
+            if previous.is_null() {
+                FREE_LIST = next;
+            } else {
+                (*previous).next = next;
+            }
+
+            if aligned > start {
+                insert_free(start, aligned - start);
+            }
+            if aligned + size < end {
+                insert_free(aligned + size, end - aligned - size);
+            }
+
+            return aligned as *mut u8;
+        }
+
+        previous = current;
+        current = (*current).next;
+    }
+
+    null_mut()
+}
+
+// Ask the page allocator for enough pages to serve the request
+unsafe fn grow(size: usize, align: usize) -> bool {
+    let pages = (size + align + page_allocator::PAGE_SIZE - 1) / page_allocator::PAGE_SIZE;
+    let pages = pages.max(ALLOC_SPACE);
+
+    // Retry with the exact amount if the bigger chunk is not available
+    let mut region = page_allocator::alloc(pages);
+    let mut region_pages = pages;
+    if region.is_null() {
+        region_pages = (size + align + page_allocator::PAGE_SIZE - 1) / page_allocator::PAGE_SIZE;
+        region = page_allocator::alloc(region_pages);
+    }
+
+    if region.is_null() {
+        return false;
+    }
+
+    insert_free(region as usize, region_pages * page_allocator::PAGE_SIZE);
+    HEAP_SIZE += region_pages * page_allocator::PAGE_SIZE;
+    true
+}
 
 unsafe impl GlobalAlloc for MyAllocator {
-    // TODO: Add alignment constraints
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if KMALLOC_HEAD.add(layout.size()) > KMALLOC_END {
-            println!("No space left, leaving OS!");
-            return null_mut();
+        if let Some(cache) = size_class(&layout) {
+            return cache.alloc();
         }
 
-        let output = KMALLOC_HEAD;
-        KMALLOC_HEAD = KMALLOC_HEAD.add(layout.size());
-        output
+        let size = block_size(&layout);
+        let align = block_align(&layout);
+
+        lock::without_interrupts(|| {
+            let mut output = take_free(size, align);
+            if output.is_null() && grow(size, align) {
+                output = take_free(size, align);
+            }
+
+            if output.is_null() {
+                println!("No space left for an allocation of {} bytes", layout.size());
+            }
+            output
+        })
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(cache) = size_class(&layout) {
+            return cache.free(ptr);
+        }
+
+        lock::without_interrupts(|| insert_free(ptr as usize, block_size(&layout)));
+    }
 }
 
 #[global_allocator]
@@ -34,11 +189,61 @@ static GLOBAL: MyAllocator = MyAllocator;
 
 pub fn init() {
     unsafe {
-        KMALLOC_HEAD = page_allocator::alloc(ALLOC_SPACE);
-        KMALLOC_END = KMALLOC_HEAD.add(page_allocator::PAGE_SIZE * ALLOC_SPACE);
+        FREE_LIST = null_mut();
+        let region = page_allocator::alloc(ALLOC_SPACE);
+        assert!(!region.is_null(), "Cannot allocate the kernel heap");
+        insert_free(region as usize, page_allocator::PAGE_SIZE * ALLOC_SPACE);
+        HEAP_SIZE = page_allocator::PAGE_SIZE * ALLOC_SPACE;
     }
 }
 
+pub fn stats() -> HeapStats {
+    lock::without_interrupts(|| unsafe {
+        let mut free = 0;
+        let mut current = FREE_LIST;
+        while !current.is_null() {
+            free += (*current).size;
+            current = (*current).next;
+        }
+        HeapStats {
+            size: HEAP_SIZE,
+            free,
+        }
+    })
+}
+
 pub fn init_sanity_check() {
     let _test_allocation_heap: Box<u8> = Box::new(5);
+
+    // Small objects come from the slabs
+    let small: Box<[u8; 24]> = Box::new([1; 24]);
+    assert_eq!(&*small as *const [u8; 24] as usize % 32, 0);
+
+    unsafe {
+        // Alignment must be honoured
+        let layout = Layout::from_size_align(64, 4096).unwrap();
+        let aligned = GLOBAL.alloc(layout);
+        assert!(!aligned.is_null());
+        assert_eq!(aligned as usize % 4096, 0, "Allocation is not aligned");
+
+        // Freed memory must be reused
+        GLOBAL.dealloc(aligned, layout);
+        let again = GLOBAL.alloc(layout);
+        assert_eq!(aligned, again, "Freed memory is not reused");
+        GLOBAL.dealloc(again, layout);
+    }
+
+    // Growing a vector frees its previous buffers
+    let mut values: Vec<usize> = Vec::new();
+    for i in 0..1024 {
+        values.push(i);
+    }
+    assert_eq!(values.iter().sum::<usize>(), 1023 * 1024 / 2);
+
+    // Bigger than the initial arena, forces the heap to grow
+    let before = stats();
+    let big: Vec<u8> = alloc::vec![0; page_allocator::PAGE_SIZE * ALLOC_SPACE];
+    assert_eq!(big.len(), page_allocator::PAGE_SIZE * ALLOC_SPACE);
+    assert!(stats().size > before.size, "Heap did not grow");
+    assert!(stats().free <= stats().size);
 }
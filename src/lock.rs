@@ -1,3 +1,4 @@
+use core::arch::asm;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 pub struct SpinLock {
@@ -22,3 +23,24 @@ impl SpinLock {
         self.flag.store(false, Ordering::Release);
     }
 }
+
+const SSTATUS_SIE: usize = 1 << 1;
+
+// Run f with supervisor interrupts disabled and restore the previous state afterwards.
+// With a single hart this makes f atomic with respect to the trap handler.
+pub fn without_interrupts<R, F: FnOnce() -> R>(f: F) -> R {
+    let sstatus: usize;
+    unsafe {
+        asm!("csrrci {}, sstatus, {}", out(reg) sstatus, const SSTATUS_SIE);
+    }
+
+    let result = f();
+
+    if sstatus & SSTATUS_SIE != 0 {
+        unsafe {
+            asm!("csrsi sstatus, {}", const SSTATUS_SIE);
+        }
+    }
+
+    result
+}
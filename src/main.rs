@@ -51,7 +51,6 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
 // Memory layout, defined in the linker script.
 extern "C" {
-    static _start: u8;
     static _text_start: u8;
     static _text_end: u8;
     static _rodata_start: u8;
@@ -66,20 +65,19 @@ extern "C" {
     static _heap_start: usize;
 }
 
-// This is the entry point of the operating system
+// This is the entry point of the kernel, the SBI firmware jumps here in
+// supervisor mode with the hart id in a0 and the device tree address in a1
 global_asm!(
     r#"
 .attribute arch, "rv64imac"
-.align 4
-.text
-.global _start
-_start:
-	# Any hardware threads (hart) that are not bootstrapping
-	# need to wait for an IPI
-	csrr	t0, mhartid
-	bnez	t0, _end
-
-	# Clear BSS secion
+.section .kernel_entry, "ax"
+.global _kernel_entry
+_kernel_entry:
+	# Keep the hart id and the device tree address
+	mv		s0, a0
+	mv		s1, a1
+
+	# Clear BSS secion, firmwares do not always do it
 	la 		a0, __bss_start
 	la		a1, __bss_end
 	bgeu	a0, a1, 2f
@@ -88,57 +86,44 @@ _start:
 	addi	a0, a0, 8
 	bltu	a0, a1, 1b
 2:
-	# Setup pmp registers
-	# Allow all
-	li t0, 0xFFFFFFFF
-	csrw pmpaddr0,t0
-	li t0, 0xFF
-	csrw pmpcfg0,t0
-
 	# Disable paging
 	csrw	satp, zero
 	# Stack pointer at the very end of the stack space
-	# TODO: Find previous stack pointer here
-	li		sp, 0x80f00000
-	# ld		sp, __stack_end
-	# Setting `mstatus` register:
-	# 0b11 << 11: Machine's previous protection mode is 11 (MPP=1). --> We enter in supervisor mode
-	# 1 << 7    : Machine's previous interrupt-enable bit is 1 (MPIE=1).
-	# 1 << 3    : Machine's interrupt-enable bit is 1 (MIE=1).
-	li t0, (0b11 << 11) | (1 << 7) | (1 << 3)
-	csrw	mstatus, t0
-
-    # Jump to harware initialisation code
-    jal init
+	ld		sp, __stack_end
+	# Interrupts stay off while we initialize
+	csrw	sie, zero
 
-	# Machine's exception program counter (MEPC) is set to `kinit`.
-	la		t1, kmain
-	csrw	mepc, t1
-
-	# Setup trigger first timer interrupt
-	li      t0, 5000000
-	li      t1, 0x2004000
-	sd      t0, 0(t1)
-
-	# Setting Machine's interrupt-enable bits (`mie` register):
-	# 1 << 3 : Machine's M-mode software interrupt-enable bit is 1 (MSIE=1).
-	# 1 << 7 : Machine's timer interrupt-enable bit is 1 (MTIE=1).
-	# 1 << 11: Machine's external interrupt-enable bit is 1 (MEIE=1).
-	li		t3, (1 << 3) | (1 << 7) | (1 << 11)
-	csrw	mie, t3
+    # Jump to harware initialisation code with the hart id and the device tree address
+	mv		a0, s0
+	mv		a1, s1
+    jal init
 
-	# Machine's trap vector base address is set to `asm_trap_vector`.
+	# Setting `sstatus` register:
+	# 1 << 5 : Supervisor's previous interrupt-enable bit is 1 (SPIE=1).
+	# 1 << 18: Supervisor can access user memory (SUM=1).
+	# The previous privilege mode (SPP) was set when the init process was scheduled
+	li		t0, (1 << 5) | (1 << 18)
+	csrs	sstatus, t0
+
+	# Setting Supervisor's interrupt-enable bits (`sie` register):
+	# 1 << 1 : Supervisor's software interrupt-enable bit is 1 (SSIE=1).
+	# 1 << 5 : Supervisor's timer interrupt-enable bit is 1 (STIE=1).
+	# 1 << 9 : Supervisor's external interrupt-enable bit is 1 (SEIE=1).
+	li		t3, (1 << 1) | (1 << 5) | (1 << 9)
+	csrw	sie, t3
+
+	# Supervisor's trap vector base address is set to `asm_trap_vector`.
 	la		t2, asm_trap_vector
-	csrw	mtvec, t2
+	csrw	stvec, t2
 
     # Jump now to the kernel process
 
-    # Switch t6 with mscratch
-    csrr	t6, mscratch
+    # Switch t6 with sscratch
+    csrr	t6, sscratch
 
     # Load program counter
 	ld a0, 264(t6)
-	csrw	mepc, a0
+	csrw	sepc, a0
 
 	# save the registers.
 	ld		ra, 0(t6)
@@ -174,11 +159,8 @@ _start:
 	ld		t6, 240(t6)
 
 	# Jump back
-	mret
+	sret
 
-_end:
-	wfi
-	j		_end
 .align 8
 __bss_start:
     .dword {_bss_start}
@@ -194,13 +176,13 @@ __stack_end:
 global_asm!(
     r#".global asm_trap_vector
 # This must be aligned by 4 since the last two bits
-# of the mtvec register do not contribute to the address
+# of the stvec register do not contribute to the address
 # of this vector.
 .align 4
 asm_trap_vector:
 
-    # Switch t6 with mscratch
-    csrrw	t6, mscratch, t6
+    # Switch t6 with sscratch
+    csrrw	t6, sscratch, t6
 
 	# save the registers.
 	sd		ra, 0(t6)
@@ -235,23 +217,33 @@ asm_trap_vector:
 	sd		t5, 232(t6)
 
     # Store the current pc in the process structure
-    csrr tp, mepc
+    csrr tp, sepc
     sd tp, 264(t6)
 
+    # User processes trap on their kernel stack
+    ld		t0, 272(t6)
+    beqz	t0, 1f
+    mv		sp, t0
+1:
+
     # Finally, we can store t6 in the process structure
 	mv		t5, t6
-	csrr	t6, mscratch
-	csrw mscratch, t5
+	csrr	t6, sscratch
+	csrw sscratch, t5
 
     # Jump now to the trap handler
-	call	m_trap
+	call	s_trap
 
+# Resume the process in sscratch, kernel processes whose system call switched
+# to another process come here too
+.global asm_resume_process
+asm_resume_process:
 	# Jump now to the kernel process
-	csrr t6, mscratch
+	csrr t6, sscratch
 
 	# Load program counter
 	ld a0, 264(t6)
-	csrw	mepc, a0
+	csrw	sepc, a0
 
 	ld		ra, 0(t6)
 	ld		sp, 8(t6)
@@ -270,6 +262,7 @@ asm_trap_vector:
 	ld		a5, 112(t6)
 	ld		a6, 120(t6)
 	ld		a7, 128(t6)
+	ld		s2, 136(t6)
 	ld		s3, 144(t6)
 	ld		s4, 152(t6)
 	ld		s5, 160(t6)
@@ -284,19 +277,40 @@ asm_trap_vector:
 	ld		t5, 232(t6)
 	ld		t6, 240(t6)
 
-	mret"#
+	sret"#
 );
 
 #[no_mangle]
-extern "C" fn init() {
+extern "C" fn init(hartid: usize, dtb: usize) {
+    reg::set_hartid(hartid);
+
+    // Discover the hardware from the device tree
+    platform::init(dtb);
+    let platform = platform::get();
+
     // Setup driver
-    uart::Uart::start_driver(0x1000_0000);
+    uart::Uart::start_driver(platform.uart, platform.uart_irq);
     println!("Uart driver : \x1b[32m[DONE]\x1b[0m");
 
+    match &platform.device_tree {
+        Some(fdt) => {
+            fdt::init_sanity_check(fdt);
+            println!("Device tree : \x1b[32m[DONE]\x1b[0m");
+        }
+        None => println!("Device tree : \x1b[33m[MISSING]\x1b[0m"),
+    }
+
     // Init page allocator
-    page_allocator::init_allocator();
+    page_allocator::init_allocator(platform::memory_end());
     page_allocator::init_sanity_check();
-    println!("Page allocator : \x1b[32m[DONE]\x1b[0m");
+    println!(
+        "Page allocator : \x1b[32m[DONE]\x1b[0m ({} pages)",
+        page_allocator::total_pages()
+    );
+
+    // Init slab allocator
+    slab::init_sanity_check();
+    println!("Slab allocator : \x1b[32m[DONE]\x1b[0m");
 
     // Init memory allocator
     kmalloc::init();
@@ -304,10 +318,20 @@ extern "C" fn init() {
     println!("Memory allocator : \x1b[32m[DONE]\x1b[0m");
 
     // Init plic
-    plic::init();
+    plic::init(platform.plic);
     plic::init_sanity_check();
     println!("Plic : \x1b[32m[DONE]\x1b[0m");
 
+    // Check the firmware below us
+    sbi::init_sanity_check();
+    let (major, minor) = sbi::spec_version();
+    println!(
+        "SBI : \x1b[32m[DONE]\x1b[0m (v{}.{}, implementation {:#x})",
+        major,
+        minor,
+        sbi::impl_id()
+    );
+
     // Init paging
     paging::init();
     paging::init_sanity_check();
@@ -319,7 +343,7 @@ extern "C" fn init() {
     println!("Scheduler : \x1b[32m[DONE]\x1b[0m");
 
     // Init virtio
-    virtio::init();
+    virtio::init(platform.virtio_slots());
     virtio::init_sanity_check();
     println!("Virtio : \x1b[32m[DONE]\x1b[0m");
 
@@ -328,6 +352,77 @@ extern "C" fn init() {
     block::init_sanity_check();
     println!("Block device : \x1b[32m[DONE]\x1b[0m");
 
+    // Init scratch ram disk
+    ramdisk::init();
+    ramdisk::init_sanity_check();
+    println!("Ram disk : \x1b[32m[DONE]\x1b[0m");
+
+    // Init partitions
+    partition::init();
+    partition::init_sanity_check();
+    println!(
+        "Partitions : \x1b[32m[DONE]\x1b[0m ({} found)",
+        partition::partitions().len()
+    );
+
+    // Init buffer cache
+    bcache::init();
+    bcache::init_sanity_check();
+    println!("Buffer cache : \x1b[32m[DONE]\x1b[0m");
+
+    // Init FAT32 file system
+    fat32::init();
+    fat32::init_sanity_check();
+    match fat32::volume() {
+        Some(_) => println!("FAT32 : \x1b[32m[DONE]\x1b[0m"),
+        None => println!("FAT32 : \x1b[33m[NO VOLUME]\x1b[0m"),
+    }
+
+    // Init ext2 file system
+    ext2::init();
+    ext2::init_sanity_check();
+    match ext2::volume() {
+        Some(_) => println!("ext2 : \x1b[32m[DONE]\x1b[0m"),
+        None => println!("ext2 : \x1b[33m[NO VOLUME]\x1b[0m"),
+    }
+
+    // Init ramfs
+    ramfs::init();
+    ramfs::init_sanity_check();
+    println!("Ramfs : \x1b[32m[DONE]\x1b[0m");
+
+    // Init virtual file system
+    vfs::init();
+    vfs::init_sanity_check();
+    println!("VFS : \x1b[32m[DONE]\x1b[0m");
+    vfs::for_each_mount(|path, fs| println!("    {} on {}", fs, path));
+
+    // Unpack the initramfs into the root
+    initramfs::init();
+    initramfs::init_sanity_check();
+    let unpacked = initramfs::stats();
+    println!(
+        "Initramfs : \x1b[32m[DONE]\x1b[0m ({} files, {} directories, {} symlinks)",
+        unpacked.files, unpacked.directories, unpacked.symlinks
+    );
+
+    // Init devfs
+    devfs::init();
+    devfs::init_sanity_check();
+    println!("Devfs : \x1b[32m[DONE]\x1b[0m");
+
+    // Init procfs
+    procfs::init();
+    procfs::init_sanity_check();
+    println!("Procfs : \x1b[32m[DONE]\x1b[0m");
+
+    // The system call table
+    syscall::init_sanity_check();
+    println!(
+        "System calls : \x1b[32m[DONE]\x1b[0m ({} calls)",
+        syscall::count()
+    );
+
     // Install page table
     unsafe {
         let root_address = (paging::ROOT) as usize;
@@ -337,6 +432,9 @@ extern "C" fn init() {
     }
 
     println!("Installing page table : \x1b[32m[DONE]\x1b[0m");
+
+    // Setup trigger first timer interrupt
+    sbi::set_timer(reg::time_read() + 5_000_000);
 }
 
 #[no_mangle]
@@ -350,21 +448,43 @@ extern "C" fn kmain() {
     let mut i: usize = 0;
     loop {
         println!("Init process {}", i);
+        // Collect the exit code of finished children
+        while let Ok(Some((pid, code))) = scheduler::try_wait(None) {
+            println!("Process {} exited with code {}", pid, code);
+        }
         for _ in 0..500000 {}
 
         i += 1;
     }
 }
 
+mod bcache;
 mod block;
+pub mod clint;
+pub mod devfs;
+pub mod ext2;
+pub mod fat32;
+pub mod fdt;
+pub mod firmware;
+pub mod initramfs;
 pub mod kmalloc;
 pub mod lock;
 pub mod page_allocator;
 pub mod paging;
+mod partition;
+pub mod platform;
 pub mod plic;
 pub mod process;
+pub mod procfs;
+pub mod ramdisk;
+pub mod ramfs;
 pub mod reg;
+pub mod sbi;
 pub mod scheduler;
+pub mod slab;
+pub mod syscall;
 pub mod trap;
 pub mod uart;
+pub mod user;
+pub mod vfs;
 pub mod virtio;
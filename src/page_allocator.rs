@@ -3,17 +3,23 @@ use core::ptr::null_mut;
 
 use crate::_heap_start;
 
-// TODO: Fix it dynamically
-const HEAP_SIZE: usize = 0x1000000;
+// Used when the platform does not tell us how much memory we have
+const DEFAULT_HEAP_SIZE: usize = 0x1000000;
 
 const EMPTY_PAGE: u8 = 0x0;
 const TAKEN_FLAG: u8 = 0x1;
-const LAST_FLAG: u8 = 0x2;
+const HEAD_FLAG: u8 = 0x2;
 
 pub(crate) const PAGE_SIZE: usize = 4096;
 
+// Largest block handed out by the buddy allocator is 2^MAX_ORDER pages
+pub(crate) const MAX_ORDER: usize = 10;
+
+// Every page has a descriptor. Only the first page of a block carries flags,
+// the other pages of the block stay empty.
 pub struct Page {
     flags: u8,
+    order: u8,
 }
 
 impl Page {
@@ -25,8 +31,17 @@ impl Page {
         self.flags & TAKEN_FLAG != 0
     }
 
-    pub fn last(&self) -> bool {
-        self.flags & LAST_FLAG != 0
+    // First page of a free block sitting in one of the free lists
+    pub fn head(&self) -> bool {
+        self.flags & HEAD_FLAG != 0
+    }
+
+    pub fn order(&self) -> usize {
+        self.order as usize
+    }
+
+    pub fn set_order(&mut self, order: usize) {
+        self.order = order as u8
     }
 
     pub fn set_flag(&mut self, flags: u8) {
@@ -39,31 +54,116 @@ impl Page {
 
     pub fn clear_all_flags(&mut self) {
         self.flags = 0x0;
+        self.order = 0;
     }
 }
 
+// Free blocks are linked together through their first bytes
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
 static mut ALLOC_START: usize = 0;
 pub static mut ALLOCATED_PAGE_HEAP_ALLOCATOR: usize = 0;
+static mut FREE_PAGES: usize = 0;
+
+static mut FREE_LISTS: [*mut FreeBlock; MAX_ORDER + 1] = [null_mut(); MAX_ORDER + 1];
 
 pub const fn page_align_round_up(val: usize) -> usize {
     let o = 4096 - 1;
     (val + o) & !o
 }
 
-pub fn init_allocator() {
+// Smallest order such that 2^order >= pages
+pub fn order_for(pages: usize) -> usize {
+    let mut order = 0;
+    while (1 << order) < pages {
+        order += 1;
+    }
+    order
+}
+
+unsafe fn descriptor(idx: usize) -> *mut Page {
+    ((&raw const _heap_start as usize) as *mut Page).add(idx)
+}
+
+unsafe fn block_address(idx: usize) -> *mut FreeBlock {
+    (ALLOC_START + PAGE_SIZE * idx) as *mut FreeBlock
+}
+
+unsafe fn block_index(block: *mut FreeBlock) -> usize {
+    (block as usize - ALLOC_START) / PAGE_SIZE
+}
+
+unsafe fn push_block(idx: usize, order: usize) {
+    let block = block_address(idx);
+    (*block).prev = null_mut();
+    (*block).next = FREE_LISTS[order];
+    if !FREE_LISTS[order].is_null() {
+        (*FREE_LISTS[order]).prev = block;
+    }
+    FREE_LISTS[order] = block;
+
+    let page = descriptor(idx);
+    (*page).clear_all_flags();
+    (*page).set_flag(HEAD_FLAG);
+    (*page).set_order(order);
+}
+
+unsafe fn remove_block(idx: usize, order: usize) {
+    let block = block_address(idx);
+    if (*block).prev.is_null() {
+        FREE_LISTS[order] = (*block).next;
+    } else {
+        (*(*block).prev).next = (*block).next;
+    }
+    if !(*block).next.is_null() {
+        (*(*block).next).prev = (*block).prev;
+    }
+
+    (*descriptor(idx)).clear_all_flags();
+}
+
+// Manage every page between the end of the kernel image and memory_end
+pub fn init_allocator(memory_end: Option<usize>) {
     unsafe {
-        ALLOCATED_PAGE_HEAP_ALLOCATOR = HEAP_SIZE / PAGE_SIZE;
+        let heap_start = &raw const _heap_start as usize;
+        let memory_end = memory_end.unwrap_or(heap_start + DEFAULT_HEAP_SIZE);
+        assert!(memory_end > heap_start, "No memory left after the kernel");
+
+        // Each page costs its own size plus one descriptor
+        let mut number_pages = (memory_end - heap_start) / (PAGE_SIZE + size_of::<Page>());
+        while page_align_round_up(heap_start + number_pages * size_of::<Page>())
+            + number_pages * PAGE_SIZE
+            > memory_end
+        {
+            number_pages -= 1;
+        }
 
-        let pointer = (&raw const _heap_start as usize) as *mut Page;
+        ALLOCATED_PAGE_HEAP_ALLOCATOR = number_pages;
+        FREE_PAGES = number_pages;
 
         // Reserve some place for the page allocator
-        ALLOC_START = page_align_round_up(
-            (&raw const _heap_start as usize) + ALLOCATED_PAGE_HEAP_ALLOCATOR * size_of::<Page>(),
-        );
+        ALLOC_START =
+            page_align_round_up(heap_start + ALLOCATED_PAGE_HEAP_ALLOCATOR * size_of::<Page>());
 
         // Clear pages for security reason
         for i in 0..ALLOCATED_PAGE_HEAP_ALLOCATOR {
-            (*pointer.add(i)).clear_all_flags();
+            (*descriptor(i)).clear_all_flags();
+        }
+
+        for order in 0..=MAX_ORDER {
+            FREE_LISTS[order] = null_mut();
+        }
+
+        // Cut the region into the largest naturally aligned blocks. We walk it backwards
+        // so that the lowest addresses end up at the head of the free lists.
+        let mut end = ALLOCATED_PAGE_HEAP_ALLOCATOR;
+        while end > 0 {
+            let order = (end.trailing_zeros() as usize).min(MAX_ORDER);
+            end -= 1 << order;
+            push_block(end, order);
         }
     }
 }
@@ -72,44 +172,46 @@ pub fn alloc(pages: usize) -> *mut u8 {
     // Safety assertion
     assert!(pages > 0);
 
-    unsafe {
-        let pointer = (&raw const _heap_start as usize) as *mut Page;
-        let number_pages: usize = HEAP_SIZE / PAGE_SIZE;
+    let order = order_for(pages);
+    if order > MAX_ORDER {
+        return null_mut();
+    }
 
-        for i in 0..number_pages {
-            if (*pointer.add(i)).free() {
-                let mut good = true;
+    unsafe {
+        // Find the smallest free block that is big enough
+        let mut current_order = order;
+        while current_order <= MAX_ORDER && FREE_LISTS[current_order].is_null() {
+            current_order += 1;
+        }
 
-                for j in i..i + pages {
-                    if (*pointer.add(j)).taken() {
-                        good = false;
-                        break;
-                    }
-                }
+        if current_order > MAX_ORDER {
+            // Failure
+            return null_mut();
+        }
 
-                if good {
-                    // Set all pages allocated
-                    for j in i..i + pages {
-                        (*pointer.add(j)).set_flag(TAKEN_FLAG);
-                    }
+        let idx = block_index(FREE_LISTS[current_order]);
+        remove_block(idx, current_order);
 
-                    (*pointer.add(i + pages - 1)).set_flag(LAST_FLAG);
+        // Split the block until it has the requested size, giving back the upper halves
+        while current_order > order {
+            current_order -= 1;
+            push_block(idx + (1 << current_order), current_order);
+        }
 
-                    let raw_pointer = (ALLOC_START + PAGE_SIZE * i) as *mut u64;
+        let page = descriptor(idx);
+        (*page).set_flag(TAKEN_FLAG);
+        (*page).set_order(order);
+        FREE_PAGES -= 1 << order;
 
-                    // Clear pages for security reasons
-                    for offset in 0..PAGE_SIZE * pages / 8 {
-                        (*raw_pointer.add(offset)) = 0;
-                    }
+        let raw_pointer = (ALLOC_START + PAGE_SIZE * idx) as *mut u64;
 
-                    return raw_pointer as *mut u8;
-                }
-            }
+        // Clear pages for security reasons
+        for offset in 0..PAGE_SIZE * pages / 8 {
+            (*raw_pointer.add(offset)) = 0;
         }
-    }
 
-    // Failure
-    null_mut()
+        raw_pointer as *mut u8
+    }
 }
 
 pub fn dealloc(pointer: *mut u8) {
@@ -117,34 +219,65 @@ pub fn dealloc(pointer: *mut u8) {
     assert!(!pointer.is_null());
 
     unsafe {
-        // Convert pointer to page address
-        let page_structure_address =
-            (&raw const _heap_start as usize) + (pointer as usize - ALLOC_START) / PAGE_SIZE;
-
         // Safety assertion
         assert!(
-            (&raw const _heap_start as usize) <= page_structure_address
-                && page_structure_address < (&raw const _heap_start as usize) + HEAP_SIZE
+            ALLOC_START <= pointer as usize
+                && (pointer as usize) < ALLOC_START + ALLOCATED_PAGE_HEAP_ALLOCATOR * PAGE_SIZE
+        );
+        assert!(
+            (pointer as usize - ALLOC_START) % PAGE_SIZE == 0,
+            "Pointer is not page aligned"
         );
 
-        let mut page_pointer = page_structure_address as *mut Page;
-
-        while (*page_pointer).taken() && !(*page_pointer).last() {
-            // Clear page pointer
-            (*page_pointer).clear_all_flags();
-            // Move to the next one
-            page_pointer = page_pointer.add(1);
-        }
+        // Convert pointer to page index
+        let mut idx = (pointer as usize - ALLOC_START) / PAGE_SIZE;
 
         // Check for double free
-        assert!((*page_pointer).last() == true, "Possible double free here");
+        assert!((*descriptor(idx)).taken(), "Possible double free here");
+
+        let mut order = (*descriptor(idx)).order();
+        (*descriptor(idx)).clear_all_flags();
+        FREE_PAGES += 1 << order;
 
-        // Clear the last page
-        (*page_pointer).clear_all_flags();
+        // Merge with the buddy as long as it is free and has the same size
+        while order < MAX_ORDER {
+            let buddy = idx ^ (1 << order);
+            if buddy + (1 << order) > ALLOCATED_PAGE_HEAP_ALLOCATOR {
+                break;
+            }
+
+            let buddy_page = descriptor(buddy);
+            if !(*buddy_page).head() || (*buddy_page).order() != order {
+                break;
+            }
+
+            remove_block(buddy, order);
+            idx = idx.min(buddy);
+            order += 1;
+        }
+
+        push_block(idx, order);
     }
 }
 
+// Number of pages managed by the allocator
+// End of the memory handed out by the allocator
+pub fn memory_end() -> usize {
+    unsafe { ALLOC_START + ALLOCATED_PAGE_HEAP_ALLOCATOR * PAGE_SIZE }
+}
+
+pub fn total_pages() -> usize {
+    unsafe { ALLOCATED_PAGE_HEAP_ALLOCATOR }
+}
+
+// Number of pages not handed out yet
+pub fn free_pages() -> usize {
+    unsafe { FREE_PAGES }
+}
+
 pub fn init_sanity_check() {
+    let free_before = free_pages();
+
     // Check we allocate in the correct zone
     let first_alloc = alloc(1);
     assert!(first_alloc > 0x80000000 as *mut u8);
@@ -164,6 +297,20 @@ pub fn init_sanity_check() {
     let last_alloc = alloc(1);
     assert!(first_alloc == last_alloc);
 
+    // Two single pages must come from the same split block and merge back together
+    let buddy_alloc = alloc(1);
+    assert!(buddy_alloc as usize == last_alloc as usize + PAGE_SIZE);
+    dealloc(last_alloc);
+    dealloc(buddy_alloc);
+    let merged_alloc = alloc(2);
+    assert!(first_alloc == merged_alloc);
+    dealloc(merged_alloc);
+
+    // Requests above the largest order must fail
+    assert!(alloc((1 << MAX_ORDER) + 1).is_null());
+
     // Free all the memory for the operating systems
     dealloc(third_alloc);
+    assert_eq!(free_before, free_pages(), "Page accounting is broken");
+    assert!(free_pages() <= total_pages());
 }
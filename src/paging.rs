@@ -1,5 +1,8 @@
 use crate::page_allocator;
+use crate::platform;
+use crate::plic;
 use crate::uart;
+use core::arch::asm;
 use core::ptr::null_mut;
 
 #[repr(i64)]
@@ -22,6 +25,10 @@ pub enum EntryBits {
     UserReadWrite = 1 << 1 | 1 << 2 | 1 << 4,
     UserReadExecute = 1 << 1 | 1 << 3 | 1 << 4,
     UserReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4,
+
+    // Reserved for software: the page was allocated for the address space and
+    // is freed with it
+    Owned = 1 << 8,
 }
 
 impl EntryBits {
@@ -84,6 +91,20 @@ pub fn get_virtual_offsets(virtual_address: usize) -> [usize; 3] {
 }
 
 pub fn map(virtual_address: usize, physical_address: usize, bits: i64) {
+    unsafe { map_in(ROOT, virtual_address, physical_address, bits) }
+}
+
+// Map a page in the address space of root
+//
+// # Safety
+// root must point to a valid page table, and the caller must own the mapping
+// it replaces.
+pub unsafe fn map_in(
+    root: *mut PageTable,
+    virtual_address: usize,
+    physical_address: usize,
+    bits: i64,
+) {
     // Safety assertion
     assert!(bits & 0xe != 0);
 
@@ -96,12 +117,13 @@ pub fn map(virtual_address: usize, physical_address: usize, bits: i64) {
     ];
 
     unsafe {
-        let mut current = &mut (*ROOT).entries[virtual_offsets[0]];
+        let mut current = &mut (*root).entries[virtual_offsets[0]];
 
         for i in 1..=2 {
             if current.is_invalid() {
                 // Create page
                 let page = page_allocator::alloc(1);
+                assert!(!page.is_null(), "Cannot allocate a page table");
                 // Binds page
                 current.set_entry((page as i64 >> 2) | EntryBits::Valid.val());
             }
@@ -125,10 +147,18 @@ pub fn map(virtual_address: usize, physical_address: usize, bits: i64) {
 }
 
 pub fn virtual_to_physical(virtual_address: usize) -> Option<usize> {
+    unsafe { translate(ROOT, virtual_address) }
+}
+
+// Physical address of a virtual address in the address space of root
+//
+// # Safety
+// root must point to a valid page table.
+pub unsafe fn translate(root: *const PageTable, virtual_address: usize) -> Option<usize> {
     let virtual_offsets = get_virtual_offsets(virtual_address);
 
     unsafe {
-        let mut current = &(*ROOT).entries[virtual_offsets[0]];
+        let mut current = &(*root).entries[virtual_offsets[0]];
 
         for i in 0..=2 {
             if current.is_invalid() {
@@ -151,6 +181,28 @@ pub fn virtual_to_physical(virtual_address: usize) -> Option<usize> {
     }
 }
 
+// Physical address of a page mapped for user mode, readable and writable
+// when write is set. Kernel mappings are not visible to user processes.
+//
+// # Safety
+// root must point to a valid page table.
+pub unsafe fn translate_user(
+    root: *const PageTable,
+    virtual_address: usize,
+    write: bool,
+) -> Option<usize> {
+    let mut bits = EntryBits::User.val() | EntryBits::Read.val();
+    if write {
+        bits |= EntryBits::Write.val();
+    }
+
+    let entry = leaf_entry(root, virtual_address)?;
+    if entry & bits != bits {
+        return None;
+    }
+    translate(root, virtual_address)
+}
+
 pub const fn page_align_round_down(val: usize) -> usize {
     let o = 4096 - 1;
     val & !o
@@ -171,6 +223,133 @@ pub fn identity_map_range(start: usize, end: usize) {
     }
 }
 
+// Leaf entry of a virtual address in the address space of root
+unsafe fn leaf_entry(root: *const PageTable, virtual_address: usize) -> Option<i64> {
+    let virtual_offsets = get_virtual_offsets(virtual_address);
+    let mut current = &(*root).entries[virtual_offsets[0]];
+
+    for i in 0..=2 {
+        if current.is_invalid() {
+            return None;
+        }
+        if current.is_leaf() {
+            return Some(current.get_entry());
+        }
+        if i == 2 {
+            return None;
+        }
+
+        let current_entry = get_address_from_entry(current) as *const PageTableEntry;
+        current = current_entry.add(virtual_offsets[i + 1]).as_ref().unwrap()
+    }
+    None
+}
+
+// Address space of a user process. The kernel mappings are shared with ROOT
+// through its top level entries, user pages must be mapped outside of them.
+// Null when out of memory.
+pub fn new_address_space() -> *mut PageTable {
+    let root = page_allocator::alloc(1) as *mut PageTable;
+    if root.is_null() {
+        return root;
+    }
+
+    unsafe {
+        (*root).entries = (*ROOT).entries;
+    }
+    root
+}
+
+// True when the top level entry for this address comes from the kernel table
+//
+// # Safety
+// root must point to a valid page table.
+pub unsafe fn is_kernel_range(root: *const PageTable, virtual_address: usize) -> bool {
+    let index = get_virtual_offsets(virtual_address)[0];
+    let entry = (*root).entries[index];
+    entry.is_valid() && entry.get_entry() == (*ROOT).entries[index].get_entry()
+}
+
+// Free a table built by new_address_space, with the tables below it and the
+// pages marked as owned. Shared kernel tables are left alone.
+//
+// # Safety
+// root must come from new_address_space, must not be in use by any hart and
+// must not be used afterwards.
+pub unsafe fn free_address_space(root: *mut PageTable) {
+    for index in 0..PageTable::len() {
+        let entry = (*root).entries[index];
+        if entry.is_invalid() || entry.get_entry() == (*ROOT).entries[index].get_entry() {
+            continue;
+        }
+        free_entry(&entry, 0);
+    }
+    page_allocator::dealloc(root as *mut u8);
+}
+
+unsafe fn free_entry(entry: &PageTableEntry, level: usize) {
+    let address = get_address_from_entry(entry) as *mut u8;
+    if entry.is_leaf() {
+        if entry.get_entry() & EntryBits::Owned.val() != 0 {
+            page_allocator::dealloc(address);
+        }
+        return;
+    }
+
+    let table = address as *mut PageTable;
+    if level < 2 {
+        for child in (*table).entries.iter().filter(|child| child.is_valid()) {
+            free_entry(child, level + 1);
+        }
+    }
+    page_allocator::dealloc(address);
+}
+
+// Pages allocated for an address space, tables included
+//
+// # Safety
+// root must point to a valid page table.
+pub unsafe fn owned_pages(root: *const PageTable) -> usize {
+    fn count(entry: &PageTableEntry, level: usize) -> usize {
+        if entry.is_leaf() {
+            return (entry.get_entry() & EntryBits::Owned.val() != 0) as usize;
+        }
+        let table = get_address_from_entry(entry) as *const PageTable;
+        let mut pages = 1;
+        if level < 2 {
+            unsafe {
+                for child in (*table).entries.iter().filter(|child| child.is_valid()) {
+                    pages += count(child, level + 1);
+                }
+            }
+        }
+        pages
+    }
+
+    let mut pages = 1;
+    for index in 0..PageTable::len() {
+        let entry = (*root).entries[index];
+        if entry.is_valid() && entry.get_entry() != (*ROOT).entries[index].get_entry() {
+            pages += count(&entry, 0);
+        }
+    }
+    pages
+}
+
+// Install an address space, null selects the kernel one
+pub fn activate(root: *const PageTable) {
+    let root = if root.is_null() {
+        unsafe { ROOT as *const PageTable }
+    } else {
+        root
+    };
+    let satp = craft_satp(8, 0, root as usize);
+    unsafe {
+        asm!("csrw satp, {}", in(reg) satp);
+        asm!("sfence.vma");
+    }
+}
+
 /// Build satp value from mode, asid and page table base addr
 pub fn craft_satp(mode: usize, asid: usize, addr: usize) -> usize {
     if addr % 4096 != 0 {
@@ -226,10 +405,11 @@ pub fn init() {
         // Bss section
         identity_map_range(&raw const _bss_start as usize, &raw const _bss_end as usize);
 
-        // Map memory used for page allocation
+        // Map the page descriptors and the pages handed out by the allocator,
+        // the kernel runs with translation on in supervisor mode
         identity_map_range(
             &raw const _heap_start as usize,
-            &raw const _heap_start as usize + page_allocator::ALLOCATED_PAGE_HEAP_ALLOCATOR,
+            page_allocator::memory_end(),
         );
 
         // Map uart driver
@@ -238,6 +418,23 @@ pub fn init() {
             uart::UART_BASE_ADDRESS + page_allocator::PAGE_SIZE,
         );
     }
+
+    let platform = platform::get();
+    identity_map_range(platform.plic, platform.plic + plic::PLIC_SIZE);
+    for slot in platform.virtio_slots() {
+        identity_map_range(slot.address, slot.address + page_allocator::PAGE_SIZE);
+    }
+    if let Some((start, end)) = platform.initrd {
+        identity_map_range(start, end);
+    }
+    if let Some(fdt) = platform.device_tree {
+        identity_map_range(fdt.address(), fdt.address() + fdt.total_size());
+    }
+}
+
+// Forget stale translations after changing the kernel table
+pub fn flush() {
+    unsafe { asm!("sfence.vma") };
 }
 
 pub fn init_sanity_check() {
@@ -277,4 +474,38 @@ pub fn init_sanity_check() {
             "Identity mapping is broken for uart driver"
         );
     }
+
+    // User address spaces share the kernel mappings and free what they own
+    let free_before = page_allocator::free_pages();
+    let user_address = 0x10_0000_0000;
+    let root = new_address_space();
+    assert!(!root.is_null(), "Cannot create an address space");
+    unsafe {
+        assert!(is_kernel_range(root, &raw const _text_start as usize));
+        assert!(!is_kernel_range(root, user_address));
+        assert_eq!(
+            translate(root, &raw const _text_start as usize),
+            virtual_to_physical(&raw const _text_start as usize)
+        );
+
+        let page = page_allocator::alloc(1);
+        map_in(
+            root,
+            user_address,
+            page as usize,
+            EntryBits::UserReadWrite.val() | EntryBits::Owned.val(),
+        );
+        assert_eq!(translate(root, user_address + 8), Some(page as usize + 8));
+        assert_eq!(virtual_to_physical(user_address), None);
+        assert!(leaf_entry(root, user_address).unwrap() & EntryBits::User.val() != 0);
+        // The user page, two tables below the root and the root
+        assert_eq!(owned_pages(root), 4);
+
+        free_address_space(root);
+    }
+    assert_eq!(
+        page_allocator::free_pages(),
+        free_before,
+        "Address space leaked pages"
+    );
 }
@@ -0,0 +1,474 @@
+// Partition tables. Every disk in the block registry is scanned for an MBR or
+// a GPT, each partition found is registered as a block device of its own
+// ("vda1", "vda2", ...) covering a window of the underlying disk.
+
+use crate::block::{self, BlockDevice, BlockError};
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const MAX_PARTITIONS: usize = 32;
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_ENTRY_COUNT: usize = 4;
+// Boot indicator of an entry, anything else means the sector is not an MBR
+const MBR_STATUS_INACTIVE: u8 = 0x00;
+const MBR_STATUS_ACTIVE: u8 = 0x80;
+
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+// Logical partitions are not supported, extended containers are ignored
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+// Refuse partition arrays bigger than this, the spec minimum is 16KiB
+const GPT_MAX_ENTRIES_SIZE: usize = 1 << 20;
+
+pub struct Partition {
+    disk: Option<&'static dyn BlockDevice>,
+    // Registry id of the disk
+    disk_id: usize,
+    name: [u8; 16],
+    name_len: usize,
+    // First sector and sector count on the disk
+    start: u64,
+    length: u64,
+}
+
+impl Partition {
+    const fn empty() -> Self {
+        Partition {
+            disk: None,
+            disk_id: 0,
+            name: [0; 16],
+            name_len: 0,
+            start: 0,
+            length: 0,
+        }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    fn disk(&self) -> &'static dyn BlockDevice {
+        self.disk.expect("Partition without a disk")
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk().sector_size()
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.length
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk().is_read_only()
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buf.len())?;
+        self.disk().read(self.start + sector, buf)
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buf.len())?;
+        self.disk().write(self.start + sector, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk().flush()
+    }
+
+    fn window(&self) -> Option<(usize, u64)> {
+        Some((self.disk_id, self.start))
+    }
+}
+
+const EMPTY_PARTITION: Partition = Partition::empty();
+
+static mut PARTITIONS: [Partition; MAX_PARTITIONS] = [EMPTY_PARTITION; MAX_PARTITIONS];
+static mut PARTITION_COUNT: usize = 0;
+
+pub fn partitions() -> &'static [Partition] {
+    unsafe {
+        let partitions = &*(&raw const PARTITIONS);
+        &partitions[..PARTITION_COUNT]
+    }
+}
+
+// CRC32 as used by GPT (IEEE 802.3, reflected)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// A partition found in a table, before it gets registered
+struct Entry {
+    // Number of the slot in the table, from 1
+    number: usize,
+    start: u64,
+    length: u64,
+    // Only set for MBR entries, GPT entries are all usable partitions
+    mbr_type: u8,
+}
+
+// Boot sector of a FAT or NTFS volume spanning the whole disk. It carries the
+// 0x55aa signature too, its boot code would be read as partition entries.
+fn is_volume_boot_record(sector: &[u8]) -> bool {
+    let jump = (sector[0] == 0xeb && sector[2] == 0x90) || sector[0] == 0xe9;
+    if !jump {
+        return false;
+    }
+
+    // File system type of FAT12/16 and FAT32, OEM name of NTFS
+    if &sector[54..57] == b"FAT" || &sector[82..87] == b"FAT32" || &sector[3..11] == b"NTFS    " {
+        return true;
+    }
+
+    // Old FAT volumes may not name themselves, their BPB still makes sense
+    let bytes_per_sector = le_u16(sector, 11);
+    let sectors_per_cluster = sector[13];
+    let fats = sector[16];
+    let media = sector[21];
+    [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+        && sectors_per_cluster.is_power_of_two()
+        && le_u16(sector, 14) != 0
+        && (fats == 1 || fats == 2)
+        && (media == 0xf0 || media >= 0xf8)
+}
+
+fn parse_mbr(sector: &[u8]) -> Option<Vec<Entry>> {
+    if le_u16(sector, MBR_SIGNATURE_OFFSET) != MBR_SIGNATURE || is_volume_boot_record(sector) {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for i in 0..MBR_ENTRY_COUNT {
+        let entry = &sector[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        // Like Linux, one bad boot indicator and the whole table is refused
+        if entry[0] != MBR_STATUS_INACTIVE && entry[0] != MBR_STATUS_ACTIVE {
+            return None;
+        }
+
+        let kind = entry[4];
+        let start = le_u32(entry, 8) as u64;
+        let length = le_u32(entry, 12) as u64;
+
+        if kind == MBR_TYPE_EMPTY || MBR_TYPE_EXTENDED.contains(&kind) || length == 0 {
+            continue;
+        }
+
+        entries.push(Entry {
+            number: i + 1,
+            start,
+            length,
+            mbr_type: kind,
+        });
+    }
+
+    Some(entries)
+}
+
+fn is_protective_mbr(entries: &[Entry]) -> bool {
+    entries
+        .iter()
+        .any(|entry| entry.mbr_type == MBR_TYPE_GPT_PROTECTIVE)
+}
+
+// Where a GPT header says its partition array is
+struct GptHeader {
+    entries_lba: u64,
+    entry_count: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+// Check a header read from sector lba, it must say it lives there
+fn parse_gpt_header(header: &mut [u8], lba: u64) -> Result<GptHeader, BlockError> {
+    let header_size = le_u32(header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE
+        || header_size < GPT_HEADER_MIN_SIZE
+        || header_size > header.len()
+        || le_u64(header, 24) != lba
+    {
+        return Err(BlockError::InvalidBuffer);
+    }
+
+    // The checksum is computed with its own field zeroed
+    let expected = le_u32(header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != expected {
+        return Err(BlockError::IoError);
+    }
+
+    let parsed = GptHeader {
+        entries_lba: le_u64(header, 72),
+        entry_count: le_u32(header, 80) as usize,
+        entry_size: le_u32(header, 84) as usize,
+        entries_crc: le_u32(header, 88),
+    };
+    if parsed.entry_size < GPT_ENTRY_MIN_SIZE
+        || parsed.entry_count.saturating_mul(parsed.entry_size) > GPT_MAX_ENTRIES_SIZE
+    {
+        return Err(BlockError::InvalidBuffer);
+    }
+    Ok(parsed)
+}
+
+// The primary header is in sector 1, the backup in the last sector of the disk
+fn parse_gpt(disk: &dyn BlockDevice) -> Result<Vec<Entry>, BlockError> {
+    let backup = disk.num_sectors() - 1;
+    parse_gpt_at(disk, 1).or_else(|_| parse_gpt_at(disk, backup))
+}
+
+fn parse_gpt_at(disk: &dyn BlockDevice, lba: u64) -> Result<Vec<Entry>, BlockError> {
+    let sector_size = disk.sector_size();
+    let mut header = vec![0u8; sector_size];
+    disk.read(lba, &mut header)?;
+    let GptHeader {
+        entries_lba,
+        entry_count,
+        entry_size,
+        entries_crc,
+    } = parse_gpt_header(&mut header, lba)?;
+
+    let entries_size = entry_count * entry_size;
+    let mut array = vec![0u8; entries_size.div_ceil(sector_size) * sector_size];
+    disk.read(entries_lba, &mut array)?;
+    if crc32(&array[..entries_size]) != entries_crc {
+        return Err(BlockError::IoError);
+    }
+
+    let mut entries = Vec::new();
+    for (index, raw) in array[..entries_size].chunks(entry_size).enumerate() {
+        // An all zero type GUID marks an unused entry
+        if raw[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        // The last LBA is inclusive
+        let first = le_u64(raw, 32);
+        let last = le_u64(raw, 40);
+        if last < first {
+            continue;
+        }
+
+        entries.push(Entry {
+            number: index + 1,
+            start: first,
+            length: last - first + 1,
+            mbr_type: MBR_TYPE_EMPTY,
+        });
+    }
+
+    Ok(entries)
+}
+
+// Read the partition table of the disk, None when there is none we understand
+fn read_table(disk: &dyn BlockDevice) -> Option<Vec<Entry>> {
+    if disk.num_sectors() == 0 || disk.sector_size() < 512 {
+        return None;
+    }
+
+    let mut first = vec![0u8; disk.sector_size()];
+    disk.read(0, &mut first).ok()?;
+    let mbr = parse_mbr(&first)?;
+
+    if is_protective_mbr(&mbr) {
+        return parse_gpt(disk).ok();
+    }
+    Some(mbr)
+}
+
+fn register(disk_id: usize, entry: &Entry) {
+    let disk = block::get(disk_id).unwrap();
+    unsafe {
+        if PARTITION_COUNT == MAX_PARTITIONS {
+            return;
+        }
+
+        // Partitions must stay inside the disk
+        if entry
+            .start
+            .checked_add(entry.length)
+            .map_or(true, |end| end > disk.num_sectors())
+        {
+            return;
+        }
+
+        let partition = &mut (*(&raw mut PARTITIONS))[PARTITION_COUNT];
+        let mut name = [0u8; 16];
+        let mut writer = NameWriter {
+            name: &mut name,
+            len: 0,
+        };
+        let _ = core::fmt::Write::write_fmt(
+            &mut writer,
+            format_args!("{}{}", disk.name(), entry.number),
+        );
+        let name_len = writer.len;
+
+        partition.disk = Some(disk);
+        partition.disk_id = disk_id;
+        partition.name = name;
+        partition.name_len = name_len;
+        partition.start = entry.start;
+        partition.length = entry.length;
+
+        PARTITION_COUNT += 1;
+        block::register(&(*(&raw const PARTITIONS))[PARTITION_COUNT - 1]);
+    }
+}
+
+struct NameWriter<'a> {
+    name: &'a mut [u8; 16],
+    len: usize,
+}
+
+impl core::fmt::Write for NameWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        if self.len + bytes.len() > self.name.len() {
+            return Err(core::fmt::Error);
+        }
+        self.name[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+// Scan the disks registered so far, partitions found are registered after them
+pub fn init() {
+    let disks = block::count();
+    for id in 0..disks {
+        let disk = block::get(id).unwrap();
+        if let Some(entries) = read_table(disk) {
+            for entry in entries.iter() {
+                register(id, entry);
+            }
+        }
+    }
+}
+
+pub fn init_sanity_check() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926, "Wrong crc32");
+
+    // A sector without the 0x55aa signature is not an MBR
+    let mut sector = [0u8; 512];
+    assert!(parse_mbr(&sector).is_none());
+
+    sector[MBR_SIGNATURE_OFFSET] = 0x55;
+    sector[MBR_SIGNATURE_OFFSET + 1] = 0xaa;
+    sector[MBR_ENTRIES_OFFSET + 4] = MBR_TYPE_GPT_PROTECTIVE;
+    sector[MBR_ENTRIES_OFFSET + 8] = 1;
+    sector[MBR_ENTRIES_OFFSET + 12] = 0xff;
+    let entries = parse_mbr(&sector).expect("Valid MBR rejected");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].start, 1);
+    assert_eq!(entries[0].length, 0xff);
+    assert!(is_protective_mbr(&entries));
+
+    // Partitions keep the number of their slot
+    sector[MBR_ENTRIES_OFFSET + 4] = MBR_TYPE_EMPTY;
+    sector[MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE + 4] = 0x0c;
+    sector[MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE + 12] = 0x10;
+    let entries = parse_mbr(&sector).expect("Valid MBR rejected");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].number, 2);
+
+    // Only 0x00 and 0x80 are boot indicators
+    sector[MBR_ENTRIES_OFFSET] = MBR_STATUS_ACTIVE;
+    assert!(parse_mbr(&sector).is_some());
+    sector[MBR_ENTRIES_OFFSET] = 0x29;
+    assert!(parse_mbr(&sector).is_none());
+    sector[MBR_ENTRIES_OFFSET] = MBR_STATUS_INACTIVE;
+
+    // A FAT volume without a partition table is no MBR, whatever its boot
+    // code looks like
+    let mut boot_sector = sector;
+    boot_sector[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot_sector[82..90].copy_from_slice(b"FAT32   ");
+    assert!(parse_mbr(&boot_sector).is_none());
+    boot_sector[82..90].fill(0);
+    boot_sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot_sector[13] = 8;
+    boot_sector[14] = 32;
+    boot_sector[16] = 2;
+    boot_sector[21] = 0xf8;
+    assert!(parse_mbr(&boot_sector).is_none());
+
+    // A GPT header must be found where it says it is
+    let mut header = [0u8; 512];
+    header[..8].copy_from_slice(GPT_SIGNATURE);
+    header[12..16].copy_from_slice(&(GPT_HEADER_MIN_SIZE as u32).to_le_bytes());
+    header[24..32].copy_from_slice(&1u64.to_le_bytes());
+    header[84..88].copy_from_slice(&(GPT_ENTRY_MIN_SIZE as u32).to_le_bytes());
+    let crc = crc32(&header[..GPT_HEADER_MIN_SIZE]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    assert!(parse_gpt_header(&mut header.clone(), 1).is_ok());
+    assert_eq!(
+        parse_gpt_header(&mut header.clone(), 2).err(),
+        Some(BlockError::InvalidBuffer)
+    );
+    // Any change breaks the checksum
+    header[40] ^= 1;
+    assert_eq!(
+        parse_gpt_header(&mut header, 1).err(),
+        Some(BlockError::IoError)
+    );
+
+    // Partitions only see their own window of the disk
+    for partition in partitions() {
+        let mut buffer = vec![0u8; partition.sector_size()];
+        let mut on_disk = vec![0u8; partition.sector_size()];
+        partition
+            .read(0, &mut buffer)
+            .expect("Cannot read the first sector of a partition");
+        partition
+            .disk()
+            .read(partition.start(), &mut on_disk)
+            .expect("Cannot read the disk");
+        assert!(buffer == on_disk, "Partition is not mapped at its offset");
+        assert_eq!(
+            partition.read(partition.num_sectors(), &mut buffer),
+            Err(BlockError::OutOfRange)
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::_heap_start;
+use crate::fdt::Fdt;
+use crate::paging;
+
+pub const MAX_VIRTIO_SLOTS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub struct VirtioSlot {
+    pub address: usize,
+    pub irq: u32,
+}
+
+// Where the devices we drive live. Filled from the device tree at boot.
+pub struct Platform {
+    pub device_tree: Option<Fdt>,
+    pub memory: Option<(usize, usize)>,
+    // Frequency of mtime in Hz
+    pub timebase: u64,
+    // Start and end of the initrd loaded by QEMU (-initrd)
+    pub initrd: Option<(usize, usize)>,
+    pub uart: usize,
+    pub uart_irq: u32,
+    pub plic: usize,
+    pub clint: usize,
+    // Test device of QEMU, powers off or resets the machine
+    pub test: usize,
+    pub harts: usize,
+    virtio: [VirtioSlot; MAX_VIRTIO_SLOTS],
+    virtio_count: usize,
+}
+
+impl Platform {
+    // Layout of QEMU's virt machine, used when no device tree is available
+    pub const fn qemu_virt() -> Self {
+        let mut virtio = [VirtioSlot { address: 0, irq: 0 }; MAX_VIRTIO_SLOTS];
+        let mut i = 0;
+        while i < MAX_VIRTIO_SLOTS {
+            virtio[i] = VirtioSlot {
+                address: 0x1000_1000 + i * 0x1000,
+                irq: 1 + i as u32,
+            };
+            i += 1;
+        }
+
+        Platform {
+            device_tree: None,
+            memory: None,
+            timebase: 10_000_000,
+            initrd: None,
+            uart: 0x1000_0000,
+            uart_irq: 10,
+            plic: 0x0c00_0000,
+            clint: 0x0200_0000,
+            test: 0x0010_0000,
+            harts: 1,
+            virtio,
+            virtio_count: MAX_VIRTIO_SLOTS,
+        }
+    }
+
+    pub fn virtio_slots(&self) -> &[VirtioSlot] {
+        &self.virtio[..self.virtio_count]
+    }
+
+    fn discover(&mut self, fdt: Fdt) {
+        self.device_tree = Some(fdt);
+        self.memory = fdt.memory();
+
+        let timebase = fdt
+            .find_node("/cpus")
+            .and_then(|cpus| cpus.property("timebase-frequency"))
+            .and_then(|p| p.as_u64());
+        if let Some(timebase) = timebase.filter(|t| *t > 0) {
+            self.timebase = timebase;
+        }
+
+        if let Some(chosen) = fdt.find_node("/chosen") {
+            let start = chosen
+                .property("linux,initrd-start")
+                .and_then(|p| p.as_u64());
+            let end = chosen.property("linux,initrd-end").and_then(|p| p.as_u64());
+            if let (Some(start), Some(end)) = (start, end) {
+                if start < end {
+                    self.initrd = Some((start as usize, end as usize));
+                }
+            }
+        }
+
+        if let Some(uart) = fdt.find_compatible("ns16550a") {
+            if let Some((address, _)) = uart.reg().next() {
+                self.uart = address;
+            }
+            if let Some(irq) = uart.interrupts().next() {
+                self.uart_irq = irq;
+            }
+        }
+
+        let plic = fdt
+            .find_compatible("riscv,plic0")
+            .or_else(|| fdt.find_compatible("sifive,plic-1.0.0"));
+        if let Some((address, _)) = plic.and_then(|node| node.reg().next()) {
+            self.plic = address;
+        }
+
+        let clint = fdt
+            .find_compatible("riscv,clint0")
+            .or_else(|| fdt.find_compatible("sifive,clint0"));
+        if let Some((address, _)) = clint.and_then(|node| node.reg().next()) {
+            self.clint = address;
+        }
+
+        if let Some((address, _)) = fdt
+            .find_compatible("sifive,test0")
+            .and_then(|node| node.reg().next())
+        {
+            self.test = address;
+        }
+
+        let mut harts = 0;
+        fdt.for_each_compatible("riscv", |_| harts += 1);
+        if harts > 0 {
+            self.harts = harts;
+        }
+
+        let mut count = 0;
+        fdt.for_each_compatible("virtio,mmio", |node| {
+            if count == MAX_VIRTIO_SLOTS {
+                return;
+            }
+            if let Some((address, _)) = node.reg().next() {
+                self.virtio[count] = VirtioSlot {
+                    address,
+                    irq: node.interrupts().next().unwrap_or(0),
+                };
+                count += 1;
+            }
+        });
+
+        if count > 0 {
+            self.virtio_count = count;
+            // QEMU lists the slots from the last one, keep them in address order
+            self.virtio[..count].sort_unstable_by_key(|slot| slot.address);
+        }
+    }
+}
+
+static mut PLATFORM: Platform = Platform::qemu_virt();
+
+// Platform described by the device tree, QEMU's virt layout without one
+pub fn probe(dtb: usize) -> Platform {
+    let mut platform = Platform::qemu_virt();
+    if let Some(fdt) = Fdt::from_address(dtb) {
+        platform.discover(fdt);
+    }
+    platform
+}
+
+pub fn init(dtb: usize) {
+    unsafe { *(&raw mut PLATFORM) = probe(dtb) }
+}
+
+pub fn get() -> &'static Platform {
+    unsafe { &*(&raw const PLATFORM) }
+}
+
+// End of the memory we can hand to the page allocator. The device tree is
+// placed at the top of the memory by QEMU, so we stop right below it, and
+// below the initrd when there is one.
+pub fn memory_end() -> Option<usize> {
+    let platform = get();
+    let (start, size) = platform.memory?;
+    let mut end = start + size;
+
+    if let Some(fdt) = platform.device_tree {
+        let heap_start = &raw const _heap_start as usize;
+        if heap_start <= fdt.address() && fdt.address() < end {
+            end = paging::page_align_round_down(fdt.address());
+        }
+    }
+
+    if let Some((initrd_start, _)) = platform.initrd {
+        let heap_start = &raw const _heap_start as usize;
+        if heap_start <= initrd_start && initrd_start < end {
+            end = paging::page_align_round_down(initrd_start);
+        }
+    }
+
+    Some(end)
+}
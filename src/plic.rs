@@ -1,29 +1,64 @@
-const PLIC_PRIORITY: usize = 0x0c00_0000;
-const _PLIC_PENDING: usize = 0x0c00_1000;
-const PLIT_INT_ENABLE_TABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
+use crate::{reg, uart};
 
-const UART_DEVICE: u32 = 10;
+const PLIC_PRIORITY_OFFSET: usize = 0x0;
+const _PLIC_PENDING_OFFSET: usize = 0x1000;
+const PLIT_INT_ENABLE_TABLE_OFFSET: usize = 0x2000;
+const PLIC_THRESHOLD_OFFSET: usize = 0x20_0000;
+const PLIC_CLAIM_OFFSET: usize = 0x20_0004;
+
+// Each context has its own enable table, threshold and claim registers
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+
+// Size of the register space, to map it
+pub const PLIC_SIZE: usize = 0x40_0000;
+
+static mut PLIC_BASE: usize = 0x0c00_0000;
+
+pub fn init(base_address: usize) {
+    unsafe {
+        PLIC_BASE = base_address;
+    }
 
-pub fn init() {
     set_threshold(0);
-    enable_device(UART_DEVICE);
-    set_priority(UART_DEVICE, 1);
+    enable_device(uart::irq());
+    set_priority(uart::irq(), 1);
 }
 
-pub fn init_sanity_check() {}
+pub fn init_sanity_check() {
+    assert!(is_enabled(uart::irq()), "The uart interrupt is not enabled");
+}
+
+fn register(offset: usize) -> *mut u32 {
+    unsafe { (PLIC_BASE + offset) as *mut u32 }
+}
+
+// QEMU's virt machine gives every hart a machine context followed by a
+// supervisor context, we run in the latter
+fn context() -> usize {
+    2 * reg::hartid() + 1
+}
+
+// The enable table has one bit per source, 32 sources per word
+fn enable_word(id: u32) -> *mut u32 {
+    let table = register(PLIT_INT_ENABLE_TABLE_OFFSET + PLIC_ENABLE_STRIDE * context());
+    unsafe { table.add(id as usize / 32) }
+}
 
 pub fn enable_device(id: u32) {
-    let plic_enable_mask = PLIT_INT_ENABLE_TABLE as *mut u32;
+    let plic_enable_mask = enable_word(id);
     unsafe {
-        plic_enable_mask.write_volatile(plic_enable_mask.read_volatile() | (1 << id));
+        plic_enable_mask.write_volatile(plic_enable_mask.read_volatile() | (1 << (id % 32)));
     }
 }
 
+pub fn is_enabled(id: u32) -> bool {
+    unsafe { enable_word(id).read_volatile() & (1 << (id % 32)) != 0 }
+}
+
 pub fn set_priority(id: u32, priority: u8) {
     let current_priority = priority as u32 & 0x7;
-    let priority_register = PLIC_PRIORITY as *mut u32;
+    let priority_register = register(PLIC_PRIORITY_OFFSET);
 
     unsafe {
         priority_register
@@ -34,14 +69,15 @@ pub fn set_priority(id: u32, priority: u8) {
 
 pub fn set_threshold(threshold: u8) {
     let actual_threshold = threshold & 0x7;
-    let threshold_register = PLIC_THRESHOLD as *mut u32;
+    let threshold_register = register(PLIC_THRESHOLD_OFFSET + PLIC_CONTEXT_STRIDE * context());
     unsafe {
         threshold_register.write_volatile(actual_threshold as u32);
     }
 }
 
 pub fn next_interrupt() -> Option<u32> {
-    let claim_register = PLIC_CLAIM as *const u32;
+    let claim_register =
+        register(PLIC_CLAIM_OFFSET + PLIC_CONTEXT_STRIDE * context()) as *const u32;
     let claim_number;
 
     unsafe {
@@ -56,7 +92,7 @@ pub fn next_interrupt() -> Option<u32> {
 }
 
 pub fn clear_interrupt(id: u32) {
-    let complete_register = PLIC_CLAIM as *mut u32;
+    let complete_register = register(PLIC_CLAIM_OFFSET + PLIC_CONTEXT_STRIDE * context());
     unsafe {
         complete_register.write_volatile(id);
     }
@@ -1,13 +1,97 @@
+use crate::paging::{self, EntryBits, PageTable};
+use crate::scheduler::Pid;
+use crate::syscall::{self, CHUNK_SIZE, ECHILD, EFAULT, SYS_EXIT, SYS_WAIT};
+use crate::vfs::FileTable;
+use crate::{page_allocator, reg, uart, user};
+use core::arch::asm;
+use core::fmt::Write;
 use core::ptr::null_mut;
 
-use crate::{page_allocator, println};
-use core::fmt::Write;
+const STACK_PAGES: usize = 10;
+const USER_STACK_PAGES: usize = 4;
 
+// Layout of user address spaces, away from the top level entries the kernel
+// uses so that they can be shared
+pub const USER_TEXT_BASE: usize = 0x10_0000_0000;
+pub const USER_HEAP_BASE: usize = 0x11_0000_0000;
+pub const USER_MMAP_BASE: usize = 0x18_0000_0000;
+pub const USER_MMAP_END: usize = 0x1f_0000_0000;
+pub const USER_STACK_TOP: usize = 0x20_0000_0000;
+
+extern "C" {
+    static _user_text_start: u8;
+    static _user_text_end: u8;
+}
+
+// Exit code of a killed process
+pub const KILLED_EXIT_CODE: i32 = -9;
+// Exit code of a user process stopped by a fault
+pub const FAULT_EXIT_CODE: i32 = -11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    // Kernel code sharing the kernel address space
+    Supervisor,
+    // Isolated in its own address space
+    User,
+}
+
+// Exception that stopped a user process
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fault {
+    pub cause: usize,
+    pub address: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessState {
+    // Waiting in the run queue
+    Ready,
+    Running,
+    // Waiting for a child to exit
+    Blocked,
+    // Waiting for its wake up time
+    Sleeping,
+    // Waiting for console input
+    Reading,
+    // Exited, kept until the parent collects the exit code
+    Zombie,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitError {
+    // The process has no child matching the request
+    NoChild,
+}
+
+// The trap vector relies on the layout of the first fields
 #[repr(C)]
 pub struct Process {
     frame: ProcessFrame,
     stack: *mut u8,
     pc: usize,
+    // Stack the trap handler runs on, 0 to stay on the process stack
+    trap_stack: usize,
+    // Null for processes using the kernel address space
+    page_table: *mut PageTable,
+    mode: Mode,
+    pub(crate) fault: Option<Fault>,
+    pub files: FileTable,
+    pid: Pid,
+    pub(crate) parent: Option<Pid>,
+    pub(crate) state: ProcessState,
+    pub(crate) exit_code: i32,
+    // Child a blocked process waits for, None for any child
+    pub(crate) wait_target: Option<Pid>,
+    // Where the waiting process wants the status of the child, 0 for nowhere
+    pub(crate) wait_status: usize,
+    // Timer value a sleeping process waits for
+    pub(crate) wake_at: u64,
+    // Address and size of the buffer a reading process waits to fill
+    pub(crate) console_read: (usize, usize),
+    // End of the heap grown by brk, and where the next mmap goes
+    pub(crate) brk: usize,
+    pub(crate) mmap_next: usize,
 }
 
 #[repr(C)]
@@ -16,34 +100,367 @@ pub struct ProcessFrame {
     pub registers: [usize; 32],
 }
 
+// Index of registers in the frame, in the order the trap vector saves them
+pub const FRAME_RA: usize = 0;
+pub const FRAME_SP: usize = 1;
+pub const FRAME_A0: usize = 9;
+pub const FRAME_A5: usize = 14;
+pub const FRAME_A7: usize = 16;
+
 impl Process {
-    pub fn new_process(start_pc: usize) -> Self {
+    pub fn new_process(pid: Pid, parent: Option<Pid>, start_pc: usize) -> Self {
         // TODO: Make the number of pages parametrizable
         let mut process = Process {
-            stack: page_allocator::alloc(10),
+            stack: page_allocator::alloc(STACK_PAGES),
             pc: start_pc,
+            trap_stack: 0,
+            page_table: null_mut(),
+            mode: Mode::Supervisor,
+            fault: None,
             frame: ProcessFrame { registers: [0; 32] },
+            files: FileTable::new(),
+            pid,
+            parent,
+            state: ProcessState::Ready,
+            exit_code: 0,
+            wait_target: None,
+            wait_status: 0,
+            wake_at: 0,
+            console_read: (0, 0),
+            brk: USER_HEAP_BASE,
+            mmap_next: USER_MMAP_BASE,
         };
 
-        process.frame.registers[1] = process.stack as usize + 10 * 4096;
+        process.frame.registers[FRAME_SP] =
+            process.stack as usize + STACK_PAGES * page_allocator::PAGE_SIZE;
+        // Returning from the entry point exits the process
+        process.frame.registers[FRAME_RA] = process_return as *const () as usize;
 
         // We don't need to map the stack at this point. We operate under lazy mapping
         // Finally we can return the process
         process
     }
 
-    pub const fn null_proc() -> Self {
-        // TODO: Make the number of pages parametrizable
-        let process = Process {
-            stack: null_mut(),
-            pc: 0,
-            frame: ProcessFrame { registers: [0; 32] },
-        };
+    // Process running entry, a function of the user module, in user mode. The
+    // stack is null when out of memory.
+    pub fn new_user_process(pid: Pid, parent: Option<Pid>, entry: usize) -> Self {
+        let mut process = Process::new_process(pid, parent, entry);
+        if process.stack.is_null() {
+            return process;
+        }
 
-        // We don't need to map the stack at this point. We operate under lazy mapping
-        // Finally we can return the process
+        // The kernel stack is only used by the trap handler
+        process.trap_stack = process.frame.registers[FRAME_SP];
+        process.mode = Mode::User;
+        process.page_table = paging::new_address_space();
+        if process.page_table.is_null() || !process.map_user_memory() {
+            process.release_memory();
+            return process;
+        }
+
+        process.pc = user_address(entry);
+        process.frame.registers[FRAME_SP] = USER_STACK_TOP;
+        process.frame.registers[FRAME_RA] = user_address(user::user_return as *const () as usize);
         process
     }
+
+    // Map the user programs and allocate the user stack
+    fn map_user_memory(&mut self) -> bool {
+        let (start, end) = user_text();
+        unsafe {
+            for offset in (0..end - start).step_by(page_allocator::PAGE_SIZE) {
+                paging::map_in(
+                    self.page_table,
+                    USER_TEXT_BASE + offset,
+                    start + offset,
+                    EntryBits::UserReadExecute.val(),
+                );
+            }
+        }
+
+        self.map_pages(
+            USER_STACK_TOP - USER_STACK_PAGES * page_allocator::PAGE_SIZE,
+            USER_STACK_TOP,
+            EntryBits::UserReadWrite.val(),
+        )
+    }
+
+    // Map zeroed pages owned by the address space over [start, end), pages
+    // that are already mapped are kept. False when out of memory.
+    pub(crate) fn map_pages(&mut self, start: usize, end: usize, bits: i64) -> bool {
+        let start = paging::page_align_round_down(start);
+        for address in (start..end).step_by(page_allocator::PAGE_SIZE) {
+            if unsafe { paging::translate(self.page_table, address) }.is_some() {
+                continue;
+            }
+
+            let page = page_allocator::alloc(1);
+            if page.is_null() {
+                return false;
+            }
+            unsafe {
+                paging::map_in(
+                    self.page_table,
+                    address,
+                    page as usize,
+                    bits | EntryBits::Owned.val(),
+                )
+            };
+        }
+        true
+    }
+
+    // Copy between the kernel and the memory of the process, false when part
+    // of the range is not mapped with user access. Kernel processes share the
+    // kernel address space, their pointers are used as they are.
+    pub(crate) fn read_memory(&self, address: usize, buf: &mut [u8]) -> bool {
+        self.for_each_chunk(address, buf.len(), false, |physical, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(physical, buf[offset..].as_mut_ptr(), len)
+        })
+    }
+
+    pub(crate) fn write_memory(&self, address: usize, data: &[u8]) -> bool {
+        self.for_each_chunk(address, data.len(), true, |physical, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), physical, len)
+        })
+    }
+
+    // Split a range of the process at page boundaries, f gets the physical
+    // address, the offset in the range and the length of each part
+    fn for_each_chunk<F: FnMut(*mut u8, usize, usize)>(
+        &self,
+        address: usize,
+        len: usize,
+        write: bool,
+        mut f: F,
+    ) -> bool {
+        if len == 0 {
+            return true;
+        }
+        if address == 0 || address.checked_add(len).is_none() {
+            return false;
+        }
+        if self.page_table.is_null() {
+            f(address as *mut u8, 0, len);
+            return true;
+        }
+
+        let mut done = 0;
+        while done < len {
+            let virtual_address = address + done;
+            let chunk = (page_allocator::PAGE_SIZE - virtual_address % page_allocator::PAGE_SIZE)
+                .min(len - done);
+            match unsafe { paging::translate_user(self.page_table, virtual_address, write) } {
+                Some(physical) => f(physical as *mut u8, done, chunk),
+                None => return false,
+            }
+            done += chunk;
+        }
+        true
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    // Where the process resumes, saved when it was last interrupted
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn stack(&self) -> *mut u8 {
+        self.stack
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    pub fn page_table(&self) -> *mut PageTable {
+        self.page_table
+    }
+
+    // Install the address space and the privilege mode sret returns to
+    pub(crate) fn activate(&self) {
+        paging::activate(self.page_table);
+        unsafe {
+            asm!("csrc sstatus, {}", in(reg) reg::SSTATUS_SPP);
+            if self.mode == Mode::Supervisor {
+                asm!("csrs sstatus, {}", in(reg) reg::SSTATUS_SPP);
+            }
+        }
+    }
+
+    pub(crate) fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub(crate) fn register(&self, index: usize) -> usize {
+        self.frame.registers[index]
+    }
+
+    pub(crate) fn set_register(&mut self, index: usize, value: usize) {
+        self.frame.registers[index] = value;
+    }
+
+    // Give back the stack and the address space, the process must never run
+    // again
+    pub(crate) fn release_memory(&mut self) {
+        if !self.stack.is_null() {
+            page_allocator::dealloc(self.stack);
+            self.stack = null_mut();
+        }
+        if !self.page_table.is_null() {
+            unsafe { paging::free_address_space(self.page_table) };
+            self.page_table = null_mut();
+        }
+    }
+
+    // Pages allocated for the process
+    pub fn pages(&self) -> usize {
+        let mut pages = 0;
+        if !self.stack.is_null() {
+            pages += STACK_PAGES;
+        }
+        if !self.page_table.is_null() {
+            pages += unsafe { paging::owned_pages(self.page_table) };
+        }
+        pages
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.release_memory();
+    }
+}
+
+fn user_text() -> (usize, usize) {
+    (
+        &raw const _user_text_start as usize,
+        &raw const _user_text_end as usize,
+    )
+}
+
+// Where a function of the user module is mapped in user address spaces
+pub fn user_address(function: usize) -> usize {
+    let (start, end) = user_text();
+    assert!(
+        (start..end).contains(&function),
+        "Function is not in the user section"
+    );
+    USER_TEXT_BASE + function - start
+}
+
+// The process whose frame is installed in sscratch
+pub fn current() -> &'static mut Process {
+    let process = reg::sscratch_read() as *mut Process;
+    assert!(!process.is_null(), "No process is running");
+    unsafe { &mut *process }
+}
+
+// Terminate the running process, its parent gets the exit code from wait
+pub fn exit(code: i32) -> ! {
+    syscall::call(SYS_EXIT, [code as usize, 0, 0, 0, 0, 0]);
+    unreachable!("Exited process was scheduled again");
+}
+
+// Where a process goes when its entry point returns
+extern "C" fn process_return() -> ! {
+    exit(0)
+}
+
+// Wait for a child to exit, any child when pid is None. Returns the pid of the
+// child and its exit code.
+pub fn waitpid(pid: Option<Pid>) -> Result<(Pid, i32), WaitError> {
+    let mut status = 0u32;
+    let args = [
+        pid.unwrap_or(usize::MAX),
+        &raw mut status as usize,
+        0,
+        0,
+        0,
+        0,
+    ];
+    let ret = syscall::call(SYS_WAIT, args);
+    match ret as isize {
+        ret if ret == -(ECHILD as isize) => Err(WaitError::NoChild),
+        _ => Ok((ret, exit_code(status))),
+    }
+}
+
+pub fn wait() -> Result<(Pid, i32), WaitError> {
+    waitpid(None)
+}
+
+// Status word of wait4: the exit code in the second byte, or the signal for
+// processes that were killed or faulted
+fn wait_status(code: i32) -> u32 {
+    if code < 0 {
+        code.unsigned_abs() & 0x7f
+    } else {
+        (code as u32 & 0xff) << 8
+    }
+}
+
+fn exit_code(status: u32) -> i32 {
+    match status & 0x7f {
+        0 => ((status >> 8) & 0xff) as i32,
+        signal => -(signal as i32),
+    }
+}
+
+// Write the result of a wait into the frame of the waiting process, the pid or
+// a negated errno goes in a0 and the status where it asked for it
+pub(crate) fn set_wait_result(process: &mut Process, result: Result<(Pid, i32), WaitError>) {
+    let ret = match result {
+        Ok((pid, code)) => {
+            let status = wait_status(code).to_le_bytes();
+            if process.wait_status == 0 || process.write_memory(process.wait_status, &status) {
+                pid
+            } else {
+                -(EFAULT as isize) as usize
+            }
+        }
+        Err(WaitError::NoChild) => -(ECHILD as isize) as usize,
+    };
+    process.wait_status = 0;
+    process.set_register(FRAME_A0, ret);
+}
+
+// Complete the console read a process is blocked on with the buffered input,
+// false when there is none yet
+pub(crate) fn finish_console_read(process: &mut Process) -> bool {
+    let (address, count) = process.console_read;
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let read = uart::read_input(&mut buffer[..count.min(CHUNK_SIZE)]);
+    if read == 0 {
+        return false;
+    }
+
+    let ret = if process.write_memory(address, &buffer[..read]) {
+        read
+    } else {
+        -(EFAULT as isize) as usize
+    };
+    process.set_register(FRAME_A0, ret);
+    true
 }
 
 pub fn process1() {
@@ -56,12 +473,10 @@ pub fn process1() {
     }
 }
 
+// Stops after a few values, returning exits the process
 pub fn process2() {
-    let mut i: usize = 0;
-    loop {
+    for i in 0..10 {
         println!("PROCESS 2 | Value {}", i);
         for _ in 0..500000 {}
-
-        i += 1;
     }
 }
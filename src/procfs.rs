@@ -0,0 +1,368 @@
+// Process file system mounted on /proc. Files are rendered from the kernel
+// state every time they are read, nothing is stored.
+
+use crate::process::{Fault, Mode, ProcessState};
+use crate::scheduler::{self, Pid};
+use crate::trap::{self, Cause};
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, InodeRef, Stat, VfsError};
+use crate::{block, kmalloc, page_allocator, platform, reg, slab, uart};
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Node {
+    Root,
+    // Directory of the process with this pid
+    Process(usize),
+    // Points to the directory of the running process
+    SelfLink,
+    Status(usize),
+    MemInfo,
+    Interrupts,
+    Uptime,
+}
+
+const ROOT_FILES: [(&str, Node); 4] = [
+    ("meminfo", Node::MemInfo),
+    ("interrupts", Node::Interrupts),
+    ("uptime", Node::Uptime),
+    ("self", Node::SelfLink),
+];
+
+// What the status file shows of a process, copied with interrupts disabled.
+// The process may be reaped as soon as they are enabled again, formatting
+// cannot hold on to it.
+struct Status {
+    state: ProcessState,
+    parent: Option<Pid>,
+    exit_code: i32,
+    mode: Mode,
+    fault: Option<Fault>,
+    pc: usize,
+    stack: usize,
+    pages: usize,
+    files: usize,
+}
+
+fn status(pid: Pid) -> Option<Status> {
+    scheduler::with_process(pid, |process| Status {
+        state: process.state(),
+        parent: process.parent(),
+        exit_code: process.exit_code(),
+        mode: process.mode(),
+        fault: process.fault(),
+        pc: process.pc(),
+        stack: process.stack() as usize,
+        pages: process.pages(),
+        files: process.files.open_count(),
+    })
+}
+
+fn exists(pid: Pid) -> bool {
+    scheduler::with_process(pid, |_| ()).is_some()
+}
+
+//----------- Renderers ---------------//
+
+fn render_status(pid: usize) -> Result<String, VfsError> {
+    let process = status(pid).ok_or(VfsError::NotFound)?;
+    let mut text = String::new();
+    let _ = writeln!(text, "Pid:\t{}", pid);
+    let _ = writeln!(
+        text,
+        "State:\t{}",
+        match process.state {
+            ProcessState::Ready => "ready",
+            ProcessState::Running => "running",
+            ProcessState::Blocked => "blocked",
+            ProcessState::Sleeping => "sleeping",
+            ProcessState::Reading => "reading",
+            ProcessState::Zombie => "zombie",
+        }
+    );
+    let _ = writeln!(text, "PPid:\t{}", process.parent.unwrap_or(0));
+    if process.state == ProcessState::Zombie {
+        let _ = writeln!(text, "ExitCode:\t{}", process.exit_code);
+    }
+    let _ = writeln!(
+        text,
+        "Mode:\t{}",
+        match process.mode {
+            Mode::Supervisor => "supervisor",
+            Mode::User => "user",
+        }
+    );
+    if let Some(fault) = process.fault {
+        let _ = writeln!(
+            text,
+            "Fault:\t{} at 0x{:016x}",
+            Cause::new(fault.cause).name(),
+            fault.address
+        );
+    }
+    let _ = writeln!(text, "Pc:\t0x{:016x}", process.pc);
+    let _ = writeln!(text, "Stack:\t0x{:016x}", process.stack);
+    let _ = writeln!(text, "Pages:\t{}", process.pages);
+    let _ = writeln!(text, "Files:\t{}", process.files);
+    Ok(text)
+}
+
+fn render_meminfo() -> String {
+    let page_kb = page_allocator::PAGE_SIZE / 1024;
+    let heap = kmalloc::stats();
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "MemTotal:\t{} kB",
+        page_allocator::total_pages() * page_kb
+    );
+    let _ = writeln!(
+        text,
+        "MemFree:\t{} kB",
+        page_allocator::free_pages() * page_kb
+    );
+    let _ = writeln!(text, "HeapTotal:\t{} kB", heap.size / 1024);
+    let _ = writeln!(text, "HeapFree:\t{} kB", heap.free / 1024);
+    slab::for_each_cache(|stats| {
+        let _ = writeln!(
+            text,
+            "Slab {}:\t{} objects of {} bytes in {} slabs",
+            stats.name, stats.objects_in_use, stats.object_size, stats.slabs
+        );
+    });
+    text
+}
+
+fn plic_source_name(source: u32) -> &'static str {
+    if source == uart::irq() {
+        "uart"
+    } else if block::is_block_irq(source) {
+        "virtio-blk"
+    } else {
+        "unknown"
+    }
+}
+
+fn render_interrupts() -> String {
+    let mut text = String::new();
+    for source in 1..trap::PLIC_SOURCES as u32 {
+        let count = trap::plic_count(source);
+        if count > 0 {
+            let _ = writeln!(
+                text,
+                "{:>4}: {:>10}  PLIC  {}",
+                source,
+                count,
+                plic_source_name(source)
+            );
+        }
+    }
+
+    for code in 0..trap::CAUSE_COUNT {
+        let count = trap::interrupt_count(code);
+        if count > 0 {
+            let cause = Cause::new(code | trap::MASK_INTERRUPT_BIT);
+            let _ = writeln!(text, "I{:<3}: {:>10}  {}", code, count, cause.name());
+        }
+    }
+    for code in 0..trap::CAUSE_COUNT {
+        let count = trap::exception_count(code);
+        if count > 0 {
+            let cause = Cause::new(code);
+            let _ = writeln!(text, "E{:<3}: {:>10}  {}", code, count, cause.name());
+        }
+    }
+    text
+}
+
+fn render_uptime() -> String {
+    let timebase = platform::get().timebase;
+    let ticks = reg::time_read();
+    let hundredths = (ticks % timebase) * 100 / timebase;
+    format!("{}.{:02}\n", ticks / timebase, hundredths)
+}
+
+//----------- File system ---------------//
+
+struct ProcInode {
+    node: Node,
+}
+
+impl ProcInode {
+    fn new(node: Node) -> InodeRef {
+        Arc::new(ProcInode { node })
+    }
+
+    fn render(&self) -> Result<String, VfsError> {
+        match self.node {
+            Node::Status(pid) => render_status(pid),
+            Node::MemInfo => Ok(render_meminfo()),
+            Node::Interrupts => Ok(render_interrupts()),
+            Node::Uptime => Ok(render_uptime()),
+            Node::Root | Node::Process(_) => Err(VfsError::IsADirectory),
+            Node::SelfLink => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    fn file_type_of(node: Node) -> FileType {
+        match node {
+            Node::Root | Node::Process(_) => FileType::Directory,
+            Node::SelfLink => FileType::Symlink,
+            _ => FileType::Regular,
+        }
+    }
+}
+
+impl Inode for ProcInode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        // Nodes of processes that went away are gone
+        if let Node::Process(pid) | Node::Status(pid) = self.node {
+            if !exists(pid) {
+                return Err(VfsError::NotFound);
+            }
+        }
+
+        // Like on Linux files have no size, they are read until the end
+        Ok(Stat {
+            inode: match self.node {
+                Node::Root => 1,
+                Node::SelfLink => 2,
+                Node::MemInfo => 3,
+                Node::Interrupts => 4,
+                Node::Uptime => 5,
+                Node::Process(pid) => (pid as u64) << 8,
+                Node::Status(pid) => (pid as u64) << 8 | 1,
+            },
+            file_type: Self::file_type_of(self.node),
+            size: 0,
+            links: 1,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let text = self.render()?;
+        let bytes = text.as_bytes();
+        if offset >= bytes.len() as u64 {
+            return Ok(0);
+        }
+
+        let start = offset as usize;
+        let len = buf.len().min(bytes.len() - start);
+        buf[..len].copy_from_slice(&bytes[start..start + len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        match self.node {
+            Node::Root => {
+                if let Some((_, node)) = ROOT_FILES.iter().find(|(file, _)| *file == name) {
+                    return Ok(Self::new(*node));
+                }
+                let pid = name.parse::<usize>().map_err(|_| VfsError::NotFound)?;
+                if !exists(pid) {
+                    return Err(VfsError::NotFound);
+                }
+                Ok(Self::new(Node::Process(pid)))
+            }
+            Node::Process(pid) if name == "status" => Ok(Self::new(Node::Status(pid))),
+            Node::Process(_) => Err(VfsError::NotFound),
+            _ => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        match self.node {
+            Node::Root => {
+                let mut entries: Vec<DirEntry> = ROOT_FILES
+                    .iter()
+                    .map(|(name, node)| DirEntry {
+                        name: String::from(*name),
+                        file_type: Self::file_type_of(*node),
+                    })
+                    .collect();
+                for pid in scheduler::pids() {
+                    entries.push(DirEntry {
+                        name: format!("{}", pid),
+                        file_type: FileType::Directory,
+                    });
+                }
+                Ok(entries)
+            }
+            Node::Process(_) => Ok(alloc::vec![DirEntry {
+                name: String::from("status"),
+                file_type: FileType::Regular,
+            }]),
+            _ => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        match self.node {
+            Node::SelfLink => Ok(format!(
+                "{}",
+                scheduler::current_pid().ok_or(VfsError::NotFound)?
+            )),
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &str {
+        "procfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        ProcInode::new(Node::Root)
+    }
+}
+
+pub fn init() {
+    vfs::mkdir_all("/proc").expect("Cannot create /proc");
+    vfs::mount("/proc", Arc::new(ProcFs)).expect("Cannot mount /proc");
+}
+
+fn read_all(path: &str) -> String {
+    let fd = vfs::open(path, vfs::O_RDONLY).expect("Cannot open a /proc file");
+    let mut text = Vec::new();
+    let mut buffer = [0u8; 64];
+    loop {
+        let count = vfs::read(fd, &mut buffer).expect("Cannot read a /proc file");
+        if count == 0 {
+            break;
+        }
+        text.extend_from_slice(&buffer[..count]);
+    }
+    vfs::close(fd).expect("Cannot close a /proc file");
+    String::from_utf8(text).expect("/proc file is not text")
+}
+
+pub fn init_sanity_check() {
+    assert!(read_all("/proc/meminfo").starts_with("MemTotal:"));
+    assert!(read_all("/proc/uptime").ends_with('\n'));
+
+    // The kernel runs as a process, reading its status goes through /proc/self
+    let status = read_all("/proc/self/status");
+    let pid = scheduler::current_pid().expect("No process is running");
+    assert!(status.starts_with(&format!("Pid:\t{}\n", pid)));
+    assert!(status.contains("State:\trunning"));
+
+    assert_eq!(vfs::stat("/proc/0").err(), Some(VfsError::NotFound));
+
+    // Files are rendered, they cannot be written
+    let fd = vfs::open("/proc/uptime", vfs::O_WRONLY).expect("Cannot open /proc/uptime");
+    assert_eq!(vfs::write(fd, b"0"), Err(VfsError::Unsupported));
+    vfs::close(fd).expect("Cannot close /proc/uptime");
+}
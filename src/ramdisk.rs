@@ -0,0 +1,106 @@
+// Block device kept in memory ("ram0"). The sanity checks that need to write
+// use it, the disks of the user are only read at boot.
+
+use crate::bcache;
+use crate::block::{self, BlockDevice, BlockError};
+use core::cell::RefCell;
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const SCRATCH_SECTORS: u64 = 256;
+
+pub struct RamDisk {
+    data: RefCell<Vec<u8>>,
+}
+
+impl RamDisk {
+    const fn empty() -> Self {
+        RamDisk {
+            data: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn range(&self, sector: u64, len: usize) -> Result<core::ops::Range<usize>, BlockError> {
+        block::check_request(self, sector, len)?;
+        let start = sector as usize * SECTOR_SIZE;
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        "ram0"
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_sectors(&self) -> u64 {
+        (self.data.borrow().len() / SECTOR_SIZE) as u64
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let range = self.range(sector, buf.len())?;
+        buf.copy_from_slice(&self.data.borrow()[range]);
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let range = self.range(sector, buf.len())?;
+        self.data.borrow_mut()[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+static mut SCRATCH: RamDisk = RamDisk::empty();
+static mut SCRATCH_ID: Option<usize> = None;
+
+fn scratch_disk() -> &'static RamDisk {
+    unsafe { &*(&raw const SCRATCH) }
+}
+
+pub fn init() {
+    *scratch_disk().data.borrow_mut() = vec![0; SCRATCH_SECTORS as usize * SECTOR_SIZE];
+    unsafe { SCRATCH_ID = block::register(scratch_disk()) };
+}
+
+// Registry id of the scratch disk, zeroed and out of the buffer cache
+pub fn scratch() -> Option<usize> {
+    let id = unsafe { SCRATCH_ID }?;
+    bcache::invalidate_device(id).ok()?;
+    scratch_disk().data.borrow_mut().fill(0);
+    Some(id)
+}
+
+pub fn init_sanity_check() {
+    let id = match scratch() {
+        Some(id) => id,
+        None => return,
+    };
+    let disk = block::get(id).unwrap();
+    assert_eq!(disk.num_sectors(), SCRATCH_SECTORS);
+
+    let data = [0x5au8; SECTOR_SIZE];
+    disk.write(SCRATCH_SECTORS - 1, &data)
+        .expect("Cannot write the ram disk");
+    let mut read = [0u8; SECTOR_SIZE];
+    disk.read(SCRATCH_SECTORS - 1, &mut read)
+        .expect("Cannot read the ram disk");
+    assert!(read == data, "Ram disk returned different data");
+    assert_eq!(
+        disk.read(SCRATCH_SECTORS, &mut read),
+        Err(BlockError::OutOfRange)
+    );
+}
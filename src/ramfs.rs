@@ -0,0 +1,429 @@
+// RAM backed file system. File contents live in pages of the page allocator,
+// a page is only allocated once something is written to it so holes in sparse
+// files read back as zeros without using memory.
+
+use crate::page_allocator::{self, PAGE_SIZE};
+use crate::vfs::{DirEntry, FileSystem, FileType, Inode, InodeRef, Stat, VfsError};
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::RefCell;
+
+static mut NEXT_INODE: u64 = 1;
+
+fn next_inode() -> u64 {
+    unsafe {
+        let number = NEXT_INODE;
+        NEXT_INODE += 1;
+        number
+    }
+}
+
+// A page of file data, given back to the page allocator when dropped
+struct Page(*mut u8);
+
+impl Page {
+    fn alloc() -> Result<Page, VfsError> {
+        let pointer = page_allocator::alloc(1);
+        if pointer.is_null() {
+            return Err(VfsError::NoSpace);
+        }
+        Ok(Page(pointer))
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.0, PAGE_SIZE) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.0, PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        page_allocator::dealloc(self.0);
+    }
+}
+
+enum Content {
+    // Pages are indexed by their offset in the file divided by the page size
+    File {
+        pages: BTreeMap<u64, Page>,
+        size: u64,
+    },
+    Directory {
+        entries: Vec<(String, Arc<RamInode>)>,
+    },
+    Symlink(String),
+}
+
+pub struct RamInode {
+    number: u64,
+    content: RefCell<Content>,
+}
+
+impl RamInode {
+    fn new(file_type: FileType) -> Result<Arc<RamInode>, VfsError> {
+        let content = match file_type {
+            FileType::Regular => Content::File {
+                pages: BTreeMap::new(),
+                size: 0,
+            },
+            FileType::Directory => Content::Directory {
+                entries: Vec::new(),
+            },
+            _ => return Err(VfsError::Unsupported),
+        };
+
+        Ok(Arc::new(RamInode {
+            number: next_inode(),
+            content: RefCell::new(content),
+        }))
+    }
+
+    fn kind(&self) -> FileType {
+        match &*self.content.borrow() {
+            Content::File { .. } => FileType::Regular,
+            Content::Directory { .. } => FileType::Directory,
+            Content::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    fn is_empty_dir(&self) -> bool {
+        match &*self.content.borrow() {
+            Content::Directory { entries } => entries.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn child(&self, name: &str) -> Result<Arc<RamInode>, VfsError> {
+        match &*self.content.borrow() {
+            Content::Directory { entries } => entries
+                .iter()
+                .find(|(child, _)| child == name)
+                .map(|(_, inode)| inode.clone())
+                .ok_or(VfsError::NotFound),
+            _ => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn entries(&self) -> core::cell::RefMut<'_, Vec<(String, Arc<RamInode>)>> {
+        core::cell::RefMut::map(self.content.borrow_mut(), |content| match content {
+            Content::Directory { entries } => entries,
+            _ => panic!("Ramfs file used as a directory"),
+        })
+    }
+
+    // Number of pages holding data, holes are not counted
+    fn page_count(&self) -> usize {
+        match &*self.content.borrow() {
+            Content::File { pages, .. } => pages.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl Inode for RamInode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let (file_type, size, links) = match &*self.content.borrow() {
+            Content::File { size, .. } => (FileType::Regular, *size, 1),
+            Content::Directory { entries } => {
+                let subdirectories = entries
+                    .iter()
+                    .filter(|(_, inode)| inode.kind() == FileType::Directory)
+                    .count();
+                (
+                    FileType::Directory,
+                    entries.len() as u64,
+                    2 + subdirectories as u32,
+                )
+            }
+            Content::Symlink(target) => (FileType::Symlink, target.len() as u64, 1),
+        };
+
+        Ok(Stat {
+            inode: self.number,
+            file_type,
+            size,
+            links,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let content = self.content.borrow();
+        let (pages, size) = match &*content {
+            Content::File { pages, size } => (pages, *size),
+            Content::Directory { .. } => return Err(VfsError::IsADirectory),
+            Content::Symlink(_) => return Err(VfsError::InvalidArgument),
+        };
+
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let in_page = (position % PAGE_SIZE as u64) as usize;
+            let chunk = (PAGE_SIZE - in_page).min(len - done);
+
+            match pages.get(&(position / PAGE_SIZE as u64)) {
+                Some(page) => {
+                    buf[done..done + chunk].copy_from_slice(&page.data()[in_page..in_page + chunk])
+                }
+                None => buf[done..done + chunk].fill(0),
+            }
+            done += chunk;
+        }
+
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        let mut content = self.content.borrow_mut();
+        let (pages, size) = match &mut *content {
+            Content::File { pages, size } => (pages, size),
+            Content::Directory { .. } => return Err(VfsError::IsADirectory),
+            Content::Symlink(_) => return Err(VfsError::InvalidArgument),
+        };
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let in_page = (position % PAGE_SIZE as u64) as usize;
+            let chunk = (PAGE_SIZE - in_page).min(buf.len() - done);
+
+            let index = position / PAGE_SIZE as u64;
+            if !pages.contains_key(&index) {
+                match Page::alloc() {
+                    Ok(page) => pages.insert(index, page),
+                    // Report what was written before memory ran out
+                    Err(_) if done > 0 => break,
+                    Err(error) => return Err(error),
+                };
+            }
+
+            let page = pages.get_mut(&index).unwrap();
+            page.data_mut()[in_page..in_page + chunk].copy_from_slice(&buf[done..done + chunk]);
+            done += chunk;
+        }
+
+        *size = (*size).max(offset + done as u64);
+        Ok(done)
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), VfsError> {
+        let mut content = self.content.borrow_mut();
+        let (pages, size) = match &mut *content {
+            Content::File { pages, size } => (pages, size),
+            Content::Directory { .. } => return Err(VfsError::IsADirectory),
+            Content::Symlink(_) => return Err(VfsError::InvalidArgument),
+        };
+
+        // Pages past the end are freed, the tail of the last one is cleared so
+        // growing the file again reads zeros
+        let first_unused = new_size.div_ceil(PAGE_SIZE as u64);
+        drop(pages.split_off(&first_unused));
+        let in_page = (new_size % PAGE_SIZE as u64) as usize;
+        if in_page != 0 {
+            if let Some(page) = pages.get_mut(&(new_size / PAGE_SIZE as u64)) {
+                page.data_mut()[in_page..].fill(0);
+            }
+        }
+
+        *size = new_size;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        Ok(self.child(name)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        match &*self.content.borrow() {
+            Content::Directory { entries } => Ok(entries
+                .iter()
+                .map(|(name, inode)| DirEntry {
+                    name: name.clone(),
+                    file_type: inode.kind(),
+                })
+                .collect()),
+            _ => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<InodeRef, VfsError> {
+        match self.child(name) {
+            Ok(_) => return Err(VfsError::AlreadyExists),
+            Err(VfsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let inode = RamInode::new(file_type)?;
+        self.entries().push((String::from(name), inode.clone()));
+        Ok(inode)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, VfsError> {
+        match self.child(name) {
+            Ok(_) => return Err(VfsError::AlreadyExists),
+            Err(VfsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let inode = Arc::new(RamInode {
+            number: next_inode(),
+            content: RefCell::new(Content::Symlink(String::from(target))),
+        });
+        self.entries().push((String::from(name), inode.clone()));
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let child = self.child(name)?;
+        if child.kind() == FileType::Directory && !child.is_empty_dir() {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+
+        // Open files keep their inode, the pages are freed with the last reference
+        self.entries().retain(|(entry, _)| entry != name);
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, new_parent: &dyn Inode, new_name: &str) -> Result<(), VfsError> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<RamInode>()
+            .ok_or(VfsError::CrossDevice)?;
+
+        let child = self.child(name)?;
+        match new_parent.child(new_name) {
+            Ok(existing) if Arc::ptr_eq(&existing, &child) => return Ok(()),
+            Ok(_) => return Err(VfsError::AlreadyExists),
+            Err(VfsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        self.entries().retain(|(entry, _)| entry != name);
+        new_parent.entries().push((String::from(new_name), child));
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        match &*self.content.borrow() {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        RamFs {
+            root: RamInode::new(FileType::Directory).expect("Cannot create a directory"),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &str {
+        "ramfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+pub fn init() {}
+
+pub fn init_sanity_check() {
+    let fs = RamFs::new();
+    let root = fs.root.clone();
+
+    let dir = root
+        .create("dir", FileType::Directory)
+        .expect("Cannot create a directory");
+    let file = dir
+        .create("file", FileType::Regular)
+        .expect("Cannot create a file");
+    assert_eq!(
+        root.create("dir", FileType::Regular).err(),
+        Some(VfsError::AlreadyExists)
+    );
+
+    // Only the page written to is allocated, the hole before it reads as zeros
+    let offset = 3 * PAGE_SIZE as u64 + 10;
+    assert_eq!(file.write_at(offset, b"sparse"), Ok(6));
+    assert_eq!(file.stat().map(|stat| stat.size), Ok(offset + 6));
+    let ram_file = dir
+        .as_any()
+        .downcast_ref::<RamInode>()
+        .unwrap()
+        .child("file")
+        .unwrap();
+    assert_eq!(ram_file.page_count(), 1);
+
+    let mut buffer = [0xffu8; 16];
+    assert_eq!(file.read_at(offset - 10, &mut buffer), Ok(16));
+    assert_eq!(&buffer[..10], &[0; 10]);
+    assert_eq!(&buffer[10..], b"sparse");
+
+    // A write across a page boundary fills two pages
+    assert_eq!(file.write_at(PAGE_SIZE as u64 - 2, b"edge"), Ok(4));
+    assert_eq!(ram_file.page_count(), 3);
+    assert_eq!(file.read_at(PAGE_SIZE as u64 - 2, &mut buffer[..4]), Ok(4));
+    assert_eq!(&buffer[..4], b"edge");
+
+    // Shrinking frees the pages past the end and clears the tail
+    file.truncate(PAGE_SIZE as u64 - 1)
+        .expect("Cannot truncate");
+    assert_eq!(ram_file.page_count(), 1);
+    file.truncate(PAGE_SIZE as u64 + 2).expect("Cannot grow");
+    assert_eq!(file.read_at(PAGE_SIZE as u64 - 2, &mut buffer[..4]), Ok(4));
+    assert_eq!(&buffer[..4], b"e\0\0\0");
+
+    // Directories only go away once empty
+    assert_eq!(root.unlink("dir"), Err(VfsError::DirectoryNotEmpty));
+    dir.rename("file", &*root, "moved").expect("Cannot rename");
+    assert_eq!(dir.lookup("file").err(), Some(VfsError::NotFound));
+    assert_eq!(
+        root.lookup("moved")
+            .and_then(|inode| inode.stat())
+            .map(|stat| stat.size),
+        Ok(PAGE_SIZE as u64 + 2)
+    );
+    root.symlink("link", "moved")
+        .expect("Cannot create a symlink");
+    assert_eq!(
+        root.lookup("link")
+            .and_then(|link| link.read_link())
+            .as_deref(),
+        Ok("moved")
+    );
+    root.unlink("link").expect("Cannot remove a symlink");
+    root.unlink("dir")
+        .expect("Cannot remove an empty directory");
+    root.unlink("moved").expect("Cannot remove a file");
+    assert!(root.read_dir().expect("Cannot list the root").is_empty());
+}
@@ -1,41 +1,72 @@
 use core::arch::asm;
 
-pub fn mepc_read() -> usize {
+// Privilege mode sret returns to, 0 for user mode
+pub const SSTATUS_SPP: usize = 1 << 8;
+
+// Supervisor mode cannot read mhartid, the firmware gives it at boot
+static mut HART_ID: usize = 0;
+
+pub fn sepc_read() -> usize {
+    unsafe {
+        let rval;
+        asm!("csrr {}, sepc", out(reg) rval);
+        rval
+    }
+}
+
+pub fn stval_read() -> usize {
+    unsafe {
+        let rval;
+        asm!("csrr {}, stval", out(reg) rval);
+        rval
+    }
+}
+
+pub fn scause_read() -> usize {
     unsafe {
         let rval;
-        asm!("csrr {}, mepc", out(reg) rval);
+        asm!("csrr {}, scause", out(reg) rval);
         rval
     }
 }
 
-pub fn mtval_read() -> usize {
+pub fn hartid() -> usize {
+    unsafe { HART_ID }
+}
+
+pub fn set_hartid(hartid: usize) {
+    unsafe { HART_ID = hartid }
+}
+
+pub fn sie_read() -> usize {
     unsafe {
         let rval;
-        asm!("csrr {}, mtval", out(reg) rval);
+        asm!("csrr {}, sie", out(reg) rval);
         rval
     }
 }
 
-pub fn mcause_read() -> usize {
+pub fn sstatus_read() -> usize {
     unsafe {
         let rval;
-        asm!("csrr {}, mcause", out(reg) rval);
+        asm!("csrr {}, sstatus", out(reg) rval);
         rval
     }
 }
 
-pub fn mhartid_read() -> usize {
+pub fn sscratch_read() -> usize {
     unsafe {
         let rval;
-        asm!("csrr {}, mhartid", out(reg) rval);
+        asm!("csrr {}, sscratch", out(reg) rval);
         rval
     }
 }
 
-pub fn mstatus_read() -> usize {
+// Ticks of the platform timer, at platform::get().timebase Hz
+pub fn time_read() -> u64 {
     unsafe {
         let rval;
-        asm!("csrr {}, mstatus", out(reg) rval);
+        asm!("rdtime {}", out(reg) rval);
         rval
     }
 }
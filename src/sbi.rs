@@ -0,0 +1,284 @@
+// Calls to the supervisor binary interface. The kernel runs in supervisor
+// mode and asks the firmware below it, ours in firmware.rs or OpenSBI, for
+// everything that needs machine mode.
+
+use crate::reg;
+use core::arch::asm;
+
+// Extension ids, most are their name in ASCII
+pub const EXT_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+pub const EXT_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+pub const EXT_BASE: usize = 0x10;
+pub const EXT_TIME: usize = 0x5449_4d45;
+pub const EXT_IPI: usize = 0x0073_5049;
+pub const EXT_RFENCE: usize = 0x5246_4e43;
+pub const EXT_HSM: usize = 0x0048_534d;
+pub const EXT_SRST: usize = 0x5352_5354;
+
+// Function ids of each extension
+pub const BASE_GET_SPEC_VERSION: usize = 0;
+pub const BASE_GET_IMPL_ID: usize = 1;
+pub const BASE_GET_IMPL_VERSION: usize = 2;
+pub const BASE_PROBE_EXTENSION: usize = 3;
+pub const BASE_GET_MVENDORID: usize = 4;
+pub const BASE_GET_MARCHID: usize = 5;
+pub const BASE_GET_MIMPID: usize = 6;
+pub const TIME_SET_TIMER: usize = 0;
+pub const IPI_SEND_IPI: usize = 0;
+pub const RFENCE_FENCE_I: usize = 0;
+pub const RFENCE_SFENCE_VMA: usize = 1;
+pub const RFENCE_SFENCE_VMA_ASID: usize = 2;
+pub const HSM_HART_START: usize = 0;
+pub const HSM_HART_STOP: usize = 1;
+pub const HSM_HART_GET_STATUS: usize = 2;
+pub const HSM_HART_SUSPEND: usize = 3;
+pub const SRST_SYSTEM_RESET: usize = 0;
+
+// Error codes returned in a0
+pub const SUCCESS: isize = 0;
+pub const ERR_FAILED: isize = -1;
+pub const ERR_NOT_SUPPORTED: isize = -2;
+pub const ERR_INVALID_PARAM: isize = -3;
+pub const ERR_DENIED: isize = -4;
+pub const ERR_INVALID_ADDRESS: isize = -5;
+pub const ERR_ALREADY_AVAILABLE: isize = -6;
+pub const ERR_ALREADY_STARTED: isize = -7;
+pub const ERR_ALREADY_STOPPED: isize = -8;
+
+// A hart mask base selecting every hart
+pub const ALL_HARTS: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    Unknown(isize),
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> Self {
+        match code {
+            ERR_FAILED => SbiError::Failed,
+            ERR_NOT_SUPPORTED => SbiError::NotSupported,
+            ERR_INVALID_PARAM => SbiError::InvalidParam,
+            ERR_DENIED => SbiError::Denied,
+            ERR_INVALID_ADDRESS => SbiError::InvalidAddress,
+            ERR_ALREADY_AVAILABLE => SbiError::AlreadyAvailable,
+            ERR_ALREADY_STARTED => SbiError::AlreadyStarted,
+            ERR_ALREADY_STOPPED => SbiError::AlreadyStopped,
+            code => SbiError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum HartStatus {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+    Suspended = 4,
+    SuspendPending = 5,
+    ResumePending = 6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+fn call(extension: usize, function: usize, args: [usize; 4]) -> Result<usize, SbiError> {
+    let (error, value): (isize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+
+    if error == SUCCESS {
+        Ok(value)
+    } else {
+        Err(SbiError::from_code(error))
+    }
+}
+
+// Legacy extensions only return a value in a0
+fn legacy_call(extension: usize, arg: usize) -> isize {
+    let value: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg => value,
+            in("a7") extension,
+        );
+    }
+    value
+}
+
+// Major and minor version of the specification implemented by the firmware
+pub fn spec_version() -> (usize, usize) {
+    let version = call(EXT_BASE, BASE_GET_SPEC_VERSION, [0; 4]).unwrap_or(0);
+    ((version >> 24) & 0x7f, version & 0xff_ffff)
+}
+
+pub fn impl_id() -> usize {
+    call(EXT_BASE, BASE_GET_IMPL_ID, [0; 4]).unwrap_or(0)
+}
+
+pub fn impl_version() -> usize {
+    call(EXT_BASE, BASE_GET_IMPL_VERSION, [0; 4]).unwrap_or(0)
+}
+
+pub fn probe_extension(extension: usize) -> bool {
+    call(EXT_BASE, BASE_PROBE_EXTENSION, [extension, 0, 0, 0]).is_ok_and(|found| found != 0)
+}
+
+// Machine identification registers, only readable in machine mode
+pub fn machine_ids() -> (usize, usize, usize) {
+    (
+        call(EXT_BASE, BASE_GET_MVENDORID, [0; 4]).unwrap_or(0),
+        call(EXT_BASE, BASE_GET_MARCHID, [0; 4]).unwrap_or(0),
+        call(EXT_BASE, BASE_GET_MIMPID, [0; 4]).unwrap_or(0),
+    )
+}
+
+// Raise a supervisor timer interrupt once time reaches deadline, this clears
+// the pending one
+pub fn set_timer(deadline: u64) {
+    call(EXT_TIME, TIME_SET_TIMER, [deadline as usize, 0, 0, 0])
+        .expect("The SBI firmware has no timer");
+}
+
+// Raise a supervisor software interrupt on the harts of the mask
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    call(EXT_IPI, IPI_SEND_IPI, [hart_mask, hart_mask_base, 0, 0]).map(|_| ())
+}
+
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    call(
+        EXT_RFENCE,
+        RFENCE_FENCE_I,
+        [hart_mask, hart_mask_base, 0, 0],
+    )
+    .map(|_| ())
+}
+
+// Flush the translations of [start, start + size) on the harts of the mask
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> Result<(), SbiError> {
+    call(
+        EXT_RFENCE,
+        RFENCE_SFENCE_VMA,
+        [hart_mask, hart_mask_base, start, size],
+    )
+    .map(|_| ())
+}
+
+// Start a stopped hart in supervisor mode at start with a0 = hartid and
+// a1 = opaque, with translation off
+pub fn hart_start(hartid: usize, start: usize, opaque: usize) -> Result<(), SbiError> {
+    call(EXT_HSM, HSM_HART_START, [hartid, start, opaque, 0]).map(|_| ())
+}
+
+// Stop the calling hart, only returns on failure
+pub fn hart_stop() -> SbiError {
+    match call(EXT_HSM, HSM_HART_STOP, [0; 4]) {
+        Ok(_) => SbiError::Failed,
+        Err(error) => error,
+    }
+}
+
+pub fn hart_status(hartid: usize) -> Result<HartStatus, SbiError> {
+    let status = call(EXT_HSM, HSM_HART_GET_STATUS, [hartid, 0, 0, 0])?;
+    Ok(match status {
+        0 => HartStatus::Started,
+        1 => HartStatus::Stopped,
+        2 => HartStatus::StartPending,
+        3 => HartStatus::StopPending,
+        4 => HartStatus::Suspended,
+        5 => HartStatus::SuspendPending,
+        _ => HartStatus::ResumePending,
+    })
+}
+
+// Wait for an interrupt in the default retentive suspend state
+pub fn hart_suspend() -> Result<(), SbiError> {
+    call(EXT_HSM, HSM_HART_SUSPEND, [0; 4]).map(|_| ())
+}
+
+// Power off or reboot the machine, only returns on failure
+pub fn system_reset(kind: ResetType, reason: ResetReason) -> SbiError {
+    match call(
+        EXT_SRST,
+        SRST_SYSTEM_RESET,
+        [kind as usize, reason as usize, 0, 0],
+    ) {
+        Ok(_) => SbiError::Failed,
+        Err(error) => error,
+    }
+}
+
+pub fn console_putchar(byte: u8) {
+    legacy_call(EXT_LEGACY_CONSOLE_PUTCHAR, byte as usize);
+}
+
+pub fn console_getchar() -> Option<u8> {
+    match legacy_call(EXT_LEGACY_CONSOLE_GETCHAR, 0) {
+        -1 => None,
+        byte => Some(byte as u8),
+    }
+}
+
+pub fn init_sanity_check() {
+    let (major, minor) = spec_version();
+    assert!(
+        major > 0 || minor >= 2,
+        "SBI firmware is older than the 0.2 specification"
+    );
+
+    for extension in [EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST] {
+        assert!(
+            probe_extension(extension),
+            "SBI extension 0x{:x} is missing",
+            extension
+        );
+    }
+    assert!(!probe_extension(0x0bad_0bad));
+
+    assert_eq!(hart_status(reg::hartid()), Ok(HartStatus::Started));
+    assert_eq!(
+        hart_start(reg::hartid(), 0, 0),
+        Err(SbiError::AlreadyAvailable)
+    );
+
+    // Fences are harmless on the running hart
+    remote_fence_i(1 << reg::hartid(), 0).expect("Cannot fence instructions");
+    remote_sfence_vma(0, ALL_HARTS, 0, 0).expect("Cannot fence translations");
+}
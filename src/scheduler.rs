@@ -1,57 +1,538 @@
 use crate::kmain;
-use crate::process::{self, Process};
+use crate::lock;
+use crate::page_allocator;
+use crate::paging;
+use crate::process::{self, Fault, Mode, Process, ProcessState, WaitError};
+use crate::reg;
+use crate::user;
 use core::arch::asm;
 
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+pub type Pid = usize;
+
+// The process started at boot, it runs kmain
+pub const INIT_PID: Pid = 1;
+// Runs when every process is waiting, it is not in the process table
+pub const IDLE_PID: Pid = 0;
+
+// Processes are boxed so that the frame installed in sscratch never moves
 pub struct Scheduler {
-    pub init: process::Process,
-    proc1: process::Process,
-    proc2: process::Process,
-    counter: u32,
+    processes: BTreeMap<Pid, Box<Process>>,
+    // Ready processes waiting for the cpu, the running one is not in the queue
+    run_queue: VecDeque<Pid>,
+    current: Option<Pid>,
+    next_pid: Pid,
+    idle: Option<Box<Process>>,
 }
 
-pub static mut SCHEDULER: Scheduler = Scheduler {
-    init: Process::null_proc(),
-    proc1: Process::null_proc(),
-    proc2: Process::null_proc(),
-    counter: 0,
-};
+static mut SCHEDULER: Scheduler = Scheduler::empty();
+
+fn scheduler() -> &'static mut Scheduler {
+    unsafe { &mut *(&raw mut SCHEDULER) }
+}
 
 pub fn init() {
-    unsafe {
-        SCHEDULER = Scheduler::new_scheduler();
-        Scheduler::propagate_decision(&raw const SCHEDULER.init as usize);
-    }
+    let scheduler = scheduler();
+    *scheduler = Scheduler::empty();
+    let idle = Process::new_process(IDLE_PID, None, idle as *const () as usize);
+    assert!(!idle.stack().is_null(), "Cannot create the idle process");
+    scheduler.idle = Some(Box::new(idle));
+
+    let init = scheduler
+        .spawn(kmain as *const () as usize)
+        .expect("Cannot create the init process");
+    assert_eq!(init, INIT_PID);
+    scheduler
+        .spawn(process::process1 as *const () as usize)
+        .expect("Cannot create a process");
+    scheduler
+        .spawn(process::process2 as *const () as usize)
+        .expect("Cannot create a process");
+    scheduler
+        .spawn_user(user::counter as *const () as usize)
+        .expect("Cannot create a user process");
+    scheduler
+        .spawn_user(user::faulty as *const () as usize)
+        .expect("Cannot create a user process");
+    scheduler
+        .spawn_user(user::hello as *const () as usize)
+        .expect("Cannot create a user process");
+
+    // We are already running as init, its frame gets filled on the first trap
+    scheduler.run_queue.retain(|pid| *pid != INIT_PID);
+    scheduler.switch_to(INIT_PID);
 }
 
-pub fn init_sanity_check() {}
+pub fn init_sanity_check() {
+    let free_before = page_allocator::free_pages();
+
+    let pid = spawn(process::process1 as *const () as usize).expect("Cannot spawn a process");
+    assert!(pid > INIT_PID, "Pid was reused");
+    assert!(get(pid).is_some(), "Spawned process is not in the table");
+    assert!(pids().contains(&pid));
+
+    // Reaping gives the stack back and takes the process off the run queue
+    assert!(reap(pid), "Cannot reap a process");
+    assert!(get(pid).is_none(), "Reaped process is still in the table");
+    assert!(!scheduler().run_queue.contains(&pid));
+    assert_eq!(
+        page_allocator::free_pages(),
+        free_before,
+        "Process leaked pages"
+    );
+
+    // The running process cannot be reaped
+    assert_eq!(current_pid(), Some(INIT_PID));
+    assert!(!reap(INIT_PID));
+
+    // A killed child stays a zombie until its parent collects the exit code
+    let free_before = page_allocator::free_pages();
+    let child = spawn(process::process1 as *const () as usize).expect("Cannot spawn a process");
+    assert_eq!(get(child).unwrap().parent(), Some(INIT_PID));
+    assert!(kill(child), "Cannot kill a process");
+    assert_eq!(get(child).unwrap().state(), ProcessState::Zombie);
+    assert_eq!(get(child).unwrap().exit_code(), process::KILLED_EXIT_CODE);
+    assert!(!scheduler().run_queue.contains(&child));
+    assert!(!kill(child), "A zombie was killed twice");
+
+    assert_eq!(
+        try_wait(Some(child)),
+        Ok(Some((child, process::KILLED_EXIT_CODE)))
+    );
+    assert!(get(child).is_none(), "Waited process is still in the table");
+    assert_eq!(try_wait(Some(child)), Err(WaitError::NoChild));
+    assert_eq!(
+        page_allocator::free_pages(),
+        free_before,
+        "Exited process leaked pages"
+    );
+
+    // Children of an exiting process do not wait for anyone
+    let parent = spawn(process::process1 as *const () as usize).expect("Cannot spawn a process");
+    let orphan = scheduler()
+        .spawn_child(process::process1 as *const () as usize, Some(parent))
+        .expect("Cannot spawn a process");
+    assert!(kill(parent));
+    assert_eq!(get(orphan).unwrap().parent(), None);
+    assert!(kill(orphan));
+    assert!(get(orphan).is_none(), "Orphan zombie was not reaped");
+    assert_eq!(
+        try_wait(Some(parent)).map(|result| result.is_some()),
+        Ok(true)
+    );
+
+    // User processes get their own address space, freed when they are gone
+    let free_before = page_allocator::free_pages();
+    let pid = spawn_user(user::counter as *const () as usize).expect("Cannot spawn a process");
+    let process = get(pid).unwrap();
+    assert_eq!(process.mode(), Mode::User);
+    assert!(!unsafe { paging::is_kernel_range(process.page_table(), process::USER_TEXT_BASE) });
+    let entry = unsafe { paging::translate(process.page_table(), process.pc()) };
+    assert_eq!(entry, Some(user::counter as *const () as usize));
+    assert!(
+        unsafe { paging::translate(process.page_table(), process::USER_STACK_TOP - 8) }.is_some()
+    );
+    assert!(kill(pid));
+    assert_eq!(
+        try_wait(Some(pid)),
+        Ok(Some((pid, process::KILLED_EXIT_CODE)))
+    );
+    assert_eq!(
+        page_allocator::free_pages(),
+        free_before,
+        "User process leaked pages"
+    );
+}
 
 impl Scheduler {
-    pub fn new_scheduler() -> Self {
+    pub const fn empty() -> Self {
         Scheduler {
-            init: Process::new_process(kmain as usize),
-            proc1: Process::new_process(process::process1 as usize),
-            proc2: Process::new_process(process::process2 as usize),
-            counter: 0,
+            processes: BTreeMap::new(),
+            run_queue: VecDeque::new(),
+            current: None,
+            next_pid: INIT_PID,
+            idle: None,
         }
     }
 
+    // Create a child of the running process starting at entry and queue it,
+    // None when out of memory
+    pub fn spawn(&mut self, entry: usize) -> Option<Pid> {
+        self.spawn_child(entry, self.current)
+    }
+
+    // Same as spawn for a function of the user module, run in user mode
+    pub fn spawn_user(&mut self, entry: usize) -> Option<Pid> {
+        let pid = self.next_pid;
+        self.insert(Process::new_user_process(pid, self.current, entry))
+    }
+
+    fn spawn_child(&mut self, entry: usize, parent: Option<Pid>) -> Option<Pid> {
+        let pid = self.next_pid;
+        self.insert(Process::new_process(pid, parent, entry))
+    }
+
+    fn insert(&mut self, process: Process) -> Option<Pid> {
+        let pid = process.pid();
+        if process.stack().is_null() {
+            return None;
+        }
+
+        self.next_pid += 1;
+        self.processes.insert(pid, Box::new(process));
+        self.run_queue.push_back(pid);
+        Some(pid)
+    }
+
+    // Remove a process that is not running, its memory is freed
+    pub fn reap(&mut self, pid: Pid) -> bool {
+        if self.current == Some(pid) || !self.processes.contains_key(&pid) {
+            return false;
+        }
+
+        self.run_queue.retain(|queued| *queued != pid);
+        self.processes.remove(&pid);
+        true
+    }
+
+    // Put the running process at the end of the queue and switch to the first one
+    //
+    // # Safety
+    // Must be called from the trap handler or a system call with interrupts
+    // disabled, the frame of the hart is replaced by the one of the next process.
     pub unsafe fn next(&mut self) {
-        match self.counter % 3 {
-            0 => Self::propagate_decision(&raw const self.proc1 as usize),
-            1 => Self::propagate_decision(&raw const self.proc2 as usize),
-            2 => Self::propagate_decision(&raw const self.init as usize),
-            _ => unreachable!(),
+        self.reap_orphans();
+        self.wake_sleepers();
+
+        let next = match self.run_queue.pop_front() {
+            Some(pid) => pid,
+            None => return,
+        };
+
+        if let Some(current) = self.current {
+            if self.state(current) == Some(ProcessState::Running) {
+                self.set_state(current, ProcessState::Ready);
+                self.run_queue.push_back(current);
+            }
+        }
+        self.switch_to(next);
+    }
+
+    // Switch away from a running process that stopped being runnable, to the
+    // idle process when no other one is ready
+    fn schedule_away(&mut self) {
+        self.wake_sleepers();
+        match self.run_queue.pop_front() {
+            Some(next) => self.switch_to(next),
+            None => self.switch_to_idle(),
+        }
+    }
+
+    fn switch_to_idle(&mut self) {
+        let idle = self.idle.as_mut().expect("No idle process");
+        idle.state = ProcessState::Running;
+        idle.activate();
+        self.current = Some(IDLE_PID);
+        Self::propagate_decision(&**idle as *const Process as usize);
+    }
+
+    fn switch_to(&mut self, pid: Pid) {
+        if let Some(previous) = self.current {
+            if previous != pid && self.state(previous) == Some(ProcessState::Running) {
+                self.set_state(previous, ProcessState::Ready);
+            }
+        }
+
+        let process = self
+            .processes
+            .get_mut(&pid)
+            .expect("Switching to a missing process");
+        process.state = ProcessState::Running;
+        process.activate();
+        self.current = Some(pid);
+        Self::propagate_decision(&**process as *const Process as usize);
+    }
+
+    fn state(&self, pid: Pid) -> Option<ProcessState> {
+        self.processes.get(&pid).map(|process| process.state)
+    }
+
+    fn set_state(&mut self, pid: Pid, state: ProcessState) {
+        if let Some(process) = self.processes.get_mut(&pid) {
+            process.state = state;
+        }
+    }
+
+    // Turn a process into a zombie. Its children lose their parent and the
+    // parent is woken up when it waits for it. The running process keeps its
+    // stack until another one runs, we are still on it.
+    fn terminate(&mut self, pid: Pid, code: i32) -> bool {
+        let process = match self.processes.get_mut(&pid) {
+            Some(process) if process.state != ProcessState::Zombie => process,
+            _ => return false,
+        };
+        process.state = ProcessState::Zombie;
+        process.exit_code = code;
+        process.files.close_all();
+        let parent = process.parent;
+        if self.current != Some(pid) {
+            process.release_memory();
+        }
+        self.run_queue.retain(|queued| *queued != pid);
+
+        for child in self.processes.values_mut() {
+            if child.parent == Some(pid) {
+                child.parent = None;
+            }
+        }
+
+        if let Some(parent) = parent {
+            let waiting = self.processes.get(&parent).is_some_and(|parent| {
+                parent.state == ProcessState::Blocked
+                    && parent.wait_target.is_none_or(|target| target == pid)
+            });
+            if waiting {
+                let result = self.collect(pid);
+                let parent_process = self.processes.get_mut(&parent).unwrap();
+                process::set_wait_result(parent_process, Ok(result));
+                parent_process.state = ProcessState::Ready;
+                parent_process.wait_target = None;
+                self.run_queue.push_back(parent);
+            }
+        }
+
+        self.reap_orphans();
+        true
+    }
+
+    // Take the exit code of a zombie, it is removed once it no longer runs
+    fn collect(&mut self, pid: Pid) -> (Pid, i32) {
+        let process = self.processes.get_mut(&pid).unwrap();
+        process.parent = None;
+        let code = process.exit_code;
+        if self.current != Some(pid) {
+            self.processes.remove(&pid);
+        }
+        (pid, code)
+    }
+
+    // Zombies nobody will wait for
+    fn reap_orphans(&mut self) {
+        let current = self.current;
+        self.processes.retain(|pid, process| {
+            Some(*pid) == current
+                || process.state != ProcessState::Zombie
+                || process.parent.is_some()
+        });
+    }
+
+    // Collect an exited child of the running process without blocking, None
+    // when the matching children are all alive
+    pub fn try_wait(&mut self, target: Option<Pid>) -> Result<Option<(Pid, i32)>, WaitError> {
+        let current = self.current;
+        let children = || {
+            self.processes.values().filter(move |process| {
+                process.parent.is_some()
+                    && process.parent == current
+                    && target.is_none_or(|target| target == process.pid())
+            })
+        };
+        let has_children = children().next().is_some();
+        let zombie = children()
+            .find(|process| process.state == ProcessState::Zombie)
+            .map(|process| process.pid());
+
+        match zombie {
+            Some(pid) => Ok(Some(self.collect(pid))),
+            None if has_children => Ok(None),
+            None => Err(WaitError::NoChild),
+        }
+    }
+
+    // Wait syscall of the running process, it is blocked until a child exits
+    fn wait_current(&mut self, target: Option<Pid>) {
+        let current = self.current.expect("No process is running");
+        let result = match self.try_wait(target) {
+            Ok(Some(result)) => Ok(result),
+            Ok(None) => {
+                let process = self.processes.get_mut(&current).unwrap();
+                process.state = ProcessState::Blocked;
+                process.wait_target = target;
+                self.schedule_away();
+                return;
+            }
+            Err(error) => Err(error),
+        };
+        process::set_wait_result(self.processes.get_mut(&current).unwrap(), result);
+    }
+
+    // Sleep syscall of the running process, it is off the run queue until the
+    // timer reaches deadline
+    fn sleep_current(&mut self, deadline: u64) {
+        let current = self.current.expect("No process is running");
+        let process = self.processes.get_mut(&current).unwrap();
+        process.state = ProcessState::Sleeping;
+        process.wake_at = deadline;
+        self.schedule_away();
+    }
+
+    // Read syscall of the running process on the console, it is off the run
+    // queue until input comes
+    fn read_console_current(&mut self, address: usize, count: usize) {
+        let current = self.current.expect("No process is running");
+        let process = self.processes.get_mut(&current).unwrap();
+        process.state = ProcessState::Reading;
+        process.console_read = (address, count);
+        self.schedule_away();
+    }
+
+    // Give the console input to the processes waiting for it
+    fn wake_readers(&mut self) {
+        for process in self.processes.values_mut() {
+            if process.state == ProcessState::Reading && process::finish_console_read(process) {
+                process.state = ProcessState::Ready;
+                self.run_queue.push_back(process.pid());
+            }
+        }
+    }
+
+    // Queue the sleeping processes whose deadline has passed
+    fn wake_sleepers(&mut self) {
+        let now = reg::time_read();
+        for process in self.processes.values_mut() {
+            if process.state == ProcessState::Sleeping && process.wake_at <= now {
+                process.state = ProcessState::Ready;
+                self.run_queue.push_back(process.pid());
+            }
         }
+    }
+
+    // Exception raised by the running user process, it is terminated
+    fn fault_current(&mut self, fault: Fault) {
+        let current = self.current.expect("No process is running");
+        self.processes.get_mut(&current).unwrap().fault = Some(fault);
+        self.exit_current(process::FAULT_EXIT_CODE);
+    }
 
-        self.counter += 1;
+    // Exit syscall of the running process
+    fn exit_current(&mut self, code: i32) {
+        let current = self.current.expect("No process is running");
+        assert!(current != INIT_PID, "The init process exited");
+        self.terminate(current, code);
+        self.schedule_away();
     }
 
     pub(crate) fn propagate_decision(value: usize) {
         unsafe {
             asm!(
-            "csrw mscratch, {0}",
+            "csrw sscratch, {0}",
             in(reg) value
             );
         }
     }
 }
+
+pub fn spawn(entry: usize) -> Option<Pid> {
+    lock::without_interrupts(|| scheduler().spawn(entry))
+}
+
+pub fn spawn_user(entry: usize) -> Option<Pid> {
+    lock::without_interrupts(|| scheduler().spawn_user(entry))
+}
+
+pub fn reap(pid: Pid) -> bool {
+    lock::without_interrupts(|| scheduler().reap(pid))
+}
+
+// Terminate a process with KILLED_EXIT_CODE, false when it is missing or
+// already exited. Init cannot be killed and killing the running process does
+// not return.
+pub fn kill(pid: Pid) -> bool {
+    if pid == INIT_PID {
+        return false;
+    }
+    if current_pid() == Some(pid) {
+        process::exit(process::KILLED_EXIT_CODE);
+    }
+    lock::without_interrupts(|| scheduler().terminate(pid, process::KILLED_EXIT_CODE))
+}
+
+pub fn try_wait(target: Option<Pid>) -> Result<Option<(Pid, i32)>, WaitError> {
+    lock::without_interrupts(|| scheduler().try_wait(target))
+}
+
+// Called from the timer interrupt and the yield system call
+pub fn next() {
+    unsafe { scheduler().next() }
+}
+
+// Called from a system call, switch to another process
+pub(crate) fn exit_current(code: i32) {
+    scheduler().exit_current(code)
+}
+
+// Called from the trap handler, switch to another process
+pub(crate) fn fault_current(fault: Fault) {
+    scheduler().fault_current(fault)
+}
+
+// Called from a system call, may switch to another process
+pub(crate) fn wait_current(target: Option<Pid>) {
+    scheduler().wait_current(target)
+}
+
+// Called from a system call, switch to another process
+pub(crate) fn sleep_current(deadline: u64) {
+    scheduler().sleep_current(deadline)
+}
+
+// Called from a system call, switch to another process
+pub(crate) fn read_console_current(address: usize, count: usize) {
+    scheduler().read_console_current(address, count)
+}
+
+// Called from the uart interrupt once the input is buffered, a woken reader
+// replaces the idle process right away
+pub(crate) fn wake_readers() {
+    let scheduler = scheduler();
+    scheduler.wake_readers();
+    if scheduler.current == Some(IDLE_PID) {
+        unsafe { scheduler.next() }
+    }
+}
+
+// Wait for interrupts with interrupts enabled, the timer interrupt switches
+// to the processes it wakes up
+fn idle() {
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+// The reference dangles once the process is reaped, which can happen on any
+// interrupt. Only the boot checks use it, the others go through with_process.
+fn get(pid: Pid) -> Option<&'static Process> {
+    scheduler().processes.get(&pid).map(|process| &**process)
+}
+
+// Run f on a process with interrupts disabled, so that it cannot go away
+// meanwhile. f should copy what it needs out.
+pub fn with_process<T, F: FnOnce(&Process) -> T>(pid: Pid, f: F) -> Option<T> {
+    lock::without_interrupts(|| get(pid).map(f))
+}
+
+pub fn pids() -> Vec<Pid> {
+    lock::without_interrupts(|| scheduler().processes.keys().copied().collect())
+}
+
+pub fn current_pid() -> Option<Pid> {
+    scheduler().current
+}
+
+pub fn count() -> usize {
+    scheduler().processes.len()
+}
@@ -0,0 +1,252 @@
+use crate::lock;
+use crate::page_allocator::{self, PAGE_SIZE};
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+
+const MAX_CACHES: usize = 32;
+
+// Each slab is a single page starting with this header, objects follow it
+struct Slab {
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    slabs: *mut Slab,
+    slab_count: usize,
+    objects_in_use: usize,
+    registered: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+}
+
+// Caches that allocated at least once, used to report statistics
+static mut CACHES: [*const SlabCache; MAX_CACHES] = [null_mut(); MAX_CACHES];
+static mut CACHE_COUNT: usize = 0;
+
+const fn align_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < align_of::<FreeObject>() {
+            align_of::<FreeObject>()
+        } else {
+            align
+        };
+        let size = if size < size_of::<FreeObject>() {
+            size_of::<FreeObject>()
+        } else {
+            size
+        };
+
+        SlabCache {
+            name,
+            object_size: align_up(size, align),
+            align,
+            slabs: null_mut(),
+            slab_count: 0,
+            objects_in_use: 0,
+            registered: false,
+        }
+    }
+
+    pub const fn for_type<T>(name: &'static str) -> Self {
+        Self::new(name, size_of::<T>(), align_of::<T>())
+    }
+
+    fn first_object_offset(&self) -> usize {
+        align_up(size_of::<Slab>(), self.align)
+    }
+
+    pub fn objects_per_slab(&self) -> usize {
+        (PAGE_SIZE - self.first_object_offset()) / self.object_size
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    unsafe fn grow(&mut self) -> *mut Slab {
+        let slab = page_allocator::alloc(1) as *mut Slab;
+        if slab.is_null() {
+            return slab;
+        }
+
+        // Thread every object of the page on the free list
+        let first = slab as usize + self.first_object_offset();
+        let count = self.objects_per_slab();
+        for i in 0..count {
+            let object = (first + i * self.object_size) as *mut FreeObject;
+            (*object).next = if i + 1 < count {
+                (first + (i + 1) * self.object_size) as *mut FreeObject
+            } else {
+                null_mut()
+            };
+        }
+
+        (*slab).free = first as *mut FreeObject;
+        (*slab).in_use = 0;
+        (*slab).next = self.slabs;
+        self.slabs = slab;
+        self.slab_count += 1;
+
+        if !self.registered {
+            self.register();
+        }
+
+        slab
+    }
+
+    unsafe fn register(&mut self) {
+        if CACHE_COUNT < MAX_CACHES {
+            CACHES[CACHE_COUNT] = self as *const SlabCache;
+            CACHE_COUNT += 1;
+        }
+        self.registered = true;
+    }
+
+    pub fn alloc(&mut self) -> *mut u8 {
+        assert!(
+            self.objects_per_slab() > 0,
+            "Objects of cache {} do not fit in a slab",
+            self.name
+        );
+
+        lock::without_interrupts(|| unsafe {
+            let mut slab = self.slabs;
+            while !slab.is_null() && (*slab).free.is_null() {
+                slab = (*slab).next;
+            }
+
+            if slab.is_null() {
+                slab = self.grow();
+                if slab.is_null() {
+                    return null_mut();
+                }
+            }
+
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            self.objects_in_use += 1;
+
+            object as *mut u8
+        })
+    }
+
+    pub fn free(&mut self, pointer: *mut u8) {
+        // Safety assertion
+        assert!(!pointer.is_null());
+
+        lock::without_interrupts(|| unsafe {
+            let slab = (pointer as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+            assert!(
+                (pointer as usize - slab as usize - self.first_object_offset()) % self.object_size
+                    == 0,
+                "Pointer does not belong to cache {}",
+                self.name
+            );
+
+            let object = pointer as *mut FreeObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            self.objects_in_use -= 1;
+
+            // Give empty slabs back to the page allocator, but keep the last one around
+            if (*slab).in_use == 0 && self.slab_count > 1 {
+                self.release(slab);
+            }
+        })
+    }
+
+    unsafe fn release(&mut self, slab: *mut Slab) {
+        let mut link = &raw mut self.slabs;
+        while !(*link).is_null() {
+            if *link == slab {
+                *link = (*slab).next;
+                self.slab_count -= 1;
+                page_allocator::dealloc(slab as *mut u8);
+                return;
+            }
+            link = &raw mut (**link).next;
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            objects_per_slab: self.objects_per_slab(),
+            slabs: self.slab_count,
+            objects_in_use: self.objects_in_use,
+        }
+    }
+}
+
+// Call f with the statistics of every cache in use
+pub fn for_each_cache<F: FnMut(SlabStats)>(mut f: F) {
+    unsafe {
+        for i in 0..CACHE_COUNT {
+            f((*CACHES[i]).stats());
+        }
+    }
+}
+
+#[allow(dead_code)]
+struct SanityObject {
+    value: u64,
+    other: [u8; 40],
+}
+
+static mut SANITY_CACHE: SlabCache = SlabCache::for_type::<SanityObject>("sanity");
+
+pub fn init_sanity_check() {
+    let cache = unsafe { &mut *(&raw mut SANITY_CACHE) };
+    assert!(cache.object_size() >= size_of::<SanityObject>());
+
+    let first = cache.alloc();
+    let second = cache.alloc();
+    assert!(!first.is_null() && !second.is_null());
+    assert_ne!(first, second, "Slab handed out the same object twice");
+    assert_eq!(first as usize % align_of::<SanityObject>(), 0);
+    assert_eq!(cache.stats().objects_in_use, 2);
+
+    // A freed object is handed out again
+    cache.free(second);
+    let third = cache.alloc();
+    assert_eq!(second, third, "Freed object is not reused");
+    cache.free(first);
+    cache.free(third);
+
+    // Fill more than one slab and give everything back
+    let mut objects = [null_mut(); 128];
+    let count = (cache.objects_per_slab() + 1).min(objects.len());
+    for object in objects.iter_mut().take(count) {
+        *object = cache.alloc();
+    }
+    assert_eq!(cache.stats().slabs, 2);
+    for object in objects.iter().take(count) {
+        cache.free(*object);
+    }
+
+    assert_eq!(cache.stats().objects_in_use, 0);
+    assert_eq!(cache.stats().slabs, 1, "Empty slabs are not released");
+}
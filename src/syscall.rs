@@ -0,0 +1,616 @@
+// System calls. A process puts the number in a7 and up to six arguments in a0
+// to a5, the result comes back in a0. Numbers follow Linux on riscv64 and
+// failures return a negated errno.
+
+use crate::page_allocator::{self, PAGE_SIZE};
+use crate::paging::EntryBits;
+use crate::process::{self, Mode, Process, FRAME_A0, FRAME_A5, FRAME_A7};
+use crate::scheduler::{self, INIT_PID};
+use crate::vfs::{self, VfsError};
+use crate::{lock, platform, reg, uart, user};
+use core::arch::asm;
+use core::fmt::Write;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const SYS_OPENAT: usize = 56;
+pub const SYS_CLOSE: usize = 57;
+pub const SYS_READ: usize = 63;
+pub const SYS_WRITE: usize = 64;
+pub const SYS_SYNC: usize = 81;
+pub const SYS_FSYNC: usize = 82;
+pub const SYS_EXIT: usize = 93;
+pub const SYS_NANOSLEEP: usize = 101;
+pub const SYS_SCHED_YIELD: usize = 124;
+pub const SYS_GETPID: usize = 172;
+pub const SYS_BRK: usize = 214;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_WAIT: usize = 260;
+
+pub const EPERM: usize = 1;
+pub const ENOENT: usize = 2;
+pub const EIO: usize = 5;
+pub const EBADF: usize = 9;
+pub const ECHILD: usize = 10;
+pub const EAGAIN: usize = 11;
+pub const ENOMEM: usize = 12;
+pub const EFAULT: usize = 14;
+pub const EBUSY: usize = 16;
+pub const EEXIST: usize = 17;
+pub const EXDEV: usize = 18;
+pub const ENODEV: usize = 19;
+pub const ENOTDIR: usize = 20;
+pub const EISDIR: usize = 21;
+pub const EINVAL: usize = 22;
+pub const EMFILE: usize = 24;
+pub const ENOSPC: usize = 28;
+pub const EROFS: usize = 30;
+pub const ENAMETOOLONG: usize = 36;
+pub const ENOSYS: usize = 38;
+pub const ENOTEMPTY: usize = 39;
+pub const ELOOP: usize = 40;
+pub const EOPNOTSUPP: usize = 95;
+
+// The only directory openat accepts, paths must be absolute
+pub const AT_FDCWD: isize = -100;
+
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+// Standard streams the process did not open go to the console
+const STDERR: usize = 2;
+
+const MAX_PATH: usize = 256;
+// Bytes moved between the process and a file at a time
+pub(crate) const CHUNK_SIZE: usize = 256;
+
+// Errno on failure. None when the handler wrote the frame itself or the
+// process does not come back.
+type SyscallResult = Option<Result<usize, usize>>;
+type Handler = fn(&mut Process, [usize; 6]) -> SyscallResult;
+
+struct Syscall {
+    number: usize,
+    name: &'static str,
+    handler: Handler,
+}
+
+static SYSCALLS: [Syscall; 13] = [
+    Syscall {
+        number: SYS_OPENAT,
+        name: "openat",
+        handler: sys_openat,
+    },
+    Syscall {
+        number: SYS_CLOSE,
+        name: "close",
+        handler: sys_close,
+    },
+    Syscall {
+        number: SYS_READ,
+        name: "read",
+        handler: sys_read,
+    },
+    Syscall {
+        number: SYS_WRITE,
+        name: "write",
+        handler: sys_write,
+    },
+    Syscall {
+        number: SYS_SYNC,
+        name: "sync",
+        handler: sys_sync,
+    },
+    Syscall {
+        number: SYS_FSYNC,
+        name: "fsync",
+        handler: sys_fsync,
+    },
+    Syscall {
+        number: SYS_EXIT,
+        name: "exit",
+        handler: sys_exit,
+    },
+    Syscall {
+        number: SYS_NANOSLEEP,
+        name: "nanosleep",
+        handler: sys_nanosleep,
+    },
+    Syscall {
+        number: SYS_SCHED_YIELD,
+        name: "sched_yield",
+        handler: sys_sched_yield,
+    },
+    Syscall {
+        number: SYS_GETPID,
+        name: "getpid",
+        handler: sys_getpid,
+    },
+    Syscall {
+        number: SYS_BRK,
+        name: "brk",
+        handler: sys_brk,
+    },
+    Syscall {
+        number: SYS_MMAP,
+        name: "mmap",
+        handler: sys_mmap,
+    },
+    Syscall {
+        number: SYS_WAIT,
+        name: "wait4",
+        handler: sys_wait,
+    },
+];
+
+pub fn name(number: usize) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .find(|syscall| syscall.number == number)
+        .map(|syscall| syscall.name)
+}
+
+pub fn count() -> usize {
+    SYSCALLS.len()
+}
+
+// Handle the system call the running process trapped with
+pub fn handle() {
+    let process = process::current();
+    let number = process.register(FRAME_A7);
+    let mut args = [0; 6];
+    for (arg, index) in args.iter_mut().zip(FRAME_A0..=FRAME_A5) {
+        *arg = process.register(index);
+    }
+
+    if let Some(value) = dispatch(process, number, args) {
+        process.set_register(FRAME_A0, value as usize);
+    }
+}
+
+// Run a system call for process, which must be the running one. Returns what
+// goes in a0, if anything.
+fn dispatch(process: &mut Process, number: usize, args: [usize; 6]) -> Option<isize> {
+    let result = match SYSCALLS.iter().find(|syscall| syscall.number == number) {
+        Some(syscall) => (syscall.handler)(process, args)?,
+        None => {
+            println!(
+                "Process {} made an unknown system call {}",
+                process.pid(),
+                number
+            );
+            Err(ENOSYS)
+        }
+    };
+
+    Some(match result {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    })
+}
+
+pub fn errno(error: VfsError) -> usize {
+    match error {
+        VfsError::NotFound => ENOENT,
+        VfsError::NotADirectory => ENOTDIR,
+        VfsError::IsADirectory => EISDIR,
+        VfsError::AlreadyExists => EEXIST,
+        VfsError::DirectoryNotEmpty => ENOTEMPTY,
+        VfsError::InvalidPath | VfsError::InvalidArgument => EINVAL,
+        VfsError::ReadOnly => EROFS,
+        VfsError::NoSpace => ENOSPC,
+        VfsError::BadDescriptor => EBADF,
+        VfsError::TooManyOpenFiles => EMFILE,
+        VfsError::TooManySymlinks => ELOOP,
+        VfsError::CrossDevice => EXDEV,
+        VfsError::Busy => EBUSY,
+        VfsError::Unsupported => EOPNOTSUPP,
+        VfsError::IoError => EIO,
+    }
+}
+
+//----------- Handlers ---------------//
+
+fn is_console(process: &Process, fd: usize) -> bool {
+    fd <= STDERR && !process.files.is_open(fd)
+}
+
+// Null terminated string in the memory of the process
+fn read_string(process: &Process, address: usize) -> Result<String, usize> {
+    let mut bytes = Vec::new();
+    loop {
+        let mut byte = [0u8];
+        let byte_address = address.checked_add(bytes.len()).ok_or(EFAULT)?;
+        if !process.read_memory(byte_address, &mut byte) {
+            return Err(EFAULT);
+        }
+        if byte[0] == 0 {
+            break;
+        }
+        if bytes.len() == MAX_PATH {
+            return Err(ENAMETOOLONG);
+        }
+        bytes.push(byte[0]);
+    }
+    String::from_utf8(bytes).map_err(|_| EINVAL)
+}
+
+// The directory is ignored, there is no working directory
+fn sys_openat(process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    let path = match read_string(process, args[1]) {
+        Ok(path) => path,
+        Err(errno) => return Some(Err(errno)),
+    };
+    Some(vfs::open(&path, args[2] as u32).map_err(errno))
+}
+
+fn sys_close(_process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    Some(vfs::close(args[0]).map(|_| 0).map_err(errno))
+}
+
+// Console reads wait until something is typed
+fn sys_read(process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    let (fd, address, count) = (args[0], args[1], args[2]);
+    if is_console(process, fd) && count > 0 && !uart::has_input() {
+        scheduler::read_console_current(address, count);
+        return None;
+    }
+
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(CHUNK_SIZE);
+        let read = if is_console(process, fd) {
+            uart::read_input(&mut buffer[..len])
+        } else {
+            match vfs::read(fd, &mut buffer[..len]) {
+                Ok(read) => read,
+                Err(error) if done == 0 => return Some(Err(errno(error))),
+                Err(_) => break,
+            }
+        };
+        if !process.write_memory(address + done, &buffer[..read]) {
+            return Some(Err(EFAULT));
+        }
+
+        done += read;
+        if read < len {
+            break;
+        }
+    }
+    Some(Ok(done))
+}
+
+fn sys_write(process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    let (fd, address, count) = (args[0], args[1], args[2]);
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(CHUNK_SIZE);
+        if !process.read_memory(address + done, &mut buffer[..len]) {
+            return Some(Err(EFAULT));
+        }
+
+        let written = if is_console(process, fd) {
+            uart::write_bytes(&buffer[..len]);
+            len
+        } else {
+            match vfs::write(fd, &buffer[..len]) {
+                Ok(written) => written,
+                Err(error) if done == 0 => return Some(Err(errno(error))),
+                Err(_) => break,
+            }
+        };
+
+        done += written;
+        if written < len {
+            break;
+        }
+    }
+    Some(Ok(done))
+}
+
+fn sys_sync(_process: &mut Process, _args: [usize; 6]) -> SyscallResult {
+    Some(vfs::sync().map(|_| 0).map_err(errno))
+}
+
+fn sys_fsync(_process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    Some(vfs::fsync(args[0]).map(|_| 0).map_err(errno))
+}
+
+fn sys_exit(_process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    scheduler::exit_current(args[0] as i32);
+    None
+}
+
+// Takes a struct timespec. Sleepers are woken up by the timer interrupt, so
+// they may oversleep by up to one tick.
+fn sys_nanosleep(process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    let mut request = [0u8; 16];
+    if !process.read_memory(args[0], &mut request) {
+        return Some(Err(EFAULT));
+    }
+    let seconds = i64::from_le_bytes(request[..8].try_into().unwrap());
+    let nanoseconds = i64::from_le_bytes(request[8..].try_into().unwrap());
+    if seconds < 0 || !(0..1_000_000_000).contains(&nanoseconds) {
+        return Some(Err(EINVAL));
+    }
+
+    let timebase = platform::get().timebase;
+    let ticks = (seconds as u64)
+        .saturating_mul(timebase)
+        .saturating_add(nanoseconds as u64 * timebase / 1_000_000_000);
+    scheduler::sleep_current(reg::time_read().saturating_add(ticks));
+    Some(Ok(0))
+}
+
+fn sys_sched_yield(_process: &mut Process, _args: [usize; 6]) -> SyscallResult {
+    scheduler::next();
+    Some(Ok(0))
+}
+
+fn sys_getpid(process: &mut Process, _args: [usize; 6]) -> SyscallResult {
+    Some(Ok(process.pid()))
+}
+
+// Move the end of the heap and return it, the heap is not moved when the
+// address is out of range or memory runs out. Pages stay mapped when it
+// shrinks.
+fn sys_brk(process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    if process.mode() != Mode::User {
+        return Some(Err(ENOMEM));
+    }
+
+    let request = args[0];
+    if !(process::USER_HEAP_BASE..=process::USER_MMAP_BASE).contains(&request) {
+        return Some(Ok(process.brk));
+    }
+    if request > process.brk
+        && !process.map_pages(process.brk, request, EntryBits::UserReadWrite.val())
+    {
+        return Some(Ok(process.brk));
+    }
+    process.brk = request;
+    Some(Ok(request))
+}
+
+// Only anonymous mappings, the address hint is ignored
+fn sys_mmap(process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    let (len, prot, flags) = (args[1], args[2], args[3]);
+    if process.mode() != Mode::User {
+        return Some(Err(ENOMEM));
+    }
+    if flags & MAP_ANONYMOUS == 0 {
+        return Some(Err(ENODEV));
+    }
+    if len == 0 || prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0 {
+        return Some(Err(EINVAL));
+    }
+
+    let mut bits = EntryBits::User.val() | EntryBits::Read.val();
+    if prot & PROT_WRITE != 0 {
+        bits |= EntryBits::Write.val();
+    }
+    if prot & PROT_EXEC != 0 {
+        bits |= EntryBits::Execute.val();
+    }
+
+    let start = process.mmap_next;
+    let end = match start.checked_add(page_allocator::page_align_round_up(len)) {
+        Some(end) if end <= process::USER_MMAP_END => end,
+        _ => return Some(Err(ENOMEM)),
+    };
+    // Partly mapped ranges are not reused, they are freed with the process
+    process.mmap_next = end;
+    if !process.map_pages(start, end, bits) {
+        return Some(Err(ENOMEM));
+    }
+    Some(Ok(start))
+}
+
+// Like wait4, the status of the child is written where a1 points unless it is
+// null. Options and resource usage are not supported.
+fn sys_wait(process: &mut Process, args: [usize; 6]) -> SyscallResult {
+    let target = match args[0] {
+        usize::MAX => None,
+        pid => Some(pid),
+    };
+    process.wait_status = args[1];
+    scheduler::wait_current(target);
+    None
+}
+
+//----------- Calls from kernel processes ---------------//
+
+// Kernel processes run in supervisor mode where ecall goes to the SBI
+// firmware, they call the dispatcher directly with interrupts masked. When
+// the call switches to another process the caller is saved and gets a0 of its
+// frame once it runs again. Returns a0.
+pub fn call(number: usize, args: [usize; 6]) -> usize {
+    lock::without_interrupts(|| {
+        let process = process::current();
+        let caller = process as *mut Process;
+        if let Some(value) = dispatch(process, number, args) {
+            process.set_register(FRAME_A0, value as usize);
+        }
+
+        if reg::sscratch_read() == caller as usize {
+            process.register(FRAME_A0)
+        } else {
+            unsafe { switch_from(caller) }
+        }
+    })
+}
+
+// Save the calling kernel process in its frame like the trap vector would and
+// resume the process installed in sscratch. Only the registers preserved
+// across calls are saved, the caller continues at the label.
+//
+// # Safety
+// process must be the frame of the caller, another process must be installed
+// in sscratch and interrupts must be disabled.
+unsafe fn switch_from(process: *mut Process) -> usize {
+    let result;
+    asm!(
+        "sd		sp, 8(a0)",
+        "sd		gp, 16(a0)",
+        "sd		tp, 24(a0)",
+        "sd		s0, 56(a0)",
+        "sd		s1, 64(a0)",
+        "sd		s2, 136(a0)",
+        "sd		s3, 144(a0)",
+        "sd		s4, 152(a0)",
+        "sd		s5, 160(a0)",
+        "sd		s6, 168(a0)",
+        "sd		s7, 176(a0)",
+        "sd		s8, 184(a0)",
+        "sd		s9, 192(a0)",
+        "sd		s10, 200(a0)",
+        "sd		s11, 208(a0)",
+        // Saved pc, where the caller is resumed
+        "la		t0, 1f",
+        "sd		t0, 264(a0)",
+        // Processes are resumed with interrupts enabled (SPIE=1)
+        "li		t0, 1 << 5",
+        "csrs	sstatus, t0",
+        "la		t0, asm_resume_process",
+        "jr		t0",
+        "1:",
+        inlateout("a0") process => result,
+        clobber_abi("C"),
+    );
+    result
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> isize {
+    call(SYS_WRITE, [fd, buf.as_ptr() as usize, buf.len(), 0, 0, 0]) as isize
+}
+
+pub fn getpid() -> usize {
+    call(SYS_GETPID, [0; 6])
+}
+
+pub fn sched_yield() {
+    call(SYS_SCHED_YIELD, [0; 6]);
+}
+
+pub fn sleep(nanoseconds: u64) -> isize {
+    let request = [
+        (nanoseconds / 1_000_000_000) as i64,
+        (nanoseconds % 1_000_000_000) as i64,
+    ];
+    call(SYS_NANOSLEEP, [request.as_ptr() as usize, 0, 0, 0, 0, 0]) as isize
+}
+
+pub fn init_sanity_check() {
+    assert_eq!(name(SYS_WRITE), Some("write"));
+    for (index, syscall) in SYSCALLS.iter().enumerate() {
+        assert!(
+            SYSCALLS[index + 1..]
+                .iter()
+                .all(|other| other.number != syscall.number),
+            "System call {} is in the table twice",
+            syscall.name
+        );
+    }
+
+    // Runs as init, a kernel process whose pointers are used as they are
+    let init = process::current();
+    assert_eq!(dispatch(init, SYS_GETPID, [0; 6]), Some(INIT_PID as isize));
+    assert_eq!(dispatch(init, 0x0bad, [0; 6]), Some(-(ENOSYS as isize)));
+    assert_eq!(dispatch(init, SYS_BRK, [0; 6]), Some(-(ENOMEM as isize)));
+
+    let path = b"/syscall-sanity.txt\0";
+    let flags = (vfs::O_RDWR | vfs::O_CREAT | vfs::O_TRUNC) as usize;
+    let open = [AT_FDCWD as usize, path.as_ptr() as usize, flags, 0, 0, 0];
+    let fd = dispatch(init, SYS_OPENAT, open).unwrap();
+    assert!(fd >= 0, "Cannot open a file, errno {}", -fd);
+    let fd = fd as usize;
+    let data = b"system calls";
+    let write = [fd, data.as_ptr() as usize, data.len(), 0, 0, 0];
+    assert_eq!(dispatch(init, SYS_WRITE, write), Some(data.len() as isize));
+    assert_eq!(dispatch(init, SYS_CLOSE, [fd, 0, 0, 0, 0, 0]), Some(0));
+    assert_eq!(
+        dispatch(init, SYS_CLOSE, [fd, 0, 0, 0, 0, 0]),
+        Some(-(EBADF as isize))
+    );
+
+    let open = [AT_FDCWD as usize, path.as_ptr() as usize, 0, 0, 0, 0];
+    let fd = dispatch(init, SYS_OPENAT, open).unwrap() as usize;
+    let mut buffer = [0u8; 32];
+    let read = [fd, buffer.as_mut_ptr() as usize, buffer.len(), 0, 0, 0];
+    assert_eq!(dispatch(init, SYS_READ, read), Some(data.len() as isize));
+    assert_eq!(&buffer[..data.len()], data);
+    assert_eq!(dispatch(init, SYS_CLOSE, [fd, 0, 0, 0, 0, 0]), Some(0));
+    vfs::unlink("/syscall-sanity.txt").expect("Cannot remove the sanity file");
+
+    // wait4 writes the status of a killed child like a signal would
+    let child =
+        scheduler::spawn(process::process1 as *const () as usize).expect("Cannot spawn a process");
+    assert!(scheduler::kill(child));
+    let mut status = 0u32;
+    let wait = [child, &raw mut status as usize, 0, 0, 0, 0];
+    assert_eq!(dispatch(init, SYS_WAIT, wait), None);
+    assert_eq!(init.register(FRAME_A0), child);
+    assert_eq!(status, process::KILLED_EXIT_CODE.unsigned_abs());
+    assert_eq!(dispatch(init, SYS_WAIT, wait), None);
+    assert_eq!(init.register(FRAME_A0) as isize, -(ECHILD as isize));
+
+    let missing = b"/syscall-sanity/missing\0";
+    let open = [AT_FDCWD as usize, missing.as_ptr() as usize, 0, 0, 0, 0];
+    assert_eq!(dispatch(init, SYS_OPENAT, open), Some(-(ENOENT as isize)));
+
+    // User processes only reach their own memory. The process is not in the
+    // scheduler, it only lends its address space.
+    let free_before = page_allocator::free_pages();
+    let mut process = Process::new_user_process(0, None, user::hello as *const () as usize);
+    assert!(!process.stack().is_null(), "Cannot create a user process");
+    assert!(!process.read_memory(0x8000_0000, &mut buffer[..1]));
+    assert!(process.read_memory(process::USER_TEXT_BASE, &mut buffer[..4]));
+    assert!(!process.write_memory(process::USER_TEXT_BASE, b"no"));
+    assert!(process.write_memory(process::USER_STACK_TOP - 8, b"sta"));
+    assert!(!process.write_memory(process::USER_STACK_TOP - 2, b"top"));
+
+    let heap = process::USER_HEAP_BASE;
+    assert_eq!(sys_brk(&mut process, [0; 6]), Some(Ok(heap)));
+    assert!(!process.write_memory(heap, b"heap"));
+    let end = heap + PAGE_SIZE + 16;
+    assert_eq!(sys_brk(&mut process, [end, 0, 0, 0, 0, 0]), Some(Ok(end)));
+    assert!(process.write_memory(end - 4, b"heap"));
+    assert!(process.read_memory(end - 4, &mut buffer[..4]));
+    assert_eq!(&buffer[..4], b"heap");
+    assert_eq!(
+        sys_brk(&mut process, [0x8000_0000, 0, 0, 0, 0, 0]),
+        Some(Ok(end))
+    );
+
+    let anonymous = MAP_PRIVATE | MAP_ANONYMOUS;
+    let mmap = [0, 2 * PAGE_SIZE, PROT_READ | PROT_WRITE, anonymous, 0, 0];
+    assert_eq!(
+        sys_mmap(&mut process, mmap),
+        Some(Ok(process::USER_MMAP_BASE))
+    );
+    assert!(process.write_memory(process::USER_MMAP_BASE + 2 * PAGE_SIZE - 4, b"mmap"));
+    assert!(!process.write_memory(process::USER_MMAP_BASE + 2 * PAGE_SIZE, b"mmap"));
+    let mmap = [0, PAGE_SIZE, PROT_READ, anonymous, 0, 0];
+    let read_only = process::USER_MMAP_BASE + 2 * PAGE_SIZE;
+    assert_eq!(sys_mmap(&mut process, mmap), Some(Ok(read_only)));
+    assert!(process.read_memory(read_only, &mut buffer[..4]));
+    assert!(!process.write_memory(read_only, b"read"));
+    let mmap = [0, PAGE_SIZE, PROT_READ, MAP_PRIVATE, 3, 0];
+    assert_eq!(sys_mmap(&mut process, mmap), Some(Err(ENODEV)));
+    let mmap = [0, 0, PROT_READ, anonymous, 0, 0];
+    assert_eq!(sys_mmap(&mut process, mmap), Some(Err(EINVAL)));
+
+    drop(process);
+    assert_eq!(
+        page_allocator::free_pages(),
+        free_before,
+        "User memory leaked pages"
+    );
+}
@@ -1,15 +1,74 @@
+use crate::block;
+use crate::platform;
 use crate::plic;
+use crate::process::{self, Fault, Process};
 use crate::reg;
-use crate::scheduler::SCHEDULER;
+use crate::sbi;
+use crate::scheduler;
+use crate::syscall;
 use crate::uart;
 use crate::{paging, print, println};
+use core::arch::asm;
 use core::fmt::Write;
 
-const MASK_INTERRUPT_BIT: usize = 1 << (usize::BITS as usize - 1);
+// Pending supervisor software interrupt
+const SIP_SSIP: usize = 1 << 1;
+
+pub const MASK_INTERRUPT_BIT: usize = 1 << (usize::BITS as usize - 1);
+
+// Exception and interrupt codes are below this
+pub const CAUSE_COUNT: usize = 16;
+pub const PLIC_SOURCES: usize = 128;
+
+// How many traps were taken, per cause and per PLIC source
+static mut EXCEPTION_COUNTS: [u64; CAUSE_COUNT] = [0; CAUSE_COUNT];
+static mut INTERRUPT_COUNTS: [u64; CAUSE_COUNT] = [0; CAUSE_COUNT];
+static mut PLIC_COUNTS: [u64; PLIC_SOURCES] = [0; PLIC_SOURCES];
+
+pub fn exception_count(code: usize) -> u64 {
+    unsafe {
+        (*(&raw const EXCEPTION_COUNTS))
+            .get(code)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+pub fn interrupt_count(code: usize) -> u64 {
+    unsafe {
+        (*(&raw const INTERRUPT_COUNTS))
+            .get(code)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+pub fn plic_count(source: u32) -> u64 {
+    unsafe {
+        (*(&raw const PLIC_COUNTS))
+            .get(source as usize)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+fn count_trap(cause: usize) {
+    let code = Cause::cause_number(cause);
+    unsafe {
+        let counts = if (cause as isize) < 0 {
+            &mut *(&raw mut INTERRUPT_COUNTS)
+        } else {
+            &mut *(&raw mut EXCEPTION_COUNTS)
+        };
+        if let Some(count) = counts.get_mut(code) {
+            *count += 1;
+        }
+    }
+}
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 #[repr(usize)]
-pub enum MCause {
+pub enum Cause {
     // Exceptions
     InstrAddrMisaligned = 0,
     InstrAccessFault = 1,
@@ -40,45 +99,75 @@ pub enum MCause {
     UnknownInt,
 }
 
-impl MCause {
+impl Cause {
     pub fn new(cause: usize) -> Self {
         if (cause as isize) < 0 {
             // Interrupt
             // set last bit to 0
             match cause ^ MASK_INTERRUPT_BIT {
-                0 => MCause::UserSoftInt,
-                1 => MCause::SupervisorSoftInt,
-                3 => MCause::MachineSoftInt,
-                4 => MCause::UserTimerInt,
-                5 => MCause::SupervisorTimerInt,
-                7 => MCause::MachineTimerInt,
-                8 => MCause::UserExternalInt,
-                9 => MCause::SupervisorExternalInt,
-                11 => MCause::MachineExternalInt,
-                _ => MCause::UnknownInt,
+                0 => Cause::UserSoftInt,
+                1 => Cause::SupervisorSoftInt,
+                3 => Cause::MachineSoftInt,
+                4 => Cause::UserTimerInt,
+                5 => Cause::SupervisorTimerInt,
+                7 => Cause::MachineTimerInt,
+                8 => Cause::UserExternalInt,
+                9 => Cause::SupervisorExternalInt,
+                11 => Cause::MachineExternalInt,
+                _ => Cause::UnknownInt,
             }
         } else {
             // Trap
             match cause {
-                0 => MCause::InstrAddrMisaligned,
-                1 => MCause::InstrAccessFault,
-                2 => MCause::IllegalInstr,
-                3 => MCause::Breakpoint,
-                4 => MCause::LoadAddrMisaligned,
-                5 => MCause::LoadAccessFault,
-                6 => MCause::StoreAddrMisaligned,
-                7 => MCause::StoreAccessFault,
-                8 => MCause::EcallFromUMode,
-                9 => MCause::EcallFromSMode,
-                11 => MCause::EcallFromMMode,
-                12 => MCause::InstrPageFault,
-                13 => MCause::LoadPageFault,
-                15 => MCause::StorePageFault,
-                _ => MCause::UnknownException,
+                0 => Cause::InstrAddrMisaligned,
+                1 => Cause::InstrAccessFault,
+                2 => Cause::IllegalInstr,
+                3 => Cause::Breakpoint,
+                4 => Cause::LoadAddrMisaligned,
+                5 => Cause::LoadAccessFault,
+                6 => Cause::StoreAddrMisaligned,
+                7 => Cause::StoreAccessFault,
+                8 => Cause::EcallFromUMode,
+                9 => Cause::EcallFromSMode,
+                11 => Cause::EcallFromMMode,
+                12 => Cause::InstrPageFault,
+                13 => Cause::LoadPageFault,
+                15 => Cause::StorePageFault,
+                _ => Cause::UnknownException,
             }
         }
     }
 
+    pub fn name(self) -> &'static str {
+        match self {
+            Cause::InstrAddrMisaligned => "Instruction address misaligned",
+            Cause::InstrAccessFault => "Instruction access fault",
+            Cause::IllegalInstr => "Illegal instruction",
+            Cause::Breakpoint => "Breakpoint",
+            Cause::LoadAddrMisaligned => "Load address misaligned",
+            Cause::LoadAccessFault => "Load access fault",
+            Cause::StoreAddrMisaligned => "Store address misaligned",
+            Cause::StoreAccessFault => "Store access fault",
+            Cause::EcallFromUMode => "Ecall from U-mode",
+            Cause::EcallFromSMode => "Ecall from S-mode",
+            Cause::EcallFromMMode => "Ecall from M-mode",
+            Cause::InstrPageFault => "Instruction page fault",
+            Cause::LoadPageFault => "Load page fault",
+            Cause::StorePageFault => "Store page fault",
+            Cause::UnknownException => "Unknown exception",
+            Cause::UserSoftInt => "User software interrupt",
+            Cause::SupervisorSoftInt => "Supervisor software interrupt",
+            Cause::MachineSoftInt => "Machine software interrupt",
+            Cause::UserTimerInt => "User timer interrupt",
+            Cause::SupervisorTimerInt => "Supervisor timer interrupt",
+            Cause::MachineTimerInt => "Machine timer interrupt",
+            Cause::UserExternalInt => "User external interrupt",
+            Cause::SupervisorExternalInt => "Supervisor external interrupt",
+            Cause::MachineExternalInt => "Machine external interrupt",
+            Cause::UnknownInt => "Unknown interrupt",
+        }
+    }
+
     pub fn is_interrupt(self) -> bool {
         self as usize & MASK_INTERRUPT_BIT != 0
     }
@@ -93,74 +182,89 @@ impl MCause {
 }
 
 #[no_mangle]
-extern "C" fn m_trap() -> usize {
-    let mut return_pc = reg::mepc_read();
-    let tval = reg::mtval_read();
-    let cause = reg::mcause_read();
-    let hart = reg::mhartid_read();
-
-    match MCause::new(cause) {
-        MCause::EcallFromUMode => {
-            println!(
-                "E-call from Supervisor mode from core : {} -> 0x{:08x}",
-                hart, return_pc
-            );
-            // Go to next instruction
-            return_pc += 4
-        }
-        MCause::EcallFromSMode => {
-            println!(
-                "E-call from Supervisor mode from core : {} -> 0x{:08x}",
-                hart, return_pc
-            );
+extern "C" fn s_trap() -> usize {
+    let mut return_pc = reg::sepc_read();
+    let tval = reg::stval_read();
+    let cause = reg::scause_read();
+    let hart = reg::hartid();
+    // The trap vector resumes the process in sscratch at its saved pc, which
+    // may be another one after scheduling
+    let trapped = reg::sscratch_read() as *mut Process;
+    let from_user = reg::sstatus_read() & reg::SSTATUS_SPP == 0;
+    count_trap(cause);
+
+    match Cause::new(cause) {
+        Cause::EcallFromUMode => {
             // Go to next instruction
-            return_pc += 4
+            return_pc += 4;
+            syscall::handle();
         }
-        MCause::EcallFromMMode => {
+        exception if from_user && !exception.is_interrupt() => {
+            // Faults of user code stop the process, not the kernel
+            let process = process::current();
             println!(
-                "E-call from Machine mode from core : {} -> 0x{:08x}",
-                hart, return_pc
+                "Process {} stopped: {} at 0x{:08x}, address 0x{:08x}",
+                process.pid(),
+                exception.name(),
+                return_pc,
+                tval
             );
-            // Go to next instruction
-            return_pc += 4
+            scheduler::fault_current(Fault {
+                cause,
+                address: tval,
+            });
         }
-        MCause::InstrPageFault => {
+        Cause::InstrPageFault => {
             // Instruction page fault
             println!(
                 "Instruction page fault from core : {} -> 0x{:08x}",
                 hart, tval
             );
             paging::map(tval, tval, paging::EntryBits::ReadWriteExecute.val());
+            paging::flush();
         }
-        MCause::LoadPageFault => {
+        Cause::LoadPageFault => {
             // Load page fault
             println!("Load page fault from core : {} -> 0x{:08x}", hart, tval);
             paging::map(tval, tval, paging::EntryBits::ReadWriteExecute.val());
+            paging::flush();
         }
-        MCause::StorePageFault => {
+        Cause::StorePageFault => {
             // Store page fault
             println!("Store page fault from core : {} -> 0x{:08x}", hart, tval);
             paging::map(tval, tval, paging::EntryBits::ReadWriteExecute.val());
+            paging::flush();
         }
-        MCause::MachineTimerInt => {
-            unsafe {
-                // TODO: Make sure it is optimal : https://five-embeddev.com/riscv-priv-isa-manual/Priv-v1.12/machine.html#machine-timer-registers-mtime-and-mtimecmp
-                let mtimecmp = 0x0200_4000 as *mut u64;
-                let time_second = 100_000_00;
-                mtimecmp.write_volatile(mtimecmp.read_volatile() + 1 * time_second);
+        Cause::SupervisorTimerInt => {
+            // The firmware clears the pending bit when the next deadline is set
+            let time_second = platform::get().timebase;
+            sbi::set_timer(reg::time_read() + time_second);
 
-                println!("\x1b[0;33mReceived a timer interrupt, scheduling new process\x1b[0m");
+            println!("\x1b[0;33mReceived a timer interrupt, scheduling new process\x1b[0m");
 
-                SCHEDULER.next();
-            }
+            scheduler::next();
+        }
+        Cause::SupervisorSoftInt => {
+            // Inter processor interrupt, there is nothing to do but acknowledge it
+            unsafe { asm!("csrc sip, {}", in(reg) SIP_SSIP) };
         }
-        MCause::MachineExternalInt => {
+        Cause::SupervisorExternalInt => {
             if let Some(interrupt_code) = plic::next_interrupt() {
+                unsafe {
+                    if let Some(count) = (*(&raw mut PLIC_COUNTS)).get_mut(interrupt_code as usize)
+                    {
+                        *count += 1;
+                    }
+                }
                 match interrupt_code {
-                    10 => {
+                    code if code == uart::irq() => {
                         print!("\x1b[1m\x1b[3m\x1b[36m");
                         print_uart_value();
                         print!("\x1b[0m");
+                        scheduler::wake_readers();
+                    }
+                    code if block::is_block_irq(code) => {
+                        block::handle_interrupt(code);
                     }
                     _ => {
                         println!("Ignored plic interrupt");
@@ -175,6 +279,11 @@ extern "C" fn m_trap() -> usize {
         }
     }
 
+    // Exited processes stay in the table until the next switch, the pointer
+    // is still valid
+    if !trapped.is_null() {
+        unsafe { (*trapped).set_pc(return_pc) };
+    }
     return_pc
 }
 
@@ -183,9 +292,11 @@ fn print_uart_value() {
     if let Some(c) = uart_module.read() {
         match c {
             10 | 13 => {
+                uart::push_input(b'\n');
                 print!("\n");
             }
             _ => {
+                uart::push_input(c);
                 print!("{}", c as char);
             }
         }
@@ -1,6 +1,9 @@
 use core::fmt::Error;
 use core::fmt::Write;
 
+use crate::devfs::{self, CharDevice};
+use crate::vfs::VfsError;
+
 static RECEIVER_OFFSET: usize = 0;
 static TRANSMITTER_OFFSET: usize = 0;
 static INTERRUPT_ENABLE_REGISTER_OFFSET: usize = 1;
@@ -10,7 +13,15 @@ static LINE_STATUS_REGISTER_OFFSET: usize = 5;
 
 static DATA_READY_MASK: u8 = 0x1;
 
-pub static UART_BASE_ADDRESS: usize = 0x1000_0000;
+pub static mut UART_BASE_ADDRESS: usize = 0x1000_0000;
+static mut UART_IRQ: u32 = 10;
+
+// Bytes received and not read through /dev/console yet, the oldest ones are
+// dropped when nobody reads them
+const INPUT_SIZE: usize = 256;
+static mut INPUT: [u8; INPUT_SIZE] = [0; INPUT_SIZE];
+static mut INPUT_START: usize = 0;
+static mut INPUT_LEN: usize = 0;
 
 pub struct Uart {
     base_address: usize,
@@ -27,15 +38,21 @@ impl Write for Uart {
 
 impl Uart {
     pub fn get() -> Self {
-        Uart {
-            base_address: 0x1000_0000,
-        }
+        Uart::at(unsafe { UART_BASE_ADDRESS })
+    }
+
+    // Uart at a given address, without touching the state of the driver
+    pub const fn at(base_address: usize) -> Self {
+        Uart { base_address }
     }
 
-    pub fn start_driver(base_address: usize) -> u8 {
+    pub fn start_driver(base_address: usize, irq: u32) -> u8 {
         let pointer = base_address as *mut u8;
 
         unsafe {
+            UART_BASE_ADDRESS = base_address;
+            UART_IRQ = irq;
+
             // Set word length
             pointer
                 .add(LINE_CONTROL_REGISTER_OFFSET)
@@ -52,6 +69,8 @@ impl Uart {
                 .write_volatile(0x1);
         }
 
+        let _ = devfs::register_char("console", &CONSOLE);
+
         0
     }
 
@@ -78,7 +97,7 @@ impl Uart {
         unsafe { pointer.add(LSR_OFFSET).read_volatile() & LSR_THRE == 0 }
     }
 
-    fn write(&mut self, payload: u8) {
+    pub fn write(&mut self, payload: u8) {
         let pointer = self.base_address as *mut u8;
 
         while self.is_line_busy() {}
@@ -88,3 +107,60 @@ impl Uart {
         }
     }
 }
+
+// Interrupt line of the uart on the plic
+pub fn irq() -> u32 {
+    unsafe { UART_IRQ }
+}
+
+// Keep a received byte for readers of the console
+pub fn push_input(byte: u8) {
+    unsafe {
+        if INPUT_LEN == INPUT_SIZE {
+            INPUT_START = (INPUT_START + 1) % INPUT_SIZE;
+            INPUT_LEN -= 1;
+        }
+        INPUT[(INPUT_START + INPUT_LEN) % INPUT_SIZE] = byte;
+        INPUT_LEN += 1;
+    }
+}
+
+pub fn has_input() -> bool {
+    unsafe { INPUT_LEN > 0 }
+}
+
+// Move the pending input into buf, returns how many bytes were copied
+pub fn read_input(buf: &mut [u8]) -> usize {
+    unsafe {
+        let count = buf.len().min(INPUT_LEN);
+        for byte in buf.iter_mut().take(count) {
+            *byte = INPUT[INPUT_START];
+            INPUT_START = (INPUT_START + 1) % INPUT_SIZE;
+        }
+        INPUT_LEN -= count;
+        count
+    }
+}
+
+pub fn write_bytes(buf: &[u8]) {
+    let mut uart = Uart::get();
+    for byte in buf {
+        uart.write(*byte);
+    }
+}
+
+// /dev/console, reads never wait for input
+struct Console;
+
+impl CharDevice for Console {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(read_input(buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, VfsError> {
+        write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+static CONSOLE: Console = Console;
@@ -0,0 +1,92 @@
+// Programs running in user mode. They live in the .user_text section which is
+// mapped into every user address space at USER_TEXT_BASE, so they cannot call
+// anything outside of it. The kernel is built without optimizations, the
+// bodies stick to inline assembly to be sure no call to core is emitted.
+
+use crate::syscall::{SYS_EXIT, SYS_NANOSLEEP, SYS_WRITE};
+use core::arch::asm;
+
+#[inline(always)]
+fn exit(code: i32) -> ! {
+    unsafe {
+        asm!(
+            "mv a0, {code}",
+            "li a7, {number}",
+            "ecall",
+            "2: j 2b",
+            code = in(reg) code as usize,
+            number = const SYS_EXIT,
+            options(noreturn)
+        );
+    }
+}
+
+// Where a user process goes when its entry point returns
+#[link_section = ".user_text"]
+pub extern "C" fn user_return() -> ! {
+    exit(0)
+}
+
+// Spins long enough to be preempted a few times, then exits
+#[link_section = ".user_text"]
+pub extern "C" fn counter() -> ! {
+    unsafe {
+        asm!(
+            "li t0, {iterations}",
+            "2: addi t0, t0, -1",
+            "bnez t0, 2b",
+            iterations = const 20_000_000,
+            out("t0") _,
+        );
+    }
+    exit(0)
+}
+
+// Reads kernel memory, which is not mapped for user mode
+#[link_section = ".user_text"]
+pub extern "C" fn faulty() -> ! {
+    let value: usize;
+    unsafe {
+        asm!(
+            "li t0, 0x80000000",
+            "ld {value}, 0(t0)",
+            value = out(reg) value,
+            out("t0") _,
+        );
+    }
+    exit(value as i32)
+}
+
+// Greets through the standard output, naps for 100ms and exits
+#[link_section = ".user_text"]
+pub extern "C" fn hello() -> ! {
+    unsafe {
+        asm!(
+            "li a0, 1",
+            "lla a1, 3f",
+            "li a2, {len}",
+            "li a7, {write}",
+            "ecall",
+            // struct timespec on the stack
+            "addi sp, sp, -16",
+            "sd zero, 0(sp)",
+            "li t0, 100000000",
+            "sd t0, 8(sp)",
+            "mv a0, sp",
+            "li a1, 0",
+            "li a7, {nanosleep}",
+            "ecall",
+            "addi sp, sp, 16",
+            "li a0, 0",
+            "li a7, {exit}",
+            "ecall",
+            "2: j 2b",
+            "3: .ascii \"Hello from user mode\\n\"",
+            len = const 21,
+            write = const SYS_WRITE,
+            nanosleep = const SYS_NANOSLEEP,
+            exit = const SYS_EXIT,
+            options(noreturn)
+        );
+    }
+}
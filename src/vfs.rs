@@ -0,0 +1,797 @@
+// Virtual file system. File systems expose their files through the Inode trait
+// and get mounted at a path, paths are resolved across mount points and every
+// process keeps its own table of open files.
+
+use crate::{ext2, fat32, process, ramfs};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::Cell;
+
+pub const MAX_OPEN_FILES: usize = 32;
+// Bytes of each disk file read by the boot check
+const SANITY_READ_SIZE: usize = 512;
+// How many symlinks a single path resolution may follow
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+// Flags of open, same values as Linux
+pub const O_RDONLY: u32 = 0x0;
+pub const O_WRONLY: u32 = 0x1;
+pub const O_RDWR: u32 = 0x2;
+const O_ACCESS_MODE: u32 = 0x3;
+pub const O_CREAT: u32 = 0x40;
+pub const O_TRUNC: u32 = 0x200;
+pub const O_APPEND: u32 = 0x400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidPath,
+    InvalidArgument,
+    ReadOnly,
+    NoSpace,
+    // The file descriptor is not open, or not open for this operation
+    BadDescriptor,
+    TooManyOpenFiles,
+    TooManySymlinks,
+    // Rename between two file systems
+    CrossDevice,
+    // The path is a mount point
+    Busy,
+    Unsupported,
+    IoError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    pub links: u32,
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+pub type InodeRef = Arc<dyn Inode>;
+
+// A file, directory or device of a mounted file system. Operations a file
+// system does not support keep the default implementation.
+pub trait Inode {
+    fn stat(&self) -> Result<Stat, VfsError>;
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<InodeRef, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<InodeRef, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    // Move the entry name of this directory to new_name in new_parent, both
+    // directories always belong to the same file system
+    fn rename(
+        &self,
+        _name: &str,
+        _new_parent: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        Err(VfsError::InvalidArgument)
+    }
+
+    // Used by rename to get the concrete inode of new_parent back
+    fn as_any(&self) -> &dyn Any;
+
+    fn file_type(&self) -> Result<FileType, VfsError> {
+        self.stat().map(|stat| stat.file_type)
+    }
+}
+
+pub trait FileSystem {
+    fn name(&self) -> &str;
+
+    fn root(&self) -> InodeRef;
+
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+// A resolved path: the canonical absolute path and the inode it leads to
+#[derive(Clone)]
+pub struct Dentry {
+    pub path: String,
+    pub inode: InodeRef,
+}
+
+//----------- Mount table ---------------//
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+    root: InodeRef,
+}
+
+static mut MOUNTS: Vec<Mount> = Vec::new();
+
+fn mounts() -> &'static mut Vec<Mount> {
+    unsafe { &mut *(&raw mut MOUNTS) }
+}
+
+// Root of the file system mounted last at path
+fn mounted_at(path: &str) -> Option<InodeRef> {
+    mounts()
+        .iter()
+        .rev()
+        .find(|mount| mount.path == path)
+        .map(|mount| mount.root.clone())
+}
+
+// Index of the mount a canonical path belongs to, the deepest one wins and
+// the last one among mounts on the same path
+fn mount_of(path: &str) -> Option<usize> {
+    mounts()
+        .iter()
+        .enumerate()
+        .filter(|(_, mount)| {
+            mount.path == "/"
+                || path == mount.path
+                || (path.starts_with(mount.path.as_str())
+                    && path.as_bytes().get(mount.path.len()) == Some(&b'/'))
+        })
+        .max_by_key(|(_, mount)| mount.path.len())
+        .map(|(index, _)| index)
+}
+
+// Mount a file system on a directory, the first mount has to be "/"
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = if path == "/" {
+        String::from("/")
+    } else {
+        let dentry = resolve(path, true)?;
+        if dentry.inode.file_type()? != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        dentry.path
+    };
+
+    let root = fs.root();
+    mounts().push(Mount { path, fs, root });
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let path = resolve(path, true)?.path;
+    let index = mounts()
+        .iter()
+        .rposition(|mount| mount.path == path)
+        .ok_or(VfsError::InvalidArgument)?;
+
+    // Nothing may be mounted below it
+    let is_below = |other: &str| {
+        other != path
+            && (path == "/"
+                || (other.starts_with(path.as_str())
+                    && other.as_bytes().get(path.len()) == Some(&b'/')))
+    };
+    if mounts().iter().any(|mount| is_below(&mount.path)) {
+        return Err(VfsError::Busy);
+    }
+
+    mounts()[index].fs.sync()?;
+    mounts().remove(index);
+    Ok(())
+}
+
+// Call f with the mount point and name of every mounted file system
+pub fn for_each_mount<F: FnMut(&str, &str)>(mut f: F) {
+    for mount in mounts().iter() {
+        f(&mount.path, mount.fs.name());
+    }
+}
+
+pub fn sync() -> Result<(), VfsError> {
+    for mount in mounts().iter() {
+        mount.fs.sync()?;
+    }
+    Ok(())
+}
+
+// Sync the file system a canonical path belongs to
+fn sync_path(path: &str) -> Result<(), VfsError> {
+    match mount_of(path) {
+        Some(index) => mounts()[index].fs.sync(),
+        None => Ok(()),
+    }
+}
+
+//----------- Path resolution ---------------//
+
+fn join(components: &[(String, InodeRef)]) -> String {
+    if components.is_empty() {
+        return String::from("/");
+    }
+
+    let mut path = String::new();
+    for (name, _) in components {
+        path.push('/');
+        path.push_str(name);
+    }
+    path
+}
+
+// Walk path from the directory at the top of the stack. The stack holds every
+// component from the root, which makes ".." work across mount points.
+fn walk(
+    root: &InodeRef,
+    stack: &mut Vec<(String, InodeRef)>,
+    path: &str,
+    follow_last: bool,
+    depth: &mut usize,
+) -> Result<(), VfsError> {
+    if path.starts_with('/') {
+        stack.clear();
+    }
+
+    let mut components = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .peekable();
+
+    while let Some(component) = components.next() {
+        if component == ".." {
+            stack.pop();
+            continue;
+        }
+
+        let current = stack.last().map_or(root, |(_, inode)| inode).clone();
+        if current.file_type()? != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+
+        let mut child = current.lookup(component)?;
+        stack.push((String::from(component), child.clone()));
+        if let Some(mounted) = mounted_at(&join(stack)) {
+            child = mounted;
+            stack.last_mut().unwrap().1 = child.clone();
+        }
+
+        let is_last = components.peek().is_none();
+        if child.file_type()? == FileType::Symlink && (!is_last || follow_last) {
+            *depth += 1;
+            if *depth > MAX_SYMLINK_DEPTH {
+                return Err(VfsError::TooManySymlinks);
+            }
+
+            // Relative targets start from the directory holding the link
+            let target = child.read_link()?;
+            stack.pop();
+            walk(root, stack, &target, true, depth)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Resolve an absolute path, follow_last tells whether a symlink at the end is followed
+pub fn resolve(path: &str, follow_last: bool) -> Result<Dentry, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+
+    let root = mounted_at("/").ok_or(VfsError::NotFound)?;
+    let mut stack = Vec::new();
+    let mut depth = 0;
+    walk(&root, &mut stack, path, follow_last, &mut depth)?;
+
+    Ok(Dentry {
+        path: join(&stack),
+        inode: stack.last().map_or(root, |(_, inode)| inode.clone()),
+    })
+}
+
+// The directory holding the last component of path and the name of that component
+fn resolve_parent(path: &str) -> Result<(Dentry, &str), VfsError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').ok_or(VfsError::InvalidPath)?;
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(VfsError::InvalidPath);
+    }
+
+    let parent = resolve(if parent.is_empty() { "/" } else { parent }, true)?;
+    if parent.inode.file_type()? != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    Ok((parent, name))
+}
+
+fn child_path(parent: &Dentry, name: &str) -> String {
+    let mut path = parent.path.clone();
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    path.push_str(name);
+    path
+}
+
+pub fn stat(path: &str) -> Result<Stat, VfsError> {
+    resolve(path, true)?.inode.stat()
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    resolve(path, true)?.inode.read_dir()
+}
+
+pub fn create(path: &str, file_type: FileType) -> Result<Dentry, VfsError> {
+    let (parent, name) = resolve_parent(path)?;
+    match parent.inode.lookup(name) {
+        Ok(_) => return Err(VfsError::AlreadyExists),
+        Err(VfsError::NotFound) => {}
+        Err(error) => return Err(error),
+    }
+
+    let inode = parent.inode.create(name, file_type)?;
+    Ok(Dentry {
+        path: child_path(&parent, name),
+        inode,
+    })
+}
+
+pub fn mkdir(path: &str) -> Result<(), VfsError> {
+    create(path, FileType::Directory).map(|_| ())
+}
+
+// Create a directory and every missing directory above it
+pub fn mkdir_all(path: &str) -> Result<(), VfsError> {
+    let mut prefix = String::new();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        prefix.push('/');
+        prefix.push_str(component);
+        match mkdir(&prefix) {
+            Ok(()) | Err(VfsError::AlreadyExists) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    match resolve(path, true)?.inode.file_type()? {
+        FileType::Directory => Ok(()),
+        _ => Err(VfsError::NotADirectory),
+    }
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<(), VfsError> {
+    let (parent, name) = resolve_parent(path)?;
+    match parent.inode.lookup(name) {
+        Ok(_) => return Err(VfsError::AlreadyExists),
+        Err(VfsError::NotFound) => {}
+        Err(error) => return Err(error),
+    }
+    parent.inode.symlink(name, target).map(|_| ())
+}
+
+// Remove a file or an empty directory
+pub fn unlink(path: &str) -> Result<(), VfsError> {
+    let (parent, name) = resolve_parent(path)?;
+    if mounted_at(&child_path(&parent, name)).is_some() {
+        return Err(VfsError::Busy);
+    }
+    parent.inode.unlink(name)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), VfsError> {
+    let (old_parent, old_name) = resolve_parent(from)?;
+    let (new_parent, new_name) = resolve_parent(to)?;
+
+    let old_path = child_path(&old_parent, old_name);
+    if mounted_at(&old_path).is_some() {
+        return Err(VfsError::Busy);
+    }
+    if mount_of(&old_parent.path) != mount_of(&new_parent.path) {
+        return Err(VfsError::CrossDevice);
+    }
+
+    // A directory cannot be moved inside itself
+    let new_path = child_path(&new_parent, new_name);
+    if new_path.starts_with(old_path.as_str())
+        && new_path.as_bytes().get(old_path.len()) == Some(&b'/')
+    {
+        return Err(VfsError::InvalidArgument);
+    }
+
+    old_parent
+        .inode
+        .rename(old_name, &*new_parent.inode, new_name)
+}
+
+//----------- Open files ---------------//
+
+pub struct OpenFile {
+    dentry: Dentry,
+    flags: u32,
+    offset: Cell<u64>,
+    written: Cell<bool>,
+}
+
+// File systems cache their writes, those of a file reach the device once the
+// last descriptor to it is closed
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if self.written.get() {
+            let _ = sync_path(&self.dentry.path);
+        }
+    }
+}
+
+impl OpenFile {
+    fn readable(&self) -> bool {
+        self.flags & O_ACCESS_MODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_ACCESS_MODE != O_RDONLY
+    }
+
+    pub fn path(&self) -> &str {
+        &self.dentry.path
+    }
+}
+
+// Per process table of open files, indexed by file descriptor
+pub struct FileTable {
+    files: [Option<Arc<OpenFile>>; MAX_OPEN_FILES],
+}
+
+impl FileTable {
+    pub const fn new() -> Self {
+        FileTable {
+            files: [const { None }; MAX_OPEN_FILES],
+        }
+    }
+
+    fn get(&self, fd: usize) -> Result<Arc<OpenFile>, VfsError> {
+        self.files
+            .get(fd)
+            .and_then(|file| file.clone())
+            .ok_or(VfsError::BadDescriptor)
+    }
+
+    // Lowest free descriptor
+    fn insert(&mut self, file: Arc<OpenFile>) -> Result<usize, VfsError> {
+        let fd = self
+            .files
+            .iter()
+            .position(|file| file.is_none())
+            .ok_or(VfsError::TooManyOpenFiles)?;
+        self.files[fd] = Some(file);
+        Ok(fd)
+    }
+
+    pub fn close_all(&mut self) {
+        for file in self.files.iter_mut() {
+            *file = None;
+        }
+    }
+
+    pub fn is_open(&self, fd: usize) -> bool {
+        self.files.get(fd).is_some_and(|file| file.is_some())
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+}
+
+fn files() -> &'static mut FileTable {
+    &mut process::current().files
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+pub fn open(path: &str, flags: u32) -> Result<usize, VfsError> {
+    let dentry = match resolve(path, true) {
+        Ok(dentry) => dentry,
+        Err(VfsError::NotFound) if flags & O_CREAT != 0 => create(path, FileType::Regular)?,
+        Err(error) => return Err(error),
+    };
+
+    let file = OpenFile {
+        dentry,
+        flags,
+        offset: Cell::new(0),
+        written: Cell::new(false),
+    };
+
+    let file_type = file.dentry.inode.file_type()?;
+    if file.writable() && file_type == FileType::Directory {
+        return Err(VfsError::IsADirectory);
+    }
+    if file.writable() && flags & O_TRUNC != 0 && file_type == FileType::Regular {
+        file.dentry.inode.truncate(0)?;
+    }
+
+    files().insert(Arc::new(file))
+}
+
+pub fn close(fd: usize) -> Result<(), VfsError> {
+    let slot = files().files.get_mut(fd).ok_or(VfsError::BadDescriptor)?;
+    slot.take().map(|_| ()).ok_or(VfsError::BadDescriptor)
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, VfsError> {
+    let file = files().get(fd)?;
+    if !file.readable() {
+        return Err(VfsError::BadDescriptor);
+    }
+
+    let len = file.dentry.inode.read_at(file.offset.get(), buf)?;
+    file.offset.set(file.offset.get() + len as u64);
+    Ok(len)
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, VfsError> {
+    let file = files().get(fd)?;
+    if !file.writable() {
+        return Err(VfsError::BadDescriptor);
+    }
+
+    if file.flags & O_APPEND != 0 {
+        file.offset.set(file.dentry.inode.stat()?.size);
+    }
+    let len = file.dentry.inode.write_at(file.offset.get(), buf)?;
+    file.offset.set(file.offset.get() + len as u64);
+    file.written.set(true);
+    Ok(len)
+}
+
+pub fn lseek(fd: usize, offset: i64, whence: Whence) -> Result<u64, VfsError> {
+    let file = files().get(fd)?;
+    let base = match whence {
+        Whence::Set => 0,
+        Whence::Current => file.offset.get(),
+        Whence::End => file.dentry.inode.stat()?.size,
+    };
+
+    let position = base
+        .checked_add_signed(offset)
+        .ok_or(VfsError::InvalidArgument)?;
+    file.offset.set(position);
+    Ok(position)
+}
+
+pub fn fstat(fd: usize) -> Result<Stat, VfsError> {
+    files().get(fd)?.dentry.inode.stat()
+}
+
+// Write the cached changes of the file system holding the file to its device
+pub fn fsync(fd: usize) -> Result<(), VfsError> {
+    sync_path(&files().get(fd)?.dentry.path)
+}
+
+// The root lives in memory and gets filled from the initramfs, disk file
+// systems found at boot are mounted below /mnt
+pub fn init() {
+    mount("/", Arc::new(ramfs::RamFs::new())).expect("Cannot mount the root file system");
+
+    if let Some(fat) = fat32::volume() {
+        mkdir_all("/mnt/fat32").expect("Cannot create the FAT32 mount point");
+        mount("/mnt/fat32", Arc::new(fat32::FatFs::new(fat))).expect("Cannot mount FAT32");
+    }
+    if let Some(ext2) = ext2::volume() {
+        mkdir_all("/mnt/ext2").expect("Cannot create the ext2 mount point");
+        mount("/mnt/ext2", Arc::new(ext2::Ext2Fs::new(ext2))).expect("Cannot mount ext2");
+    }
+}
+
+// Minimal in memory tree used to check path resolution without a disk
+struct SanityInode {
+    file_type: FileType,
+    children: Vec<(&'static str, InodeRef)>,
+    target: &'static str,
+}
+
+impl Inode for SanityInode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: self as *const SanityInode as u64,
+            file_type: self.file_type,
+            size: 0,
+            links: 1,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        self.children
+            .iter()
+            .find(|(child, _)| *child == name)
+            .map(|(_, inode)| inode.clone())
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        Ok(String::from(self.target))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct SanityFs {
+    root: InodeRef,
+}
+
+impl FileSystem for SanityFs {
+    fn name(&self) -> &str {
+        "sanity"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+pub fn init_sanity_check() {
+    let node = |file_type, children, target| -> InodeRef {
+        Arc::new(SanityInode {
+            file_type,
+            children,
+            target,
+        })
+    };
+
+    // /a/b, /a/link -> ../c and /c
+    let b = node(FileType::Directory, Vec::new(), "");
+    let link = node(FileType::Symlink, Vec::new(), "../c");
+    let a = node(
+        FileType::Directory,
+        alloc::vec![("b", b), ("link", link)],
+        "",
+    );
+    let c = node(FileType::Directory, Vec::new(), "");
+    let root = node(FileType::Directory, alloc::vec![("a", a), ("c", c)], "");
+
+    // Resolution is checked on a private mount table
+    let saved = core::mem::take(mounts());
+    mount("/", Arc::new(SanityFs { root })).expect("Cannot mount the root");
+
+    assert_eq!(
+        resolve("/a/./b/..", true).map(|d| d.path).ok().as_deref(),
+        Some("/a")
+    );
+    assert_eq!(
+        resolve("/../a/b", true).map(|d| d.path).ok().as_deref(),
+        Some("/a/b")
+    );
+    assert_eq!(
+        resolve("/a/link", true).map(|d| d.path).ok().as_deref(),
+        Some("/c")
+    );
+    assert_eq!(
+        resolve("/a/link", false).map(|d| d.path).ok().as_deref(),
+        Some("/a/link")
+    );
+    assert_eq!(resolve("/a/missing", true).err(), Some(VfsError::NotFound));
+
+    // ".." leaves a mounted file system
+    let other = node(FileType::Directory, Vec::new(), "");
+    mount(
+        "/c",
+        Arc::new(SanityFs {
+            root: other.clone(),
+        }),
+    )
+    .expect("Cannot mount on /c");
+    let mounted = resolve("/a/link", true).expect("Cannot cross a mount point");
+    assert!(
+        Arc::ptr_eq(&mounted.inode, &other),
+        "Mount point is not followed"
+    );
+    assert_eq!(
+        resolve("/c/../a", true).map(|d| d.path).ok().as_deref(),
+        Some("/a")
+    );
+    assert_eq!(unmount("/").err(), Some(VfsError::Busy));
+    unmount("/c").expect("Cannot unmount /c");
+
+    *mounts() = saved;
+
+    assert_eq!(close(MAX_OPEN_FILES), Err(VfsError::BadDescriptor));
+
+    // Files of the disks are readable through their mount, only the start of
+    // each one is read so that big files do not matter
+    for mount_point in ["/mnt/fat32", "/mnt/ext2"] {
+        let entries = match read_dir(mount_point) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries
+            .iter()
+            .filter(|entry| entry.file_type == FileType::Regular)
+        {
+            let path = alloc::format!("{}/{}", mount_point, entry.name);
+            let size = stat(&path).expect("Cannot stat a disk file").size;
+            let fd = open(&path, O_RDONLY).expect("Cannot open a disk file");
+            let mut buffer = [0u8; SANITY_READ_SIZE];
+            assert_eq!(
+                read(fd, &mut buffer),
+                Ok(size.min(SANITY_READ_SIZE as u64) as usize),
+                "Disk file was read partially"
+            );
+            close(fd).expect("Cannot close a disk file");
+        }
+
+        let missing = alloc::format!("{}/does-not-exist", mount_point);
+        assert_eq!(stat(&missing).err(), Some(VfsError::NotFound));
+    }
+
+    // Round trip through a file descriptor when the root is writable
+    if mounted_at("/").is_none() {
+        return;
+    }
+    let fd = match open("/vfs-sanity.txt", O_RDWR | O_CREAT | O_TRUNC) {
+        Ok(fd) => fd,
+        Err(VfsError::ReadOnly) => return,
+        Err(error) => panic!("Cannot create a file: {:?}", error),
+    };
+    assert_eq!(write(fd, b"hello vfs"), Ok(9));
+    assert_eq!(lseek(fd, -3, Whence::End), Ok(6));
+    let mut buffer = [0u8; 8];
+    assert_eq!(read(fd, &mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], b"vfs");
+    assert_eq!(fstat(fd).map(|stat| stat.size), Ok(9));
+    close(fd).expect("Cannot close a file");
+    unlink("/vfs-sanity.txt").expect("Cannot remove a file");
+    assert_eq!(stat("/vfs-sanity.txt").err(), Some(VfsError::NotFound));
+}
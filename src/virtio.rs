@@ -1,6 +1,12 @@
-use crate::block::{initialize_block_device, read_block_device};
-use crate::page_allocator::PAGE_SIZE;
+use crate::block::{self, initialize_block_device};
+use crate::page_allocator::{self, PAGE_SIZE};
+use crate::platform::VirtioSlot;
 use core::fmt::Write;
+use core::ptr::{self, null_mut};
+use core::sync::atomic::{fence, Ordering};
+
+extern crate alloc;
+use alloc::vec;
 
 pub const VIRTIO_DESC_F_NEXT: u16 = 1;
 pub const VIRTIO_DESC_F_WRITE: u16 = 2;
@@ -47,9 +53,164 @@ pub struct Queue {
     pub used: Used,
 }
 
-const MMIO_BASE: usize = 0x10001000;
-const MMIO_END: usize = 0x10008000;
-const MMIO_STRIDE: usize = 0x1000;
+// A buffer of a descriptor chain, writable buffers are filled by the device
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    pub device_writable: bool,
+}
+
+// Split virtqueue shared by the virtio drivers. Unused descriptors are linked
+// together through their next field, starting at free_head.
+pub struct Virtqueue {
+    queue: *mut Queue,
+    free_head: u16,
+    num_free: usize,
+    // Next entry of the used ring we have not looked at yet
+    last_used: u16,
+}
+
+impl Virtqueue {
+    pub const fn empty() -> Self {
+        Virtqueue {
+            queue: null_mut(),
+            free_head: 0,
+            num_free: 0,
+            last_used: 0,
+        }
+    }
+
+    // Allocate the rings of the queue with the given index and hand them to the device
+    pub unsafe fn setup(dev: *mut u32, index: u32) -> Option<Self> {
+        // Selects the queue
+        dev.add(MmioOffset::QueueSel as usize / 4)
+            .write_volatile(index);
+
+        let max_size = dev
+            .add(MmioOffset::QueueNumMax as usize / 4)
+            .read_volatile();
+        if (max_size as usize) < VIRTIO_RING_SIZE {
+            return None;
+        }
+
+        // Writes the size of the queue
+        dev.add(MmioOffset::QueueNum as usize / 4)
+            .write_volatile(VIRTIO_RING_SIZE as u32);
+
+        // Negotiates the page size with the host
+        dev.add(MmioOffset::GuestPageSize as usize / 4)
+            .write_volatile(PAGE_SIZE as u32);
+
+        let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let queue = page_allocator::alloc(num_pages) as *mut Queue;
+        if queue.is_null() {
+            return None;
+        }
+
+        // Physical memory address shifted by the page size
+        dev.add(MmioOffset::QueuePfn as usize / 4)
+            .write_volatile((queue as usize / PAGE_SIZE) as u32);
+
+        for i in 0..VIRTIO_RING_SIZE {
+            (*queue).desc[i].next = (i + 1) as u16;
+        }
+
+        Some(Virtqueue {
+            queue,
+            free_head: 0,
+            num_free: VIRTIO_RING_SIZE,
+            last_used: 0,
+        })
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.queue.is_null()
+    }
+
+    pub fn num_free(&self) -> usize {
+        self.num_free
+    }
+
+    // Chain the buffers together and make them available to the device.
+    // Returns the head descriptor, or None if the queue is too full.
+    pub unsafe fn add_chain(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = &mut (*self.queue).desc[current as usize];
+            let next_free = desc.next;
+
+            desc.addr = buffer.addr;
+            desc.len = buffer.len;
+            desc.flags = if buffer.device_writable {
+                VIRTIO_DESC_F_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                desc.flags |= VIRTIO_DESC_F_NEXT;
+                desc.next = next_free;
+            }
+
+            self.num_free -= 1;
+            self.free_head = next_free;
+            current = next_free;
+        }
+
+        // The descriptors must be visible before the device sees the new index
+        let avail = &raw mut (*self.queue).avail;
+        let idx = ptr::read_volatile(&(*avail).idx);
+        (*avail).ring[idx as usize % VIRTIO_RING_SIZE] = head;
+        fence(Ordering::SeqCst);
+        ptr::write_volatile(&mut (*avail).idx, idx.wrapping_add(1));
+        fence(Ordering::SeqCst);
+
+        Some(head)
+    }
+
+    // Next chain the device is done with as (head, bytes written by the device).
+    // Its descriptors go back to the free list.
+    pub unsafe fn pop_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        let used = &raw const (*self.queue).used;
+        if self.last_used == ptr::read_volatile(&(*used).idx) {
+            return None;
+        }
+
+        let elem = &(*used).ring[self.last_used as usize % VIRTIO_RING_SIZE];
+        let head = elem.id as u16;
+        let len = elem.len;
+        self.last_used = self.last_used.wrapping_add(1);
+
+        self.free_chain(head);
+        Some((head, len))
+    }
+
+    unsafe fn free_chain(&mut self, head: u16) {
+        let mut current = head;
+        loop {
+            let desc = &mut (*self.queue).desc[current as usize];
+            let has_next = desc.flags & VIRTIO_DESC_F_NEXT != 0;
+            let next = desc.next;
+
+            desc.flags = 0;
+            desc.next = self.free_head;
+            self.free_head = current;
+            self.num_free += 1;
+
+            if !has_next {
+                break;
+            }
+            current = next;
+        }
+    }
+}
+
 const MMIO_MAGIC: u32 = 0x74726976;
 
 #[allow(dead_code)]
@@ -71,6 +232,7 @@ pub enum MmioOffset {
     InterruptStatus = 0x60,
     InterruptAck = 0x64,
     Status = 0x70,
+    Config = 0x100,
 }
 
 impl From<MmioOffset> for usize {
@@ -108,14 +270,14 @@ impl DeviceType {
     }
 }
 
-pub fn init() {
-    for addr in (MMIO_BASE..=MMIO_END).step_by(MMIO_STRIDE) {
+pub fn init(slots: &[VirtioSlot]) {
+    for slot in slots {
         let magic_value: u32;
         let device_id: u32;
         let version_id: u32;
         let vendor_id: u32;
 
-        let ptr = addr as *mut u32;
+        let ptr = slot.address as *mut u32;
 
         unsafe {
             magic_value = ptr.add(MmioOffset::MagicValue as usize / 4).read_volatile();
@@ -134,7 +296,7 @@ pub fn init() {
                     println!("Disk device found");
                     assert_eq!(version_id, 1);
                     assert_eq!(vendor_id, 0x554d4551);
-                    initialize_block_device(ptr);
+                    initialize_block_device(ptr, slot.irq);
                 }
                 DeviceType::Entropy => {
                     println!("Entropy device found")
@@ -148,7 +310,8 @@ pub fn init() {
 }
 
 pub fn init_sanity_check() {
-    let output = unsafe { read_block_device(0) };
-
-    assert_eq!(output[3], 97, "must be equal 97");
+    let disk = block::find("vda").expect("No virtio block device registered");
+    let mut output = vec![0u8; disk.sector_size()];
+    disk.read(0, &mut output)
+        .expect("Cannot read the first sector");
 }